@@ -0,0 +1,77 @@
+// replays an event log into a fresh engine and dumps the state it reached,
+// for working out after the fact why a fill happened
+//
+//     replay <event log> [--seq <last seq>] [--until <timestamp>]
+//
+// --seq stops after the record with that seq, --until after the last record
+// at or before that timestamp in nanoseconds. the dump is json on stdout
+
+use derivadex::{Account, Engine, Journal, L3Snapshot, PositionView, ReplayedFill, Symbol};
+use serde::Serialize;
+use std::{collections::BTreeMap, process::exit};
+use web3::types::Address;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Dump {
+    // last record replayed, 0 when nothing was
+    seq: u64,
+    timestamp: u128,
+    accounts: Vec<Account>,
+    // by trader, including traders whose account was deleted
+    positions: BTreeMap<Address, Vec<PositionView>>,
+    // every resting order, in the order it would fill
+    books: BTreeMap<Symbol, L3Snapshot>,
+    fills: Vec<ReplayedFill>,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: replay <event log> [--seq <last seq>] [--until <timestamp>]");
+    exit(2)
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let Some(path) = args.next() else { usage() };
+    let mut last_seq = u64::MAX;
+    let mut until = u128::MAX;
+    while let Some(flag) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+        match flag.as_str() {
+            "--seq" => last_seq = value.parse().unwrap_or_else(|_| usage()),
+            "--until" => until = value.parse().unwrap_or_else(|_| usage()),
+            _ => usage(),
+        }
+    }
+
+    let records = Journal::read(&path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        exit(1)
+    });
+    let (mut seq, mut timestamp) = (0, 0);
+    let (engine, fills) = Engine::replay(records, |record| {
+        if record.seq > last_seq || record.timestamp > until {
+            return true;
+        }
+        (seq, timestamp) = (record.seq, record.timestamp);
+        false
+    })
+    .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1)
+    });
+
+    let dump = Dump {
+        seq,
+        timestamp,
+        accounts: engine.get_accounts(),
+        positions: engine.get_all_positions(),
+        books: Symbol::ALL
+            .iter()
+            .map(|symbol| (*symbol, engine.get_l3_snapshot(*symbol)))
+            .collect(),
+        fills,
+    };
+    serde_json::to_writer_pretty(std::io::stdout().lock(), &dump).unwrap();
+    println!();
+}
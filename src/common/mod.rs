@@ -1,15 +1,62 @@
 use rust_decimal::Decimal;
 use serde::{de::Visitor, Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
+use std::str::FromStr;
 use web3::types::{Address, H256, U256};
 
-#[derive(Debug, Copy, Clone, Deserialize_repr, Serialize_repr)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize_repr, Serialize_repr)]
 #[repr(u8)]
 pub enum Side {
     Bid,
     Ask,
 }
 
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum TimeInForce {
+    // rests in the book until filled or cancelled
+    #[default]
+    Gtc,
+    // whatever does not fill immediately is dropped
+    Ioc,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MarketKind {
+    Spot,
+    Perpetual,
+}
+
+#[derive(
+    Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize,
+)]
+pub enum Symbol {
+    // spot DDX/USD, kept as the default so existing clients need not send a symbol
+    #[default]
+    #[serde(rename = "DDXUSD")]
+    DdxUsd,
+    #[serde(rename = "DDXPERP")]
+    DdxPerp,
+}
+
+impl Symbol {
+    pub const ALL: [Symbol; 2] = [Symbol::DdxUsd, Symbol::DdxPerp];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Symbol::DdxUsd => "DDXUSD",
+            Symbol::DdxPerp => "DDXPERP",
+        }
+    }
+
+    pub fn kind(&self) -> MarketKind {
+        match self {
+            Symbol::DdxUsd => MarketKind::Spot,
+            Symbol::DdxPerp => MarketKind::Perpetual,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Account {
@@ -23,6 +70,12 @@ pub struct Account {
     pub usd_book_outstanding: Decimal,
 }
 
+impl std::fmt::Display for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[derive(Debug, Copy, Clone, Serialize)]
 pub struct Nonce(pub H256);
 
@@ -81,6 +134,12 @@ impl<'de> Deserialize<'de> for Nonce {
             where
                 E: serde::de::Error,
             {
+                // nonces serialize as hex, accept that back as well
+                if let Some(hex) = v.strip_prefix("0x") {
+                    return H256::from_str(hex)
+                        .map(Nonce)
+                        .map_err(|e| serde::de::Error::custom(e));
+                }
                 let mut bytes = [0u8; 32];
                 U256::from_dec_str(v)
                     .map_err(|e| serde::de::Error::custom(e))?
@@ -109,11 +168,33 @@ pub struct Order {
     pub price: Decimal,
     pub side: Side,
     pub trader_address: Address,
+    #[serde(default)]
+    pub symbol: Symbol,
+    #[serde(default)]
+    pub time_in_force: TimeInForce,
+    // only ever shrinks the trader's position, clamped to what is left of it
+    #[serde(default)]
+    pub reduce_only: bool,
+    // reduce only, sized to the whole position
+    #[serde(default)]
+    pub close_position: bool,
 
     #[serde(skip)]
     pub timestamp: u128,
 }
 
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FillKind {
+    #[default]
+    Trade,
+    // a liquidation order matched against the book
+    Liquidation,
+    // a profitable position forcibly reduced against a bankrupt one, there
+    // are no orders behind it so both hashes are zero
+    Deleverage,
+}
+
 #[derive(Debug, Copy, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Fill {
@@ -121,4 +202,6 @@ pub struct Fill {
     pub taker_hash: H256,
     pub fill_amount: Decimal,
     pub price: Decimal,
+    #[serde(default)]
+    pub kind: FillKind,
 }
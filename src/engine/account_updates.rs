@@ -0,0 +1,333 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use web3::types::{Address, H256};
+
+use super::{liquidation::LiquidationEvent, order_status::TerminalReason, Engine};
+use crate::{Account, Fill, Order, Side, Symbol, TimeInForce};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OrderStatus {
+    // resting in the book, or about to match for a taker
+    Accepted,
+    PartiallyFilled,
+    Filled,
+    // removed by its trader, a liquidation, reduce-only enforcement or a
+    // self match
+    Cancelled,
+    // what an ioc order could not fill straight away
+    Expired,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderUpdate {
+    pub hash: H256,
+    pub trader_address: Address,
+    pub symbol: Symbol,
+    pub side: Side,
+    pub price: Decimal,
+    // still open in the book, zero once the order is done
+    pub remaining: Decimal,
+    pub status: OrderStatus,
+    // set when the order was cancelled or expired
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<TerminalReason>,
+    pub timestamp: u128,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Liquidity {
+    Maker,
+    Taker,
+}
+
+// one side of a fill, as seen by the trader of that order
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FillUpdate {
+    pub order_hash: H256,
+    pub trader_address: Address,
+    pub symbol: Symbol,
+    pub side: Side,
+    pub price: Decimal,
+    pub amount: Decimal,
+    pub liquidity: Liquidity,
+    // usd charged for the fill, there is no fee schedule yet so it is zero
+    pub fee: Decimal,
+    pub timestamp: u128,
+}
+
+// private updates, only for the trader they name
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "camelCase")]
+pub enum AccountUpdate {
+    Order(OrderUpdate),
+    Fill(FillUpdate),
+    // the account after its ddx or usd balance moved
+    Balance(Account),
+    // the trader's positions were closed out, their balance follows
+    Liquidation(LiquidationEvent),
+}
+
+impl AccountUpdate {
+    pub fn trader_address(&self) -> Address {
+        match self {
+            AccountUpdate::Order(update) => update.trader_address,
+            AccountUpdate::Fill(update) => update.trader_address,
+            AccountUpdate::Balance(account) => account.trader_address,
+            AccountUpdate::Liquidation(liquidation) => liquidation.trader_address,
+        }
+    }
+}
+
+impl Engine {
+    // lifecycle of a taker and the makers it matched, called once the book
+    // has the taker's remainder (if any) and before balances move
+    pub(super) fn report_fills(&mut self, taker_hash: H256, order: &Order, fills: &[Fill]) {
+        let now = self.now;
+        let book = &self.markets[&order.symbol].book;
+        let mut updates = vec![];
+        let order_update = |hash, trader_address, side, price, remaining, status| {
+            AccountUpdate::Order(OrderUpdate {
+                hash,
+                trader_address,
+                symbol: order.symbol,
+                side,
+                price,
+                remaining,
+                status,
+                reason: None,
+                timestamp: now,
+            })
+        };
+        let fill_update = |order_hash, trader_address, side, fill: &Fill, liquidity| {
+            AccountUpdate::Fill(FillUpdate {
+                order_hash,
+                trader_address,
+                symbol: order.symbol,
+                side,
+                price: fill.price,
+                amount: fill.fill_amount,
+                liquidity,
+                fee: Decimal::ZERO,
+                timestamp: now,
+            })
+        };
+
+        updates.push(order_update(
+            taker_hash,
+            order.trader_address,
+            order.side,
+            order.price,
+            order.amount,
+            OrderStatus::Accepted,
+        ));
+        let maker_side = match order.side {
+            Side::Bid => Side::Ask,
+            Side::Ask => Side::Bid,
+        };
+        for fill in fills {
+            let maker_address = self.hash_to_address[&fill.maker_hash];
+            updates.push(fill_update(
+                taker_hash,
+                order.trader_address,
+                order.side,
+                fill,
+                Liquidity::Taker,
+            ));
+            updates.push(fill_update(
+                fill.maker_hash,
+                maker_address,
+                maker_side,
+                fill,
+                Liquidity::Maker,
+            ));
+            let (remaining, status) = match book.get_order(fill.maker_hash) {
+                Ok(maker) => (maker.amount, OrderStatus::PartiallyFilled),
+                Err(_) => (Decimal::ZERO, OrderStatus::Filled),
+            };
+            updates.push(order_update(
+                fill.maker_hash,
+                maker_address,
+                maker_side,
+                fill.price,
+                remaining,
+                status,
+            ));
+        }
+
+        let filled: Decimal = fills.iter().map(|fill| fill.fill_amount).sum();
+        let taker_status = match book.get_order(taker_hash) {
+            Ok(_) if filled.is_zero() => None,
+            Ok(_) => Some((OrderStatus::PartiallyFilled, None)),
+            Err(_) if filled == order.amount => Some((OrderStatus::Filled, None)),
+            Err(_) if order.time_in_force == TimeInForce::Ioc => Some((
+                OrderStatus::Expired,
+                Some(TerminalReason::ImmediateOrCancel),
+            )),
+            // the remainder of a gtc order only stays out of the book when it
+            // would have matched its own trader's order
+            Err(_) => Some((OrderStatus::Cancelled, Some(TerminalReason::SelfMatch))),
+        };
+        if let Some((status, reason)) = taker_status {
+            let remaining = match status {
+                OrderStatus::PartiallyFilled => order.amount - filled,
+                _ => Decimal::ZERO,
+            };
+            let mut update = order_update(
+                taker_hash,
+                order.trader_address,
+                order.side,
+                order.price,
+                remaining,
+                status,
+            );
+            if let AccountUpdate::Order(update) = &mut update {
+                update.reason = reason;
+            }
+            updates.push(update);
+        }
+
+        self.open_order_record(taker_hash, order);
+        for update in updates {
+            self.push_account_update(update);
+        }
+    }
+
+    pub(super) fn report_cancel(&mut self, hash: H256, order: &Order, reason: TerminalReason) {
+        self.push_account_update(AccountUpdate::Order(OrderUpdate {
+            hash,
+            trader_address: order.trader_address,
+            symbol: order.symbol,
+            side: order.side,
+            price: order.price,
+            remaining: Decimal::ZERO,
+            status: OrderStatus::Cancelled,
+            reason: Some(reason),
+            timestamp: self.now,
+        }));
+    }
+
+    // reduce-only enforcement shrank a resting order to `remaining`
+    pub(super) fn report_reduce(&mut self, hash: H256, order: &Order, remaining: Decimal) {
+        let status = self
+            .order_records
+            .get(&hash)
+            .map_or(OrderStatus::Accepted, |record| record.status);
+        self.push_account_update(AccountUpdate::Order(OrderUpdate {
+            hash,
+            trader_address: order.trader_address,
+            symbol: order.symbol,
+            side: order.side,
+            price: order.price,
+            remaining,
+            status,
+            reason: None,
+            timestamp: self.now,
+        }));
+    }
+
+    fn push_account_update(&mut self, update: AccountUpdate) {
+        self.track_order(&update);
+        self.pending_account_updates.push(update);
+    }
+
+    // called with every accepted command after the state tree caught up,
+    // `moved` are the accounts whose balances it saw change
+    pub(super) fn publish_account_updates(&mut self, moved: Vec<Address>) {
+        let mut updates = std::mem::take(&mut self.pending_account_updates);
+        updates.extend(
+            moved
+                .into_iter()
+                .filter_map(|address| self.accounts.get(&address))
+                .map(|account| AccountUpdate::Balance(*account)),
+        );
+        if let Some(publisher) = self.publisher.as_mut() {
+            for update in updates {
+                publisher.publish_account(update);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        engine::tests::{account, perp_order},
+        MarketUpdate, Publisher,
+    };
+
+    struct Channel(mpsc::Sender<AccountUpdate>);
+
+    impl Publisher for Channel {
+        fn publish(&mut self, _update: MarketUpdate) {}
+
+        fn publish_account(&mut self, update: AccountUpdate) {
+            self.0.send(update).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_maker_learns_about_its_fills() {
+        let mut engine = Engine::new();
+        let (sender, receiver) = mpsc::channel();
+        let maker = Address::from_low_u64_be(1);
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        engine.create_account(account(2, dec!(1000))).unwrap();
+        engine.set_publisher(Box::new(Channel(sender)));
+
+        engine
+            .create_order(perp_order(1, Side::Ask, dec!(2), dec!(100), 1))
+            .unwrap();
+        let fills = engine
+            .create_order(perp_order(2, Side::Bid, dec!(1), dec!(100), 2))
+            .unwrap();
+        engine
+            .create_order(perp_order(2, Side::Bid, dec!(1), dec!(100), 3))
+            .unwrap();
+        // buying back the short at a loss moves the maker's balance
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(2), dec!(110), 4))
+            .unwrap();
+        engine
+            .create_order(perp_order(2, Side::Ask, dec!(2), dec!(110), 5))
+            .unwrap();
+
+        let updates: Vec<AccountUpdate> = receiver
+            .try_iter()
+            .filter(|update| update.trader_address() == maker)
+            .collect();
+        let statuses: Vec<(OrderStatus, Decimal)> = updates
+            .iter()
+            .filter_map(|update| match update {
+                AccountUpdate::Order(order) if order.price == dec!(100) => {
+                    Some((order.status, order.remaining))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (OrderStatus::Accepted, dec!(2)),
+                (OrderStatus::PartiallyFilled, dec!(1)),
+                (OrderStatus::Filled, dec!(0)),
+            ]
+        );
+        assert!(updates.iter().any(|update| matches!(
+            update,
+            AccountUpdate::Fill(fill)
+                if fill.order_hash == fills[0].maker_hash && fill.liquidity == Liquidity::Maker
+        )));
+        assert!(matches!(
+            updates.last(),
+            Some(AccountUpdate::Balance(account)) if account.usd_balance == dec!(980)
+        ));
+    }
+}
@@ -0,0 +1,199 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use web3::types::{Address, H256};
+
+use super::{error::Result, Engine};
+use crate::{Fill, FillKind, Side, Symbol};
+
+// number of buckets the queue is split into for display, 1 is first in line
+const ADL_BUCKETS: usize = 5;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdlRank {
+    pub symbol: Symbol,
+    pub side: Side,
+    // None when the position is not profitable and so cannot be deleveraged
+    pub rank: Option<usize>,
+    pub queue_length: usize,
+    pub bucket: Option<usize>,
+    pub score: Option<Decimal>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleverageFill {
+    pub counterparty: Address,
+    pub fill: Fill,
+}
+
+impl Engine {
+    // profitable positions on the given side of the market, highest
+    // pnl ratio x leverage first
+    fn adl_queue(&self, symbol: Symbol, side: Side) -> Vec<(Address, Decimal)> {
+        let mut queue: Vec<(Address, Decimal)> = self
+            .positions
+            .iter()
+            .filter(|(address, _)| **address != self.insurance_fund)
+            .filter_map(|(address, positions)| {
+                let position = positions.get(&symbol)?;
+                let on_side = match side {
+                    Side::Bid => position.size.is_sign_positive(),
+                    Side::Ask => position.size.is_sign_negative(),
+                };
+                if position.size.is_zero() || !on_side {
+                    return None;
+                }
+                let mark_price = self.mark_price(symbol).unwrap_or(position.entry_price);
+                let pnl = position.unrealized_pnl(mark_price);
+                let equity = self.position_equity(*address, symbol).ok()?;
+                if pnl <= Decimal::ZERO || equity <= Decimal::ZERO {
+                    return None;
+                }
+                let pnl_ratio = pnl / (position.size.abs() * position.entry_price);
+                let leverage = position.notional(mark_price) / equity;
+                Some((*address, pnl_ratio * leverage))
+            })
+            .collect();
+        queue.sort_by(|(a, a_score), (b, b_score)| b_score.cmp(a_score).then(a.cmp(b)));
+        queue
+    }
+
+    pub fn get_adl_ranks(&self, address: Address) -> Result<Vec<AdlRank>> {
+        Ok(self
+            .get_positions(address)?
+            .into_iter()
+            .map(|position| {
+                let side = if position.size.is_sign_positive() {
+                    Side::Bid
+                } else {
+                    Side::Ask
+                };
+                let queue = self.adl_queue(position.symbol, side);
+                let entry = queue
+                    .iter()
+                    .enumerate()
+                    .find(|(_, (queued, _))| *queued == address);
+                AdlRank {
+                    symbol: position.symbol,
+                    side,
+                    rank: entry.map(|(i, _)| i + 1),
+                    queue_length: queue.len(),
+                    bucket: entry.map(|(i, _)| i * ADL_BUCKETS / queue.len() + 1),
+                    score: entry.map(|(_, (_, score))| *score),
+                }
+            })
+            .collect())
+    }
+
+    // closes up to `amount` of a bankrupt account's position against the
+    // front of the opposing queue at the bankruptcy price, returns what was
+    // reduced
+    pub(super) fn deleverage(
+        &mut self,
+        address: Address,
+        symbol: Symbol,
+        amount: Decimal,
+        bankruptcy_price: Decimal,
+    ) -> Vec<DeleverageFill> {
+        let opposing = if amount.is_sign_positive() {
+            Side::Ask
+        } else {
+            Side::Bid
+        };
+        let mut remaining = amount;
+        let mut fills = vec![];
+        for (counterparty, _) in self.adl_queue(symbol, opposing) {
+            let size = self.positions[&counterparty][&symbol].size;
+            let reduce = if remaining.is_sign_positive() {
+                remaining.min(-size)
+            } else {
+                remaining.max(-size)
+            };
+            self.apply_perp_fill(address, symbol, -reduce, bankruptcy_price);
+            self.apply_perp_fill(counterparty, symbol, reduce, bankruptcy_price);
+            self.enforce_reduce_only(counterparty, symbol);
+            fills.push(DeleverageFill {
+                counterparty,
+                fill: Fill {
+                    maker_hash: H256::zero(),
+                    taker_hash: H256::zero(),
+                    fill_amount: reduce.abs(),
+                    price: bankruptcy_price,
+                    kind: FillKind::Deleverage,
+                },
+            });
+            remaining -= reduce;
+            if remaining.is_zero() {
+                break;
+            }
+        }
+        fills
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::engine::{
+        tests::{perp_order, priced_engine, trader},
+        Event,
+    };
+
+    #[test]
+    fn test_deleverage_when_fund_is_empty() {
+        // trader 1 is long 1 against two shorts, trader 3 far more levered
+        let (mut engine, clock) = priced_engine(
+            &[(0, dec!(100)), (10, dec!(90)), (20, dec!(70))],
+            &[dec!(20), dec!(1000), dec!(10)],
+        );
+        engine
+            .create_order(perp_order(2, Side::Ask, dec!(0.5), dec!(100), 1))
+            .unwrap();
+        engine
+            .create_order(perp_order(3, Side::Ask, dec!(0.5), dec!(100), 2))
+            .unwrap();
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(1), dec!(100), 3))
+            .unwrap();
+
+        clock.set(10);
+        assert!(engine.tick().is_empty());
+        let ranks = engine.get_adl_ranks(trader(3)).unwrap();
+        assert_eq!(ranks[0].side, Side::Ask);
+        assert_eq!(ranks[0].rank, Some(1));
+        assert_eq!(ranks[0].queue_length, 2);
+        assert_eq!(engine.get_adl_ranks(trader(2)).unwrap()[0].rank, Some(2));
+        // losing positions are not in the queue
+        assert_eq!(engine.get_adl_ranks(trader(1)).unwrap()[0].rank, None);
+
+        // empty book and empty fund, the shorts are closed at the bankruptcy price
+        clock.set(20);
+        let events = engine.tick();
+        let Event::Liquidation(event) = &events[0] else {
+            panic!("expected a liquidation");
+        };
+        let position = &event.positions[0];
+        assert_eq!(position.insurance_takeover, dec!(0));
+        assert_eq!(position.deleveraged.len(), 2);
+        assert_eq!(position.deleveraged[0].counterparty, trader(3));
+        assert_eq!(position.deleveraged[1].counterparty, trader(2));
+        assert!(position
+            .deleveraged
+            .iter()
+            .all(|deleverage| deleverage.fill.kind == FillKind::Deleverage
+                && deleverage.fill.price == dec!(80)));
+
+        for n in 1..=3 {
+            assert!(engine.get_positions(trader(n)).unwrap().is_empty());
+        }
+        assert_eq!(engine.get_account(trader(1)).unwrap().usd_balance, dec!(0));
+        assert_eq!(engine.get_account(trader(3)).unwrap().usd_balance, dec!(20));
+        assert_eq!(
+            engine.get_account(trader(2)).unwrap().usd_balance,
+            dec!(1010)
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use web3::{
+    signing::{hash_message, recover},
+    types::Address,
+};
+
+use rust_decimal::Decimal;
+
+use super::{deposit::Asset, position::MarginMode};
+use crate::{Side, Symbol};
+
+// signer of an eip-191 personal_sign signature over `message`, r || s || v
+// with v either 0/1 or 27/28. None when the signature is malformed
+pub fn recover_signer(message: &str, signature: &[u8]) -> Option<Address> {
+    let [rs @ .., v] = signature else {
+        return None;
+    };
+    if rs.len() != 64 {
+        return None;
+    }
+    let recovery_id = match v {
+        0 | 1 => *v as i32,
+        27 | 28 => *v as i32 - 27,
+        _ => return None,
+    };
+    recover(hash_message(message).as_bytes(), rs, recovery_id).ok()
+}
+
+// what a trader signs to open its private stream. `timestamp` is in
+// nanoseconds like every other, the server only takes recent ones so that a
+// signature seen by someone else is soon useless
+pub fn account_stream_message(trader_address: Address, timestamp: u128) -> String {
+    format!("DerivaDEX account stream\n{trader_address:?}\n{timestamp}")
+}
+
+// what a trader signs to delete its resting orders in one go, `symbol` and
+// `side` narrowing it down like they do for Engine::cancel_all. the ones not
+// given are written as ALL, so deleting only asks in every market reads
+// "DerivaDEX cancel all\n0x…\nALL\nASK\n<timestamp>"
+pub fn cancel_all_message(
+    trader_address: Address,
+    symbol: Option<Symbol>,
+    side: Option<Side>,
+    timestamp: u128,
+) -> String {
+    let symbol = symbol.map_or("ALL", |symbol| symbol.as_str());
+    let side = match side {
+        Some(Side::Bid) => "BID",
+        Some(Side::Ask) => "ASK",
+        None => "ALL",
+    };
+    format!("DerivaDEX cancel all\n{trader_address:?}\n{symbol}\n{side}\n{timestamp}")
+}
+
+// what a trader signs to switch a position between cross and isolated
+pub fn margin_mode_message(
+    trader_address: Address,
+    symbol: Symbol,
+    mode: MarginMode,
+    timestamp: u128,
+) -> String {
+    let mode = match mode {
+        MarginMode::Cross => "CROSS",
+        MarginMode::Isolated => "ISOLATED",
+    };
+    format!("DerivaDEX margin mode\n{trader_address:?}\n{symbol}\n{mode}\n{timestamp}")
+}
+
+// what a trader signs to move collateral into (positive) or out of an
+// isolated position. amounts are written without trailing zeros, 10.50 as
+// `10.5`
+pub fn isolated_margin_message(
+    trader_address: Address,
+    symbol: Symbol,
+    amount: Decimal,
+    timestamp: u128,
+) -> String {
+    let amount = amount.normalize();
+    format!("DerivaDEX isolated margin\n{trader_address:?}\n{symbol}\n{amount}\n{timestamp}")
+}
+
+// what a trader signs to request a withdrawal, amounts written as for
+// isolated_margin_message
+pub fn withdrawal_message(
+    trader_address: Address,
+    asset: Asset,
+    amount: Decimal,
+    timestamp: u128,
+) -> String {
+    let asset = match asset {
+        Asset::Ddx => "DDX",
+        Asset::Usd => "USD",
+    };
+    let amount = amount.normalize();
+    format!("DerivaDEX withdrawal\n{trader_address:?}\n{asset}\n{amount}\n{timestamp}")
+}
+
+#[cfg(test)]
+mod tests {
+    use secp256k1::SecretKey;
+    use web3::signing::{Key, SecretKeyRef};
+
+    use super::*;
+
+    #[test]
+    fn test_recovers_personal_sign_signer() {
+        let key = SecretKey::from_slice(&[7; 32]).unwrap();
+        let key = SecretKeyRef::new(&key);
+        let message = account_stream_message(key.address(), 1);
+        let signature = key.sign(hash_message(&message).as_bytes(), None).unwrap();
+        let bytes = [
+            signature.r.as_bytes(),
+            signature.s.as_bytes(),
+            &[signature.v as u8],
+        ]
+        .concat();
+
+        assert_eq!(recover_signer(&message, &bytes), Some(key.address()));
+        // signed for someone else, or a different time
+        let other = account_stream_message(Address::zero(), 1);
+        assert_ne!(recover_signer(&other, &bytes), Some(key.address()));
+        assert_eq!(recover_signer(&message, &bytes[1..]), None);
+    }
+}
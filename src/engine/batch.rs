@@ -0,0 +1,337 @@
+use rust_decimal::Decimal;
+use std::collections::{HashMap, HashSet};
+use web3::types::{Address, H256};
+
+use super::{
+    candles::{Candle, Interval},
+    error::{EngineError as Error, Result},
+    journal::Command,
+    order_status::{OrderRecord, TerminalReason},
+    orderbook::{BookChanges, OrderBookError},
+    position::Position,
+    Engine,
+};
+use crate::{Account, Fill, Order, Symbol};
+
+// what a market looked like before a batch traded in it. the tape and the
+// candles are only trimmed once a command is recorded, so cutting them back
+// to their old length undoes the batch
+struct MarketState {
+    trade_seq: u64,
+    last_price: Option<Decimal>,
+    trades: usize,
+    // length and last bar per interval, None for intervals without bars
+    candles: Vec<(Interval, Option<(usize, Candle)>)>,
+    // book changes not published yet
+    changes: BookChanges,
+}
+
+// the state an all-or-nothing batch touched, as it was before the first
+// order that could touch it. only what the batch's orders reach is kept, so
+// undoing costs about as much as placing them did
+#[derive(Default)]
+struct Undo {
+    accounts: HashMap<Address, Option<Account>>,
+    positions: HashMap<(Address, Symbol), Option<Position>>,
+    orders: HashMap<H256, (Symbol, Option<Order>)>,
+    reduce_only: HashMap<H256, bool>,
+    owners: HashMap<H256, (Option<Address>, Option<Symbol>)>,
+    records: HashMap<H256, Option<OrderRecord>>,
+    account_orders: HashMap<Address, usize>,
+    markets: HashMap<Symbol, MarketState>,
+    pending_trades: usize,
+    pending_account_updates: usize,
+}
+
+impl Undo {
+    fn new(engine: &Engine) -> Self {
+        Self {
+            pending_trades: engine.pending_trades.len(),
+            pending_account_updates: engine.pending_account_updates.len(),
+            ..Default::default()
+        }
+    }
+
+    // keeps whatever placing `order` may change: its trader and the traders
+    // of the orders it can reach, their positions and reduce-only orders in
+    // the market, and the market's tape
+    fn save(&mut self, engine: &Engine, order: &Order) {
+        let symbol = order.symbol;
+        let market = &engine.markets[&symbol];
+        let mut hashes = vec![market.book.order_hash(order)];
+        let mut traders = vec![order.trader_address];
+        for (hash, resting) in market.book.crossing_orders(order) {
+            hashes.push(hash);
+            traders.push(resting.trader_address);
+        }
+        for trader in &traders {
+            hashes.extend(
+                engine
+                    .resting_reduce_only(*trader, symbol)
+                    .into_iter()
+                    .map(|(hash, _)| hash),
+            );
+            self.accounts
+                .entry(*trader)
+                .or_insert_with(|| engine.accounts.get(trader).copied());
+            self.positions.entry((*trader, symbol)).or_insert_with(|| {
+                engine
+                    .positions
+                    .get(trader)
+                    .and_then(|positions| positions.get(&symbol))
+                    .copied()
+            });
+            self.account_orders
+                .entry(*trader)
+                .or_insert_with(|| engine.account_orders.get(trader).map_or(0, Vec::len));
+        }
+        for hash in hashes {
+            self.orders
+                .entry(hash)
+                .or_insert_with(|| (symbol, market.book.get_order(hash).ok()));
+            self.reduce_only
+                .entry(hash)
+                .or_insert_with(|| engine.reduce_only_orders.contains(&hash));
+            self.owners.entry(hash).or_insert_with(|| {
+                (
+                    engine.hash_to_address.get(&hash).copied(),
+                    engine.hash_to_symbol.get(&hash).copied(),
+                )
+            });
+            self.records
+                .entry(hash)
+                .or_insert_with(|| engine.order_records.get(&hash).cloned());
+        }
+        self.markets.entry(symbol).or_insert_with(|| MarketState {
+            trade_seq: market.trade_seq,
+            last_price: market.last_price,
+            trades: market.trades.len(),
+            candles: Interval::ALL
+                .iter()
+                .map(|interval| {
+                    let bars = market.candles.0.get(interval);
+                    let saved = bars.and_then(|bars| Some((bars.len(), *bars.back()?)));
+                    (*interval, saved)
+                })
+                .collect(),
+            changes: market.book.pending_changes(),
+        });
+    }
+
+    fn restore(self, engine: &mut Engine) {
+        for (address, account) in self.accounts {
+            match account {
+                Some(account) => engine.accounts.insert(address, account),
+                None => engine.accounts.remove(&address),
+            };
+            engine.state_tree.touch_account(address);
+        }
+        for ((address, symbol), position) in self.positions {
+            let positions = engine.positions.entry(address).or_default();
+            match position {
+                Some(position) => positions.insert(symbol, position),
+                None => positions.remove(&symbol),
+            };
+            if positions.is_empty() {
+                engine.positions.remove(&address);
+            }
+        }
+        for (hash, (symbol, order)) in self.orders {
+            let book = &mut engine.markets.get_mut(&symbol).unwrap().book;
+            match order {
+                Some(order) => book.reinstate(hash, order),
+                None => {
+                    let _ = book.delete_order(hash);
+                }
+            }
+        }
+        for (hash, reduce_only) in self.reduce_only {
+            match reduce_only {
+                true => engine.reduce_only_orders.insert(hash),
+                false => engine.reduce_only_orders.remove(&hash),
+            };
+        }
+        for (hash, (address, symbol)) in self.owners {
+            match address {
+                Some(address) => engine.hash_to_address.insert(hash, address),
+                None => engine.hash_to_address.remove(&hash),
+            };
+            match symbol {
+                Some(symbol) => engine.hash_to_symbol.insert(hash, symbol),
+                None => engine.hash_to_symbol.remove(&hash),
+            };
+        }
+        for (hash, record) in self.records {
+            match record {
+                Some(record) => engine.order_records.insert(hash, record),
+                None => engine.order_records.remove(&hash),
+            };
+        }
+        for (address, len) in self.account_orders {
+            if len == 0 {
+                engine.account_orders.remove(&address);
+            } else if let Some(hashes) = engine.account_orders.get_mut(&address) {
+                hashes.truncate(len);
+            }
+        }
+        for (symbol, state) in self.markets {
+            let market = engine.markets.get_mut(&symbol).unwrap();
+            market.trade_seq = state.trade_seq;
+            market.last_price = state.last_price;
+            market.trades.truncate(state.trades);
+            market.book.restore_changes(state.changes);
+            for (interval, saved) in state.candles {
+                match saved {
+                    Some((len, last)) => {
+                        let bars = market.candles.0.get_mut(&interval).unwrap();
+                        bars.truncate(len);
+                        *bars.back_mut().unwrap() = last;
+                    }
+                    None => {
+                        market.candles.0.remove(&interval);
+                    }
+                }
+            }
+        }
+        engine.pending_trades.truncate(self.pending_trades);
+        engine
+            .pending_account_updates
+            .truncate(self.pending_account_updates);
+    }
+}
+
+impl Engine {
+    // places the orders in turn, one result per order. with `atomic` they are
+    // placed all or not at all: each order first saves what it may change,
+    // and if any is rejected everything is put back, the others report
+    // BatchRolledBack and the engine is left as it was
+    pub fn create_orders(&mut self, orders: Vec<Order>, atomic: bool) -> Vec<Result<Vec<Fill>>> {
+        if !atomic {
+            return orders
+                .into_iter()
+                .map(|order| self.create_order(order))
+                .collect();
+        }
+        self.start_command();
+        let mut undo = Undo::new(self);
+        let results: Vec<Result<Vec<Fill>>> = orders
+            .iter()
+            .map(|order| {
+                undo.save(self, order);
+                self.place_order(*order)
+            })
+            .collect();
+        if results.iter().any(Result::is_err) {
+            undo.restore(self);
+            return results
+                .into_iter()
+                .map(|result| result.and(Err(Error::BatchRolledBack)))
+                .collect();
+        }
+        self.record(Command::CreateOrders {
+            timestamps: orders.iter().map(|order| order.timestamp).collect(),
+            orders,
+        });
+        results
+    }
+
+    // deletes the orders in turn, one result per hash. with `atomic` nothing
+    // is deleted unless every hash names a different resting order
+    pub fn delete_orders(&mut self, hashes: Vec<H256>, atomic: bool) -> Vec<Result<()>> {
+        if !atomic {
+            return hashes
+                .into_iter()
+                .map(|hash| self.delete_order(hash))
+                .collect();
+        }
+        self.start_command();
+        let mut seen = HashSet::new();
+        let checks: Vec<Result<()>> = hashes
+            .iter()
+            .map(|hash| match seen.insert(*hash) {
+                true => self.get_order(*hash).map(|_| ()),
+                // the first delete takes it out of the book
+                false => Err(OrderBookError::OrderNotFound(*hash).into()),
+            })
+            .collect();
+        if checks.iter().any(Result::is_err) {
+            return checks
+                .into_iter()
+                .map(|check| check.and(Err(Error::BatchRolledBack)))
+                .collect();
+        }
+
+        let results = hashes
+            .iter()
+            .map(|hash| self.cancel_order(*hash, TerminalReason::Trader))
+            .collect();
+        self.record(Command::DeleteOrders(hashes));
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use web3::types::Address;
+
+    use super::*;
+    use crate::{
+        engine::tests::{account, perp_order, temp_path},
+        Journal, ManualClock, Side, Symbol,
+    };
+
+    #[test]
+    fn test_atomic_batches_roll_back() {
+        let path = temp_path("batch");
+        let clock = ManualClock::new(1);
+        let mut engine = Engine::recover(&path, Box::new(clock.clone())).unwrap();
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        engine.create_account(account(2, dec!(1000))).unwrap();
+        engine
+            .create_order(perp_order(2, Side::Ask, dec!(2), dec!(101), 1))
+            .unwrap();
+        let before = engine.snapshot();
+
+        // the second quote takes part of the resting ask, the third is
+        // unaffordable
+        let quotes = vec![
+            perp_order(1, Side::Bid, dec!(1), dec!(100), 2),
+            perp_order(1, Side::Bid, dec!(1), dec!(101), 3),
+            perp_order(1, Side::Bid, dec!(1000), dec!(99), 4),
+        ];
+        let results = engine.create_orders(quotes.clone(), true);
+        assert!(matches!(results[0], Err(Error::BatchRolledBack)));
+        assert!(matches!(results[1], Err(Error::BatchRolledBack)));
+        assert!(matches!(results[2], Err(Error::InsufficientMargin(..))));
+        assert_eq!(engine.snapshot(), before);
+        // and leaves nothing for the next command to publish
+        let book = &mut engine.markets.get_mut(&Symbol::DdxPerp).unwrap().book;
+        assert!(book.take_order_changes().is_empty());
+        assert_eq!(book.take_level_changes(), (vec![], vec![]));
+
+        let results = engine.create_orders(quotes[..2].to_vec(), true);
+        assert_eq!(results[1].as_ref().unwrap().len(), 1);
+        let bid = engine.get_book(Symbol::DdxPerp).bids[0];
+        assert_eq!((bid.price, bid.amount), (dec!(100), dec!(1)));
+
+        // a hash twice in one batch is only there to delete once
+        let hash = engine.markets[&Symbol::DdxPerp].book.order_hash(&quotes[0]);
+        let results = engine.delete_orders(vec![hash, hash], true);
+        assert!(matches!(results[0], Err(Error::BatchRolledBack)));
+        assert!(engine.get_order(hash).is_ok());
+        assert!(engine.delete_orders(vec![hash], true)[0].is_ok());
+        drop(engine);
+
+        // each applied batch is one record and replays as one
+        let records = Journal::read(&path).unwrap();
+        assert_eq!(records.len(), 6);
+        let engine = Engine::recover(&path, Box::new(clock)).unwrap();
+        assert_eq!(
+            engine.get_positions(Address::from_low_u64_be(1)).unwrap()[0].size,
+            dec!(1)
+        );
+        assert!(engine.get_book(Symbol::DdxPerp).bids.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
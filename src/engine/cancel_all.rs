@@ -0,0 +1,94 @@
+use web3::types::{Address, H256};
+
+use super::{error::Result, journal::Command, order_status::TerminalReason, Engine};
+use crate::{Side, Symbol};
+
+impl Engine {
+    // deletes every resting order of the trader, or only those in `symbol`
+    // and/or on `side`, handing back what they reserved. returns the hashes
+    // deleted, market by market
+    pub fn cancel_all(
+        &mut self,
+        trader_address: Address,
+        symbol: Option<Symbol>,
+        side: Option<Side>,
+    ) -> Result<Vec<H256>> {
+        self.start_command();
+        self.get_account(trader_address)?;
+        let hashes: Vec<H256> = self
+            .markets
+            .iter()
+            .filter(|(market, _)| symbol.is_none() || symbol.as_ref() == Some(*market))
+            .flat_map(|(_, market)| {
+                market.book.trader_orders(trader_address).filter(|hash| {
+                    match (side, market.book.get_order(*hash)) {
+                        (Some(side), Ok(order)) => order.side == side,
+                        _ => true,
+                    }
+                })
+            })
+            .collect();
+        // the index only holds resting orders, but a failure half way must not
+        // leave the cancels before it out of the log
+        let cancelled = hashes
+            .into_iter()
+            .filter(|hash| self.cancel_order(*hash, TerminalReason::Trader).is_ok())
+            .collect();
+        self.record(Command::CancelAll {
+            trader_address,
+            symbol,
+            side,
+        });
+        Ok(cancelled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{
+        engine::tests::{account, perp_order},
+        Order, SystemClock,
+    };
+
+    #[test]
+    fn test_cancel_all_releases_reservations() {
+        let mut engine = Engine::new();
+        let trader = Address::from_low_u64_be(1);
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(1), dec!(99), 1))
+            .unwrap();
+        engine
+            .create_order(perp_order(1, Side::Ask, dec!(1), dec!(101), 2))
+            .unwrap();
+        engine
+            .create_order(Order {
+                symbol: Symbol::DdxUsd,
+                ..perp_order(1, Side::Bid, dec!(1), dec!(10), 3)
+            })
+            .unwrap();
+
+        let asks = engine
+            .cancel_all(trader, Some(Symbol::DdxPerp), Some(Side::Ask))
+            .unwrap();
+        assert_eq!(asks.len(), 1);
+        assert_eq!(engine.get_book(Symbol::DdxPerp).bids.len(), 1);
+        assert!(engine.get_book(Symbol::DdxPerp).asks.is_empty());
+
+        // the index comes back with the book from a snapshot
+        let (mut engine, _) =
+            Engine::from_snapshot(&engine.snapshot(), Box::new(SystemClock)).unwrap();
+        assert_eq!(engine.cancel_all(trader, None, None).unwrap().len(), 2);
+        assert!(engine.get_book(Symbol::DdxUsd).bids.is_empty());
+        let account = engine.get_account(trader).unwrap();
+        assert_eq!(
+            (account.usd_book_outstanding, account.ddx_book_outstanding),
+            (dec!(0), dec!(0))
+        );
+        assert_eq!(engine.get_margin(trader).unwrap().book_outstanding, dec!(0));
+        assert!(engine.cancel_all(trader, None, None).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,205 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+
+use super::{trades::Trade, Engine};
+use crate::Symbol;
+
+// bars kept per market and interval, a day and then some of 1m bars
+pub const CANDLE_HISTORY: usize = 2_000;
+// most bars one query returns
+pub const MAX_CANDLES: usize = 1_000;
+
+pub(super) const MINUTE: u128 = 60_000_000_000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub enum Interval {
+    #[serde(rename = "1m")]
+    OneMinute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "1h")]
+    OneHour,
+    #[serde(rename = "1d")]
+    OneDay,
+}
+
+impl Interval {
+    pub const ALL: [Interval; 4] = [
+        Interval::OneMinute,
+        Interval::FiveMinutes,
+        Interval::OneHour,
+        Interval::OneDay,
+    ];
+
+    // length in nanoseconds, bars open on multiples of it since the epoch
+    pub fn nanos(self) -> u128 {
+        match self {
+            Interval::OneMinute => MINUTE,
+            Interval::FiveMinutes => 5 * MINUTE,
+            Interval::OneHour => 60 * MINUTE,
+            Interval::OneDay => 24 * 60 * MINUTE,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Candle {
+    pub open_time: u128,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    pub volume: Decimal,
+    // usd traded
+    pub quote_volume: Decimal,
+    pub trades: u64,
+}
+
+impl Candle {
+    fn new(open_time: u128, trade: &Trade) -> Self {
+        Candle {
+            open_time,
+            open: trade.price,
+            high: trade.price,
+            low: trade.price,
+            close: trade.price,
+            volume: trade.amount,
+            quote_volume: trade.price * trade.amount,
+            trades: 1,
+        }
+    }
+
+    fn add(&mut self, trade: &Trade) {
+        self.high = self.high.max(trade.price);
+        self.low = self.low.min(trade.price);
+        self.close = trade.price;
+        self.volume += trade.amount;
+        self.quote_volume += trade.price * trade.amount;
+        self.trades += 1;
+    }
+}
+
+// one market's bars, oldest first. intervals without trades have no bar
+#[derive(Default)]
+pub struct Candles(pub(super) BTreeMap<Interval, VecDeque<Candle>>);
+
+impl Candles {
+    pub(super) fn record(&mut self, trade: &Trade) {
+        for interval in Interval::ALL {
+            let open_time = trade.timestamp - trade.timestamp % interval.nanos();
+            let bars = self.0.entry(interval).or_default();
+            match bars.back_mut() {
+                Some(bar) if bar.open_time == open_time => bar.add(trade),
+                _ => bars.push_back(Candle::new(open_time, trade)),
+            }
+        }
+    }
+
+    pub(super) fn trim(&mut self) {
+        for bars in self.0.values_mut() {
+            let excess = bars.len().saturating_sub(CANDLE_HISTORY);
+            bars.drain(..excess);
+        }
+    }
+}
+
+impl Engine {
+    // oldest first, bars opening at or after `since` or the latest ones
+    // without it
+    pub fn get_candles(
+        &self,
+        symbol: Symbol,
+        interval: Interval,
+        since: Option<u128>,
+        limit: usize,
+    ) -> Vec<Candle> {
+        let Some(bars) = self.markets[&symbol].candles.0.get(&interval) else {
+            return vec![];
+        };
+        let limit = limit.min(MAX_CANDLES);
+        match since {
+            Some(since) => bars
+                .iter()
+                .filter(|bar| bar.open_time >= since)
+                .take(limit)
+                .copied()
+                .collect(),
+            None => bars
+                .iter()
+                .skip(bars.len().saturating_sub(limit))
+                .copied()
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        engine::tests::{account, perp_order, temp_path},
+        ManualClock, Side, SystemClock,
+    };
+
+    #[test]
+    fn test_candles_are_rebuilt_on_replay() {
+        let path = temp_path("candles");
+        let clock = ManualClock::new(0);
+        let mut engine = Engine::recover(&path, Box::new(clock.clone())).unwrap();
+        engine.create_account(account(1, dec!(10000))).unwrap();
+        engine.create_account(account(2, dec!(10000))).unwrap();
+        // three prints in the first minute, one in the second
+        for (time, price) in [
+            (1, dec!(100)),
+            (2, dec!(104)),
+            (3, dec!(98)),
+            (61, dec!(101)),
+        ] {
+            clock.set(time * 1_000_000_000);
+            engine
+                .create_order(perp_order(1, Side::Ask, dec!(1), price, time.into()))
+                .unwrap();
+            engine
+                .create_order(perp_order(2, Side::Bid, dec!(1), price, time.into()))
+                .unwrap();
+        }
+
+        let minutes = engine.get_candles(Symbol::DdxPerp, Interval::OneMinute, None, 10);
+        assert_eq!(minutes.len(), 2);
+        assert_eq!(
+            (
+                minutes[0].open,
+                minutes[0].high,
+                minutes[0].low,
+                minutes[0].close
+            ),
+            (dec!(100), dec!(104), dec!(98), dec!(98))
+        );
+        assert_eq!(minutes[0].volume, dec!(3));
+        assert_eq!(minutes[1].open_time, MINUTE);
+        let hours = engine.get_candles(Symbol::DdxPerp, Interval::OneHour, None, 10);
+        assert_eq!((hours.len(), hours[0].trades), (1, 4));
+        assert_eq!(
+            engine.get_candles(Symbol::DdxPerp, Interval::OneMinute, Some(1), 10),
+            minutes[1..]
+        );
+        drop(engine);
+
+        let engine = Engine::recover(&path, Box::new(clock)).unwrap();
+        assert_eq!(
+            engine.get_candles(Symbol::DdxPerp, Interval::OneMinute, None, 10),
+            minutes
+        );
+        let (engine, _) = Engine::from_snapshot(&engine.snapshot(), Box::new(SystemClock)).unwrap();
+        assert_eq!(
+            engine.get_candles(Symbol::DdxPerp, Interval::OneHour, None, 10),
+            hours
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
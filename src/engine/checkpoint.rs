@@ -0,0 +1,356 @@
+use displaydoc::Display;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use thiserror::Error;
+use web3::{
+    ethabi::{self, ParamType, Token},
+    types::{Address, H256, U256},
+};
+
+use super::{
+    error::{EngineError, Result},
+    journal::Command,
+    merkle::decimal_to_int256,
+    withdrawal::WithdrawalClaim,
+    Engine,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BalanceDelta {
+    pub trader_address: Address,
+    pub ddx_delta: Decimal,
+    pub usd_delta: Decimal,
+}
+
+// everything that changed balances between two state roots, netted per
+// trader. the settlement contract accepts a checkpoint only on top of the
+// root it holds, so checkpoints have to be submitted in id order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Checkpoint {
+    // starts at 1 and has no gaps
+    pub id: u64,
+    pub timestamp: u128,
+    // zero for the first checkpoint
+    pub prev_root: H256,
+    pub state_root: H256,
+    // sorted by trader address, traders whose balances netted out are left out
+    pub deltas: Vec<BalanceDelta>,
+    // hashes of the withdrawals first committed to by this checkpoint, in
+    // nonce order. their amounts are already part of the deltas
+    pub withdrawals: Vec<H256>,
+}
+
+impl Checkpoint {
+    fn param_types() -> Vec<ParamType> {
+        vec![
+            ParamType::Uint(64),
+            ParamType::FixedBytes(32),
+            ParamType::FixedBytes(32),
+            ParamType::Array(Box::new(ParamType::Tuple(vec![
+                ParamType::Address,
+                ParamType::Int(256),
+                ParamType::Int(256),
+            ]))),
+            ParamType::Array(Box::new(ParamType::FixedBytes(32))),
+        ]
+    }
+
+    // abi.encode(uint64 id, bytes32 prevRoot, bytes32 stateRoot,
+    //     (address trader, int256 ddxDelta, int256 usdDelta)[] deltas,
+    //     bytes32[] withdrawals)
+    // with deltas in 18 decimals, the arguments of the settlement contract's
+    // submitCheckpoint
+    pub fn abi_encode(&self) -> Vec<u8> {
+        let int256 = |value| Token::Int(U256::from_big_endian(&decimal_to_int256(value)));
+        ethabi::encode(&[
+            Token::Uint(self.id.into()),
+            Token::FixedBytes(self.prev_root.as_bytes().to_vec()),
+            Token::FixedBytes(self.state_root.as_bytes().to_vec()),
+            Token::Array(
+                self.deltas
+                    .iter()
+                    .map(|delta| {
+                        Token::Tuple(vec![
+                            Token::Address(delta.trader_address),
+                            int256(delta.ddx_delta),
+                            int256(delta.usd_delta),
+                        ])
+                    })
+                    .collect(),
+            ),
+            Token::Array(
+                self.withdrawals
+                    .iter()
+                    .map(|hash| Token::FixedBytes(hash.as_bytes().to_vec()))
+                    .collect(),
+            ),
+        ])
+    }
+}
+
+// checkpoints produced so far and the balances the last one settled at
+#[derive(Default)]
+pub struct Checkpoints {
+    pub(super) history: Vec<Checkpoint>,
+    pub(super) balances: BTreeMap<Address, (Decimal, Decimal)>,
+}
+
+impl Engine {
+    // nets every balance change since the previous checkpoint against the
+    // current state root. None when the root has not moved, there is nothing
+    // to settle then
+    pub fn checkpoint(&mut self) -> Option<Checkpoint> {
+        self.start_command();
+        let prev_root = self
+            .checkpoints
+            .history
+            .last()
+            .map_or_else(H256::zero, |checkpoint| checkpoint.state_root);
+        if self.state_root() == prev_root {
+            return None;
+        }
+
+        let mut balances = BTreeMap::new();
+        for (address, account) in &self.accounts {
+            balances.insert(*address, (account.ddx_balance, account.usd_balance));
+        }
+        let traders: BTreeSet<&Address> = balances
+            .keys()
+            .chain(self.checkpoints.balances.keys())
+            .collect();
+        let zero = (Decimal::ZERO, Decimal::ZERO);
+        let deltas = traders
+            .into_iter()
+            .filter_map(|address| {
+                let (ddx, usd) = balances.get(address).unwrap_or(&zero);
+                let (prev_ddx, prev_usd) = self.checkpoints.balances.get(address).unwrap_or(&zero);
+                let delta = BalanceDelta {
+                    trader_address: *address,
+                    ddx_delta: (ddx - prev_ddx).normalize(),
+                    usd_delta: (usd - prev_usd).normalize(),
+                };
+                (!delta.ddx_delta.is_zero() || !delta.usd_delta.is_zero()).then_some(delta)
+            })
+            .collect();
+
+        let id = self.checkpoints.history.len() as u64 + 1;
+        let mut withdrawals: Vec<(u64, H256)> = self
+            .withdrawals
+            .values()
+            .filter(|(withdrawal, _)| withdrawal.checkpoint_id.is_none())
+            .map(|(withdrawal, _)| (withdrawal.nonce, withdrawal.hash))
+            .collect();
+        withdrawals.sort();
+        for (_, hash) in &withdrawals {
+            let proof = self.state_proof(*hash);
+            let (withdrawal, claim) = self.withdrawals.get_mut(hash).unwrap();
+            withdrawal.checkpoint_id = Some(id);
+            *claim = Some(proof);
+        }
+
+        let checkpoint = Checkpoint {
+            id,
+            timestamp: self.now,
+            prev_root,
+            state_root: self.state_root(),
+            deltas,
+            withdrawals: withdrawals.into_iter().map(|(_, hash)| hash).collect(),
+        };
+        self.checkpoints.history.push(checkpoint.clone());
+        self.checkpoints.balances = balances;
+        self.record(Command::Checkpoint);
+        Some(checkpoint)
+    }
+
+    pub fn get_checkpoint(&self, id: u64) -> Result<Checkpoint> {
+        id.checked_sub(1)
+            .and_then(|index| self.checkpoints.history.get(index as usize))
+            .cloned()
+            .ok_or(EngineError::CheckpointNotFound(id))
+    }
+}
+
+#[derive(Debug, Display, Error, PartialEq, Eq)]
+pub enum SettlementError {
+    /// malformed checkpoint batch: {0}
+    Malformed(String),
+
+    /// expected checkpoint {0}, got {1}
+    OutOfOrder(u64, u64),
+
+    /// checkpoint builds on root {1:?} but the contract holds {0:?}
+    RootMismatch(H256, H256),
+
+    /// checkpoint {0} was never submitted
+    UnknownCheckpoint(u64),
+
+    /// withdrawal {0:?} is not proven by its checkpoint
+    InvalidClaim(H256),
+
+    /// withdrawal {0:?} was already claimed
+    AlreadyClaimed(H256),
+}
+
+// in-process stand-in for the settlement contract on a local devnet. it
+// decodes batches from their abi encoding alone and holds balances as int256
+// words, so it settles exactly what the contract would
+#[derive(Debug, Default)]
+pub struct LocalSettlement {
+    pub last_id: u64,
+    pub state_root: H256,
+    pub balances: HashMap<Address, (U256, U256)>,
+    // every submitted root by checkpoint id, claims prove against the one
+    // they name
+    pub roots: HashMap<u64, H256>,
+    pub claimed: HashSet<H256>,
+}
+
+impl LocalSettlement {
+    pub fn submit(&mut self, batch: &[u8]) -> std::result::Result<(), SettlementError> {
+        let malformed = || SettlementError::Malformed("unexpected token".to_string());
+        let tokens = ethabi::decode(&Checkpoint::param_types(), batch)
+            .map_err(|e| SettlementError::Malformed(e.to_string()))?;
+        let [Token::Uint(id), Token::FixedBytes(prev_root), Token::FixedBytes(state_root), Token::Array(deltas), Token::Array(_)] =
+            &tokens[..]
+        else {
+            return Err(malformed());
+        };
+        let id = id.low_u64();
+        if id != self.last_id + 1 {
+            return Err(SettlementError::OutOfOrder(self.last_id + 1, id));
+        }
+        let prev_root = H256::from_slice(prev_root);
+        if prev_root != self.state_root {
+            return Err(SettlementError::RootMismatch(self.state_root, prev_root));
+        }
+
+        let mut balances = self.balances.clone();
+        for delta in deltas {
+            let Token::Tuple(fields) = delta else {
+                return Err(malformed());
+            };
+            let [Token::Address(trader), Token::Int(ddx), Token::Int(usd)] = &fields[..] else {
+                return Err(malformed());
+            };
+            let balance = balances.entry(*trader).or_default();
+            balance.0 = balance.0.overflowing_add(*ddx).0;
+            balance.1 = balance.1.overflowing_add(*usd).0;
+        }
+        self.balances = balances;
+        self.last_id = id;
+        self.state_root = H256::from_slice(state_root);
+        self.roots.insert(id, self.state_root);
+        Ok(())
+    }
+
+    // pays out a withdrawal once, the hash is recomputed from the claimed
+    // fields rather than taken from the claim
+    pub fn claim(&mut self, claim: &WithdrawalClaim) -> std::result::Result<(), SettlementError> {
+        let hash = claim.withdrawal.digest();
+        let id = claim.withdrawal.checkpoint_id.unwrap_or_default();
+        let root = self
+            .roots
+            .get(&id)
+            .ok_or(SettlementError::UnknownCheckpoint(id))?;
+        if claim.proof.key != hash || claim.proof.leaf != hash || !claim.proof.verify(*root) {
+            return Err(SettlementError::InvalidClaim(hash));
+        }
+        if !self.claimed.insert(hash) {
+            return Err(SettlementError::AlreadyClaimed(hash));
+        }
+        Ok(())
+    }
+
+    // ddx and usd balances as int256 words
+    pub fn balance(&self, trader: Address) -> (U256, U256) {
+        self.balances.get(&trader).copied().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{
+        engine::tests::{account, perp_order},
+        ManualClock, Side,
+    };
+
+    fn trade(engine: &mut Engine, buyer: u64, seller: u64, price: Decimal) {
+        engine
+            .create_order(perp_order(buyer, Side::Bid, dec!(1), price, 1))
+            .unwrap();
+        engine
+            .create_order(perp_order(seller, Side::Ask, dec!(1), price, 2))
+            .unwrap();
+    }
+
+    fn assert_settled(settlement: &LocalSettlement, engine: &Engine) {
+        assert_eq!(settlement.state_root, engine.state_root());
+        for account in engine.get_accounts() {
+            let word = |value| U256::from_big_endian(&decimal_to_int256(value));
+            assert_eq!(
+                settlement.balance(account.trader_address),
+                (word(account.ddx_balance), word(account.usd_balance))
+            );
+        }
+    }
+
+    #[test]
+    fn test_checkpoints_settle_on_devnet() {
+        let run = || {
+            let mut engine = Engine::with_clock(Box::new(ManualClock::new(0)));
+            engine.create_account(account(1, dec!(1000))).unwrap();
+            engine.create_account(account(2, dec!(1000))).unwrap();
+            let first = engine.checkpoint().unwrap();
+            trade(&mut engine, 1, 2, dec!(100));
+            trade(&mut engine, 2, 1, dec!(90));
+            (engine, first)
+        };
+        let (mut engine, first) = run();
+        assert_eq!(first.prev_root, H256::zero());
+        assert_eq!(first.deltas.len(), 2);
+
+        let mut settlement = LocalSettlement::default();
+        settlement.submit(&first.abi_encode()).unwrap();
+        let second = engine.checkpoint().unwrap();
+        assert_eq!(second.prev_root, first.state_root);
+        // the long closed at a loss of 10
+        assert_eq!(
+            second.deltas,
+            vec![
+                BalanceDelta {
+                    trader_address: Address::from_low_u64_be(1),
+                    ddx_delta: dec!(0),
+                    usd_delta: dec!(-10),
+                },
+                BalanceDelta {
+                    trader_address: Address::from_low_u64_be(2),
+                    ddx_delta: dec!(0),
+                    usd_delta: dec!(10),
+                },
+            ]
+        );
+        settlement.submit(&second.abi_encode()).unwrap();
+        assert_settled(&settlement, &engine);
+
+        // nothing moved since
+        assert!(engine.checkpoint().is_none());
+        // batches only apply on top of the root they were built from
+        assert_eq!(
+            settlement.submit(&first.abi_encode()),
+            Err(SettlementError::OutOfOrder(3, 1))
+        );
+
+        // the same commands give the same bytes
+        let (mut again, _) = run();
+        assert_eq!(
+            again.checkpoint().unwrap().abi_encode(),
+            second.abi_encode()
+        );
+    }
+}
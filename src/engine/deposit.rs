@@ -0,0 +1,104 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use web3::types::{Address, H256};
+
+use super::{
+    error::{EngineError, Result},
+    journal::Command,
+    Engine,
+};
+use crate::Account;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Asset {
+    Ddx,
+    Usd,
+}
+
+// a confirmed Deposit event, identified on chain by the transaction that
+// emitted it and its index among that block's logs
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Deposit {
+    pub tx_hash: H256,
+    pub log_index: u64,
+    pub trader_address: Address,
+    pub asset: Asset,
+    pub amount: Decimal,
+}
+
+impl Engine {
+    // credits a deposit, opening the account if the trader has none yet.
+    // returns false for a deposit that was already credited, sources may
+    // deliver the same event more than once
+    pub fn deposit(&mut self, deposit: Deposit) -> Result<bool> {
+        self.start_command();
+        if !deposit.amount.is_sign_positive() || deposit.amount.is_zero() {
+            return Err(EngineError::InvalidAmount(deposit.amount));
+        }
+        if !self.deposits.insert((deposit.tx_hash, deposit.log_index)) {
+            return Ok(false);
+        }
+        let account = self
+            .accounts
+            .entry(deposit.trader_address)
+            .or_insert_with(|| Account {
+                // same 18 decimal places as accounts opened directly
+                ddx_balance: Decimal::new(0, 18),
+                usd_balance: Decimal::new(0, 18),
+                trader_address: deposit.trader_address,
+                ddx_book_outstanding: Decimal::ZERO,
+                usd_book_outstanding: Decimal::ZERO,
+            });
+        match deposit.asset {
+            Asset::Ddx => account.ddx_balance += deposit.amount,
+            Asset::Usd => account.usd_balance += deposit.amount,
+        }
+        self.state_tree.touch_account(deposit.trader_address);
+        self.record(Command::Deposit(deposit));
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_deposits_are_credited_once() {
+        let mut engine = Engine::new();
+        let trader = Address::from_low_u64_be(1);
+        let deposit = Deposit {
+            tx_hash: H256::repeat_byte(1),
+            log_index: 0,
+            trader_address: trader,
+            asset: Asset::Usd,
+            amount: dec!(100),
+        };
+        assert!(engine.deposit(deposit.clone()).unwrap());
+        assert!(!engine.deposit(deposit.clone()).unwrap());
+        // another log of the same transaction
+        engine
+            .deposit(Deposit {
+                log_index: 1,
+                asset: Asset::Ddx,
+                ..deposit.clone()
+            })
+            .unwrap();
+
+        let account = engine.get_account(trader).unwrap();
+        assert_eq!(account.usd_balance, dec!(100));
+        assert_eq!(account.ddx_balance, dec!(100));
+        assert!(matches!(
+            engine.deposit(Deposit {
+                log_index: 2,
+                amount: dec!(0),
+                ..deposit
+            }),
+            Err(EngineError::InvalidAmount(_))
+        ));
+    }
+}
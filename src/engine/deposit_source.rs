@@ -0,0 +1,387 @@
+use displaydoc::Display;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
+use thiserror::Error;
+use web3::{
+    ethabi::{self, ParamType, Token},
+    futures::future::{self, BoxFuture, FutureExt},
+    signing::keccak256,
+    transports::Http,
+    types::{Address, BlockId, BlockNumber, FilterBuilder, H256, U256},
+    Web3,
+};
+
+use super::deposit::{Asset, Deposit};
+
+#[derive(Debug, Display, Error)]
+pub enum DepositError {
+    /// could not read the deposit fixture: {0}
+    Io(#[from] std::io::Error),
+
+    /// could not decode the deposit fixture: {0}
+    Json(#[from] serde_json::Error),
+
+    /// json-rpc request failed: {0}
+    Rpc(#[from] web3::Error),
+
+    /// malformed Deposit log in transaction {0:?}
+    MalformedLog(H256),
+
+    /// deposit amount {1} in transaction {0:?} is out of range
+    AmountOutOfRange(H256, U256),
+
+    /// block {0} was replaced after its deposits were credited
+    DeepReorg(u64),
+}
+
+type Result<T> = std::result::Result<T, DepositError>;
+
+// a Deposit(address trader, uint256 amount, address token) log, none of the
+// arguments indexed, amounts in 18 decimals
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DepositLog {
+    pub tx_hash: H256,
+    pub log_index: u64,
+    pub trader_address: Address,
+    pub amount: U256,
+    pub token: Address,
+}
+
+// read access to the chain the deposit contract lives on
+pub trait DepositSource: Send {
+    fn block_number(&mut self) -> BoxFuture<'_, Result<u64>>;
+
+    // None past the head
+    fn block_hash(&mut self, number: u64) -> BoxFuture<'_, Result<Option<H256>>>;
+
+    // deposit logs of blocks `from` to `to` inclusive, in chain order
+    fn deposit_logs(&mut self, from: u64, to: u64) -> BoxFuture<'_, Result<Vec<DepositLog>>>;
+}
+
+pub struct RpcDepositSource {
+    web3: Web3<Http>,
+    contract: Address,
+}
+
+impl RpcDepositSource {
+    pub fn new(url: &str, contract: Address) -> Result<Self> {
+        Ok(Self {
+            web3: Web3::new(Http::new(url)?),
+            contract,
+        })
+    }
+
+    fn topic() -> H256 {
+        H256(keccak256(b"Deposit(address,uint256,address)"))
+    }
+}
+
+impl DepositSource for RpcDepositSource {
+    fn block_number(&mut self) -> BoxFuture<'_, Result<u64>> {
+        async move { Ok(self.web3.eth().block_number().await?.as_u64()) }.boxed()
+    }
+
+    fn block_hash(&mut self, number: u64) -> BoxFuture<'_, Result<Option<H256>>> {
+        async move {
+            let block = self
+                .web3
+                .eth()
+                .block(BlockId::Number(BlockNumber::Number(number.into())))
+                .await?;
+            Ok(block.and_then(|block| block.hash))
+        }
+        .boxed()
+    }
+
+    fn deposit_logs(&mut self, from: u64, to: u64) -> BoxFuture<'_, Result<Vec<DepositLog>>> {
+        async move {
+            let filter = FilterBuilder::default()
+                .address(vec![self.contract])
+                .topics(Some(vec![Self::topic()]), None, None, None)
+                .from_block(BlockNumber::Number(from.into()))
+                .to_block(BlockNumber::Number(to.into()))
+                .build();
+            let types = [ParamType::Address, ParamType::Uint(256), ParamType::Address];
+            let mut logs = vec![];
+            for log in self.web3.eth().logs(filter).await? {
+                let tx_hash = log.transaction_hash.unwrap_or_default();
+                let malformed = || DepositError::MalformedLog(tx_hash);
+                let tokens = ethabi::decode(&types, &log.data.0).map_err(|_| malformed())?;
+                let [Token::Address(trader_address), Token::Uint(amount), Token::Address(token)] =
+                    tokens[..]
+                else {
+                    return Err(malformed());
+                };
+                logs.push(DepositLog {
+                    tx_hash,
+                    log_index: log.log_index.ok_or_else(malformed)?.as_u64(),
+                    trader_address,
+                    amount,
+                    token,
+                });
+            }
+            Ok(logs)
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FixtureBlock {
+    pub number: u64,
+    pub hash: H256,
+    #[serde(default)]
+    pub deposits: Vec<DepositLog>,
+}
+
+// a chain described by a json array of blocks, re-read on every call so that
+// a test can append blocks or rewrite them to simulate a reorg
+pub struct FixtureDepositSource {
+    path: PathBuf,
+}
+
+impl FixtureDepositSource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn blocks(&self) -> Result<Vec<FixtureBlock>> {
+        Ok(serde_json::from_slice(&std::fs::read(&self.path)?)?)
+    }
+}
+
+impl DepositSource for FixtureDepositSource {
+    fn block_number(&mut self) -> BoxFuture<'_, Result<u64>> {
+        let blocks = self.blocks();
+        future::ready(
+            blocks.map(|blocks| blocks.iter().map(|block| block.number).max().unwrap_or(0)),
+        )
+        .boxed()
+    }
+
+    fn block_hash(&mut self, number: u64) -> BoxFuture<'_, Result<Option<H256>>> {
+        let blocks = self.blocks();
+        future::ready(blocks.map(|blocks| {
+            blocks
+                .into_iter()
+                .find(|block| block.number == number)
+                .map(|block| block.hash)
+        }))
+        .boxed()
+    }
+
+    fn deposit_logs(&mut self, from: u64, to: u64) -> BoxFuture<'_, Result<Vec<DepositLog>>> {
+        let blocks = self.blocks();
+        future::ready(blocks.map(|mut blocks| {
+            blocks.sort_by_key(|block| block.number);
+            blocks
+                .into_iter()
+                .filter(|block| (from..=to).contains(&block.number))
+                .flat_map(|block| block.deposits)
+                .collect()
+        }))
+        .boxed()
+    }
+}
+
+// turns a source's logs into deposits once they are `confirmations` blocks
+// deep. reorgs shallower than that never reach the engine, a deeper one that
+// replaces an already credited block stops ingestion until it is dealt with
+pub struct DepositWatcher {
+    source: Box<dyn DepositSource>,
+    confirmations: u64,
+    tokens: HashMap<Address, Asset>,
+    // first block not yet scanned
+    next_block: u64,
+    // hash of the last scanned block
+    last_hash: Option<H256>,
+    // logs that cannot be credited, by transaction hash and log index, kept
+    // aside so that they do not hold up the deposits after them
+    quarantined: BTreeMap<(H256, u64), DepositLog>,
+}
+
+impl DepositWatcher {
+    // `tokens` maps token contracts to the balance their deposits credit,
+    // deposits of any other token are ignored
+    pub fn new(
+        source: Box<dyn DepositSource>,
+        confirmations: u64,
+        tokens: HashMap<Address, Asset>,
+        from_block: u64,
+    ) -> Self {
+        Self {
+            source,
+            confirmations: confirmations.max(1),
+            tokens,
+            next_block: from_block,
+            last_hash: None,
+            quarantined: BTreeMap::new(),
+        }
+    }
+
+    pub fn quarantined(&self) -> impl Iterator<Item = &DepositLog> {
+        self.quarantined.values()
+    }
+
+    // deposits that became confirmed since the last poll. after a restart
+    // blocks are scanned again from `from_block`, the engine ignores deposits
+    // it already credited
+    pub async fn poll(&mut self) -> Result<Vec<Deposit>> {
+        let head = self.source.block_number().await?;
+        let Some(confirmed) = (head + 1).checked_sub(self.confirmations) else {
+            return Ok(vec![]);
+        };
+        if confirmed < self.next_block {
+            return Ok(vec![]);
+        }
+        if let Some(last_hash) = self.last_hash {
+            let last_block = self.next_block - 1;
+            if self.source.block_hash(last_block).await? != Some(last_hash) {
+                return Err(DepositError::DeepReorg(last_block));
+            }
+        }
+
+        let tip = self.source.block_hash(confirmed).await?;
+        let logs = self.source.deposit_logs(self.next_block, confirmed).await?;
+        // the logs may come from a fork that was replaced while they were
+        // fetched, they are fetched again on the next poll
+        if tip.is_none() || self.source.block_hash(confirmed).await? != tip {
+            return Ok(vec![]);
+        }
+
+        let mut deposits = vec![];
+        for log in logs {
+            let Some(asset) = self.tokens.get(&log.token) else {
+                continue;
+            };
+            let Some(amount) = i128::try_from(log.amount)
+                .ok()
+                .and_then(|amount| Decimal::try_from_i128_with_scale(amount, 18).ok())
+            else {
+                let e = DepositError::AmountOutOfRange(log.tx_hash, log.amount);
+                eprintln!("quarantined deposit log {}: {}", log.log_index, e);
+                self.quarantined.insert((log.tx_hash, log.log_index), log);
+                continue;
+            };
+            deposits.push(Deposit {
+                tx_hash: log.tx_hash,
+                log_index: log.log_index,
+                trader_address: log.trader_address,
+                asset: *asset,
+                amount,
+            });
+        }
+        self.next_block = confirmed + 1;
+        self.last_hash = tip;
+        Ok(deposits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use serde_json::json;
+    use web3::futures::executor::block_on;
+
+    use super::*;
+    use crate::engine::tests::temp_path;
+
+    #[test]
+    fn test_confirmations_and_reorgs() {
+        let path = temp_path("deposits.json");
+        let usd = Address::repeat_byte(0xee);
+        let deposit = |tx: u8, amount: u64| {
+            json!({
+                "txHash": H256::repeat_byte(tx),
+                "logIndex": 0,
+                "traderAddress": Address::from_low_u64_be(1),
+                "amount": U256::from(amount) * U256::exp10(18),
+                "token": usd,
+            })
+        };
+        let write = |blocks: serde_json::Value| std::fs::write(&path, blocks.to_string()).unwrap();
+
+        write(json!([
+            {"number": 1, "hash": H256::repeat_byte(0xa1), "deposits": [deposit(1, 10)]},
+            {"number": 2, "hash": H256::repeat_byte(0xa2), "deposits": [deposit(2, 20)]},
+        ]));
+        let mut watcher = DepositWatcher::new(
+            Box::new(FixtureDepositSource::new(&path)),
+            2,
+            HashMap::from([(usd, Asset::Usd)]),
+            0,
+        );
+        // block 2 still needs a confirmation
+        let deposits = block_on(watcher.poll()).unwrap();
+        assert_eq!(deposits.len(), 1);
+        assert_eq!(deposits[0].amount, dec!(10));
+
+        // block 2 is replaced before it is confirmed
+        write(json!([
+            {"number": 1, "hash": H256::repeat_byte(0xa1), "deposits": [deposit(1, 10)]},
+            {"number": 2, "hash": H256::repeat_byte(0xb2), "deposits": [deposit(3, 30)]},
+            {"number": 3, "hash": H256::repeat_byte(0xb3)},
+        ]));
+        let deposits = block_on(watcher.poll()).unwrap();
+        assert_eq!(deposits.len(), 1);
+        assert_eq!(deposits[0].tx_hash, H256::repeat_byte(3));
+        assert!(block_on(watcher.poll()).unwrap().is_empty());
+
+        // replacing a confirmed block is not something to carry on from
+        write(json!([
+            {"number": 1, "hash": H256::repeat_byte(0xa1)},
+            {"number": 2, "hash": H256::repeat_byte(0xc2)},
+            {"number": 3, "hash": H256::repeat_byte(0xc3)},
+            {"number": 4, "hash": H256::repeat_byte(0xc4)},
+        ]));
+        assert!(matches!(
+            block_on(watcher.poll()),
+            Err(DepositError::DeepReorg(2))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_out_of_range_amounts_are_quarantined() {
+        let path = temp_path("quarantine.json");
+        let usd = Address::repeat_byte(0xee);
+        let deposit = |tx: u8, amount: U256| {
+            json!({
+                "txHash": H256::repeat_byte(tx),
+                "logIndex": 0,
+                "traderAddress": Address::from_low_u64_be(1),
+                "amount": amount,
+                "token": usd,
+            })
+        };
+        std::fs::write(
+            &path,
+            json!([
+                {"number": 1, "hash": H256::repeat_byte(0xa1), "deposits": [deposit(1, U256::MAX)]},
+                {"number": 2, "hash": H256::repeat_byte(0xa2), "deposits": [deposit(2, U256::exp10(18))]},
+            ])
+            .to_string(),
+        )
+        .unwrap();
+        let mut watcher = DepositWatcher::new(
+            Box::new(FixtureDepositSource::new(&path)),
+            1,
+            HashMap::from([(usd, Asset::Usd)]),
+            0,
+        );
+        let deposits = block_on(watcher.poll()).unwrap();
+        assert_eq!(deposits.len(), 1);
+        assert_eq!(deposits[0].amount, dec!(1));
+        let quarantined: Vec<&DepositLog> = watcher.quarantined().collect();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].tx_hash, H256::repeat_byte(1));
+        assert!(block_on(watcher.poll()).unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
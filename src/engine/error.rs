@@ -1,9 +1,10 @@
 use displaydoc::Display;
 use rust_decimal::Decimal;
 use thiserror::Error;
-use web3::types::Address;
+use web3::types::{Address, H256};
 
 use super::orderbook::OrderBookError;
+use crate::Symbol;
 
 pub type Result<T> = std::result::Result<T, EngineError>;
 
@@ -18,9 +19,54 @@ pub enum EngineError {
     /// account with address {0} not found
     AccountNotFound(Address),
 
+    /// account with address {0} is reserved for the exchange
+    ReservedAccount(Address),
+
+    /// account with address {0} still has positions, orders or withdrawals open
+    AccountInUse(Address),
+
     /// insufficient balance {0} for order cost {1}
     InsufficientBalance(Decimal, Decimal),
 
+    /// insufficient free collateral {0} for initial margin {1}
+    InsufficientMargin(Decimal, Decimal),
+
+    /// market {0} is not a perpetual market
+    NotPerpetual(Symbol),
+
+    /// no open position in {0} to reduce
+    NoPosition(Symbol),
+
+    /// reduce-only order would increase or flip the position in {0}
+    WouldIncreasePosition(Symbol),
+
+    /// margin mode in {0} cannot change while a position or order is open
+    PositionOpen(Symbol),
+
+    /// position in {0} is not in isolated margin mode
+    NotIsolated(Symbol),
+
+    /// amount {0} must be positive
+    InvalidAmount(Decimal),
+
+    /// checkpoint {0} not found
+    CheckpointNotFound(u64),
+
+    /// withdrawal {0:?} not found
+    WithdrawalNotFound(H256),
+
+    /// withdrawal {0:?} is not in a checkpoint yet
+    WithdrawalPending(H256),
+
+    /// price {0} must be positive
+    InvalidPrice(Decimal),
+
+    /// price grouping {0} must be positive
+    InvalidGrouping(Decimal),
+
+    /// not applied, another item of the all-or-nothing batch failed
+    BatchRolledBack,
+
     /// orderbook error: {0}
     OrderBookError(#[from] OrderBookError),
 }
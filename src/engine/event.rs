@@ -0,0 +1,11 @@
+use serde::Serialize;
+
+use super::{funding::FundingSettlement, liquidation::LiquidationEvent};
+
+// things the engine did on its own rather than in response to a request
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "camelCase")]
+pub enum Event {
+    Funding(FundingSettlement),
+    Liquidation(LiquidationEvent),
+}
@@ -0,0 +1,88 @@
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::Serialize;
+
+use super::orderbook::OrderBook;
+use crate::{Side, Symbol};
+
+pub const NANOS_PER_HOUR: u128 = 3_600_000_000_000;
+
+#[derive(Debug, Copy, Clone)]
+pub struct FundingParams {
+    // nanoseconds between settlements
+    pub interval: u128,
+    // per interval
+    pub interest_rate: Decimal,
+    // bound on how far the interest component can move the rate away from the premium
+    pub premium_clamp: Decimal,
+    // bound on the final rate, either direction
+    pub max_rate: Decimal,
+    // usd notional used to find impact prices
+    pub impact_notional: Decimal,
+}
+
+impl Default for FundingParams {
+    fn default() -> Self {
+        Self {
+            interval: 8 * NANOS_PER_HOUR,
+            interest_rate: dec!(0.0001),
+            premium_clamp: dec!(0.0005),
+            max_rate: dec!(0.0075),
+            impact_notional: dec!(1000),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FundingSettlement {
+    pub symbol: Symbol,
+    pub timestamp: u128,
+    pub index_price: Decimal,
+    pub premium_index: Decimal,
+    pub funding_rate: Decimal,
+}
+
+// premium of the book over the index, using impact prices when the book is
+// deep enough for them and falling back to the mid otherwise
+pub fn premium_index(book: &OrderBook, index_price: Decimal, impact_notional: Decimal) -> Decimal {
+    if let (Some(impact_bid), Some(impact_ask)) = (
+        book.impact_price(Side::Bid, impact_notional),
+        book.impact_price(Side::Ask, impact_notional),
+    ) {
+        return ((impact_bid - index_price).max(Decimal::ZERO)
+            - (index_price - impact_ask).max(Decimal::ZERO))
+            / index_price;
+    }
+    match book.mid_price() {
+        Some(mid) => (mid - index_price) / index_price,
+        None => Decimal::ZERO,
+    }
+}
+
+// positive means longs pay shorts
+pub fn funding_rate(premium_index: Decimal, params: &FundingParams) -> Decimal {
+    let interest = (params.interest_rate - premium_index)
+        .max(-params.premium_clamp)
+        .min(params.premium_clamp);
+    (premium_index + interest)
+        .max(-params.max_rate)
+        .min(params.max_rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_funding_rate_clamps() {
+        let params = FundingParams::default();
+        // small premium is pulled to the interest rate
+        assert_eq!(funding_rate(dec!(0.0003), &params), dec!(0.0001));
+        // large premium only moves by the clamp
+        assert_eq!(funding_rate(dec!(0.002), &params), dec!(0.0015));
+        // and is capped overall
+        assert_eq!(funding_rate(dec!(0.05), &params), dec!(0.0075));
+        assert_eq!(funding_rate(dec!(-0.05), &params), dec!(-0.0075));
+    }
+}
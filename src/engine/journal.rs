@@ -0,0 +1,623 @@
+use displaydoc::Display;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use web3::types::{Address, H256};
+
+use super::{
+    deposit::{Asset, Deposit},
+    error::EngineError,
+    Clock, Engine, Event, ManualClock, MarginMode,
+};
+use crate::{Account, Fill, Order, Side, Symbol};
+
+#[derive(Debug, Display, Error)]
+pub enum JournalError {
+    /// could not access the event log: {0}
+    Io(#[from] std::io::Error),
+
+    /// corrupt event log record at byte {0}
+    Corrupt(u64),
+
+    /// could not decode event log record: {0}
+    Json(#[from] serde_json::Error),
+
+    /// replaying event log record {0} failed: {1}
+    Replay(u64, EngineError),
+
+    /// event log record {0} is missing
+    Missing(u64),
+}
+
+// every state change the engine accepted, in the order it accepted them
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "camelCase")]
+pub enum Command {
+    // first record of a log, pins the time the engine was created at so that
+    // replay schedules funding the same way
+    Init,
+    CreateAccount(Account),
+    DeleteAccount(Address),
+    // the order timestamp is not part of the order's json
+    CreateOrder {
+        order: Order,
+        timestamp: u128,
+    },
+    DeleteOrder(H256),
+    // an all-or-nothing batch, applied in full or not at all
+    CreateOrders {
+        orders: Vec<Order>,
+        timestamps: Vec<u128>,
+    },
+    DeleteOrders(Vec<H256>),
+    #[serde(rename_all = "camelCase")]
+    CancelAll {
+        trader_address: Address,
+        symbol: Option<Symbol>,
+        side: Option<Side>,
+    },
+    #[serde(rename_all = "camelCase")]
+    SetMarginMode {
+        trader_address: Address,
+        symbol: Symbol,
+        mode: MarginMode,
+    },
+    #[serde(rename_all = "camelCase")]
+    AdjustIsolatedMargin {
+        trader_address: Address,
+        symbol: Symbol,
+        amount: Decimal,
+    },
+    #[serde(rename_all = "camelCase")]
+    SetIndexPrice {
+        symbol: Symbol,
+        index_price: Decimal,
+    },
+    // index prices pulled from the feed, only recorded when the tick changed
+    // something
+    #[serde(rename_all = "camelCase")]
+    Tick {
+        index_prices: Vec<(Symbol, Decimal)>,
+    },
+    Checkpoint,
+    Deposit(Deposit),
+    #[serde(rename_all = "camelCase")]
+    Withdraw {
+        trader_address: Address,
+        asset: Asset,
+        amount: Decimal,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Record {
+    // starts at 1 and has no gaps
+    pub seq: u64,
+    // engine clock when the command was applied
+    pub timestamp: u128,
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReplayedFill {
+    // record that generated the fill
+    pub seq: u64,
+    pub timestamp: u128,
+    pub fill: Fill,
+}
+
+// append-only log of records, each framed as a little endian u32 length and
+// crc32 of the payload followed by the json payload. once a snapshot covers
+// them the records are rotated out into a segment next to the log, named
+// after it with the seq of the segment's last record appended
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    // seq of the first record in the file, next_seq while it is empty
+    first_seq: u64,
+    next_seq: u64,
+}
+
+const HEADER_LEN: usize = 8;
+
+// called when a command the engine already applied cannot be logged. the
+// state in memory is then ahead of the log, so it must not return and let
+// the engine take another command
+pub type JournalFailure = fn(JournalError) -> !;
+
+pub(super) fn panic_on_journal_failure(e: JournalError) -> ! {
+    panic!("failed to append to the event log: {}", e)
+}
+
+impl Journal {
+    // opens or creates the log and returns the records already in it, not
+    // those rotated out. a partially written record at the end is what a
+    // crash mid-append leaves behind, it was never acknowledged and is cut off
+    pub fn open(path: impl AsRef<Path>) -> Result<(Self, Vec<Record>), JournalError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = Self::create(&path)?;
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)?;
+        let (records, valid_len) = Self::decode(&bytes)?;
+        if valid_len < bytes.len() {
+            file.set_len(valid_len as u64)?;
+            file.sync_data()?;
+        }
+        let next_seq = match records.last() {
+            Some(record) => record.seq + 1,
+            None => Self::segments(&path)?.last().map_or(1, |(seq, _)| seq + 1),
+        };
+        let first_seq = records.first().map_or(next_seq, |record| record.seq);
+        let journal = Self {
+            path,
+            file,
+            first_seq,
+            next_seq,
+        };
+        Ok((journal, records))
+    }
+
+    fn create(path: &Path) -> Result<File, JournalError> {
+        Ok(OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?)
+    }
+
+    // every record the log has held, rotated segments first
+    pub fn read(path: impl AsRef<Path>) -> Result<Vec<Record>, JournalError> {
+        let path = path.as_ref();
+        let mut records = Self::archived(path)?;
+        records.extend(Self::decode(&std::fs::read(path)?)?.0);
+        Self::check_history(&records)?;
+        Ok(records)
+    }
+
+    // the records of every segment rotated out of the log at `path`
+    fn archived(path: &Path) -> Result<Vec<Record>, JournalError> {
+        let mut records = vec![];
+        for (_, segment) in Self::segments(path)? {
+            records.extend(Self::decode(&std::fs::read(segment)?)?.0);
+        }
+        Ok(records)
+    }
+
+    // (last seq, path) of the segments rotated out of the log at `path`,
+    // oldest first
+    fn segments(path: &Path) -> Result<Vec<(u64, PathBuf)>, JournalError> {
+        let prefix = format!(
+            "{}.",
+            path.file_name().unwrap_or_default().to_string_lossy()
+        );
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut segments = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let seq = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix(&prefix)?.parse().ok());
+            if let Some(seq) = seq {
+                segments.push((seq, entry.path()));
+            }
+        }
+        segments.sort();
+        Ok(segments)
+    }
+
+    // a full history starts at the first record and has no gaps
+    fn check_history(records: &[Record]) -> Result<(), JournalError> {
+        for (expected, record) in (1..).zip(records) {
+            if record.seq != expected {
+                return Err(JournalError::Missing(expected));
+            }
+        }
+        Ok(())
+    }
+
+    // decoded records and the length of the prefix they were decoded from.
+    // the first record can have any seq, a rotated log does not start at 1
+    fn decode(bytes: &[u8]) -> Result<(Vec<Record>, usize), JournalError> {
+        let mut records: Vec<Record> = vec![];
+        let mut offset = 0;
+        while offset + HEADER_LEN <= bytes.len() {
+            let len = u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+            let crc = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().unwrap());
+            let end = offset + HEADER_LEN + len;
+            if end > bytes.len() {
+                break;
+            }
+            let payload = &bytes[offset + HEADER_LEN..end];
+            if crc32fast::hash(payload) != crc {
+                // only the last record can be torn, anything before it was synced
+                if end == bytes.len() {
+                    break;
+                }
+                return Err(JournalError::Corrupt(offset as u64));
+            }
+            let record: Record = serde_json::from_slice(payload)?;
+            let expected = records.last().map_or(record.seq, |last| last.seq + 1);
+            if record.seq != expected {
+                return Err(JournalError::Corrupt(offset as u64));
+            }
+            records.push(record);
+            offset = end;
+        }
+        Ok((records, offset))
+    }
+
+    // 0 for an empty log
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    // seq of the oldest record still in the log rather than a segment
+    pub fn first_seq(&self) -> u64 {
+        self.first_seq
+    }
+
+    // moves every record written so far into a segment and carries on in an
+    // empty log. a crash between the two leaves the segment and no log, which
+    // open starts over after the segment's last record
+    pub fn rotate(&mut self) -> Result<(), JournalError> {
+        if self.first_seq == self.next_seq {
+            return Ok(());
+        }
+        let mut segment = self.path.clone().into_os_string();
+        segment.push(format!(".{}", self.last_seq()));
+        std::fs::rename(&self.path, segment)?;
+        self.file = Self::create(&self.path)?;
+        self.first_seq = self.next_seq;
+        Ok(())
+    }
+
+    // returns once the record is on disk
+    pub fn append(&mut self, timestamp: u128, command: Command) -> Result<u64, JournalError> {
+        let record = Record {
+            seq: self.next_seq,
+            timestamp,
+            command,
+        };
+        let payload = serde_json::to_vec(&record)?;
+        let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+        bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        self.file.write_all(&bytes)?;
+        self.file.sync_data()?;
+        self.next_seq += 1;
+        Ok(record.seq)
+    }
+}
+
+impl Engine {
+    // rebuilds the engine from the log at `path` and keeps appending to it,
+    // `clock` takes over once the log has been replayed
+    pub fn recover(path: impl AsRef<Path>, clock: Box<dyn Clock>) -> Result<Self, JournalError> {
+        let path = path.as_ref();
+        let (journal, live) = Journal::open(path)?;
+        let mut records = Journal::archived(path)?;
+        records.extend(live);
+        Journal::check_history(&records)?;
+        let start = records
+            .first()
+            .map_or_else(|| clock.now(), |record| record.timestamp);
+        let replay_clock = ManualClock::new(start as u64);
+        Self::with_clock(Box::new(replay_clock.clone())).resume(
+            journal,
+            records,
+            replay_clock,
+            clock,
+        )
+    }
+
+    // replays records into a fresh engine that logs nothing, stopping at the
+    // first record `stop` returns true for, and collects every fill on the way
+    pub fn replay(
+        records: impl IntoIterator<Item = Record>,
+        mut stop: impl FnMut(&Record) -> bool,
+    ) -> Result<(Self, Vec<ReplayedFill>), JournalError> {
+        let mut records = records.into_iter().peekable();
+        let start = records.peek().map_or(0, |record| record.timestamp);
+        let replay_clock = ManualClock::new(start as u64);
+        let mut engine = Self::with_clock(Box::new(replay_clock.clone()));
+        let mut fills = vec![];
+        for record in records {
+            if stop(&record) {
+                break;
+            }
+            replay_clock.set(record.timestamp as u64);
+            let (seq, timestamp) = (record.seq, record.timestamp);
+            fills.extend(
+                engine
+                    .apply(record.command)
+                    .map_err(|e| JournalError::Replay(seq, e))?
+                    .into_iter()
+                    .map(|fill| ReplayedFill {
+                        seq,
+                        timestamp,
+                        fill,
+                    }),
+            );
+        }
+        Ok((engine, fills))
+    }
+
+    // replays `records` on top of the current state, with `replay_clock`
+    // already installed as the engine clock, then hands over to `clock` and
+    // keeps appending to `journal`
+    pub(super) fn resume(
+        mut self,
+        mut journal: Journal,
+        records: Vec<Record>,
+        replay_clock: ManualClock,
+        clock: Box<dyn Clock>,
+    ) -> Result<Self, JournalError> {
+        if journal.last_seq() == 0 {
+            journal.append(replay_clock.now(), Command::Init)?;
+        }
+        for record in records {
+            replay_clock.set(record.timestamp as u64);
+            self.apply(record.command)
+                .map_err(|e| JournalError::Replay(record.seq, e))?;
+        }
+        self.clock = clock;
+        self.journal = Some(journal);
+        Ok(self)
+    }
+
+    // applies a recorded command, the clock must already be at its timestamp.
+    // returns the fills it generated, including those of any liquidations
+    pub fn apply(&mut self, command: Command) -> Result<Vec<Fill>, EngineError> {
+        match command {
+            Command::Init => Ok(vec![]),
+            Command::CreateAccount(account) => self.create_account(account).map(|_| vec![]),
+            Command::DeleteAccount(address) => self.delete_account(address).map(|_| vec![]),
+            Command::CreateOrder { order, timestamp } => {
+                self.create_order(Order { timestamp, ..order })
+            }
+            Command::DeleteOrder(hash) => self.delete_order(hash).map(|_| vec![]),
+            Command::CreateOrders { orders, timestamps } => {
+                let orders: Vec<Order> = orders
+                    .into_iter()
+                    .zip(timestamps)
+                    .map(|(order, timestamp)| Order { timestamp, ..order })
+                    .collect();
+                self.create_orders(orders, true)
+                    .into_iter()
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(|fills| fills.concat())
+            }
+            Command::DeleteOrders(hashes) => self
+                .delete_orders(hashes, true)
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()
+                .map(|_| vec![]),
+            Command::CancelAll {
+                trader_address,
+                symbol,
+                side,
+            } => self
+                .cancel_all(trader_address, symbol, side)
+                .map(|_| vec![]),
+            Command::SetMarginMode {
+                trader_address,
+                symbol,
+                mode,
+            } => self
+                .set_margin_mode(trader_address, symbol, mode)
+                .map(|_| vec![]),
+            Command::AdjustIsolatedMargin {
+                trader_address,
+                symbol,
+                amount,
+            } => self
+                .adjust_isolated_margin(trader_address, symbol, amount)
+                .map(|_| vec![]),
+            Command::SetIndexPrice {
+                symbol,
+                index_price,
+            } => self.set_index_price(symbol, index_price).map(|_| vec![]),
+            Command::Tick { index_prices } => Ok(self
+                .apply_tick(&index_prices)
+                .into_iter()
+                .filter_map(|event| match event {
+                    Event::Liquidation(liquidation) => Some(liquidation.positions),
+                    Event::Funding(_) => None,
+                })
+                .flatten()
+                .flat_map(|position| {
+                    position.fills.into_iter().chain(
+                        position
+                            .deleveraged
+                            .into_iter()
+                            .map(|deleverage| deleverage.fill),
+                    )
+                })
+                .collect()),
+            Command::Deposit(deposit) => self.deposit(deposit).map(|_| vec![]),
+            Command::Withdraw {
+                trader_address,
+                asset,
+                amount,
+            } => self.withdraw(trader_address, asset, amount).map(|_| vec![]),
+            Command::Checkpoint => {
+                self.checkpoint();
+                Ok(vec![])
+            }
+        }
+    }
+
+    // replaces the default of panicking when the log cannot be written, so
+    // the binary can decide how to stop
+    pub fn set_journal_failure(&mut self, journal_failure: JournalFailure) {
+        self.journal_failure = journal_failure;
+    }
+
+    // makes an applied command durable before it is acknowledged or
+    // published, under the time the command started at. the state already
+    // includes it, so if the log cannot be written the engine hands over to
+    // `journal_failure` rather than serve state a restart forgets
+    pub(super) fn record(&mut self, command: Command) {
+        self.trim_history();
+        let moved = self.sync_state_tree();
+        if let Some(journal) = self.journal.as_mut() {
+            if let Err(e) = journal.append(self.now, command) {
+                (self.journal_failure)(e);
+            }
+        }
+        self.publish_market_data();
+        self.publish_account_updates(moved);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{
+        engine::tests::{account, perp_order, temp_path, trader},
+        Side,
+    };
+
+    #[test]
+    fn test_recovers_acknowledged_commands() {
+        let path = temp_path("journal.log");
+
+        let clock = ManualClock::new(1);
+        let mut engine = Engine::recover(&path, Box::new(clock.clone())).unwrap();
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        engine.create_account(account(2, dec!(1000))).unwrap();
+        clock.set(2);
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(2), dec!(100), 2))
+            .unwrap();
+        engine
+            .create_order(perp_order(2, Side::Ask, dec!(1), dec!(100), 3))
+            .unwrap();
+        // rejected commands are not logged
+        assert!(engine.create_account(account(1, dec!(1))).is_err());
+        drop(engine);
+
+        // a crash in the middle of an append leaves a torn record behind
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[200, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+
+        let mut engine = Engine::recover(&path, Box::new(clock.clone())).unwrap();
+        let records = Journal::read(&path).unwrap();
+        assert_eq!(records.len(), 5);
+        assert!(matches!(records[0].command, Command::Init));
+        assert_eq!(engine.get_positions(trader(1)).unwrap()[0].size, dec!(1));
+        assert_eq!(engine.get_book(Symbol::DdxPerp).bids[0].amount, dec!(1));
+        assert_eq!(
+            engine.get_margin(trader(1)).unwrap().book_outstanding,
+            dec!(10)
+        );
+
+        // and appending carries on from there
+        engine.create_account(account(3, dec!(1000))).unwrap();
+        assert_eq!(Journal::read(&path).unwrap()[5].seq, 6);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_is_deterministic() {
+        let path = temp_path("replay.log");
+        let clock = ManualClock::new(1);
+        let mut engine = Engine::recover(&path, Box::new(clock.clone())).unwrap();
+        for n in 1..=3 {
+            engine.create_account(account(n, dec!(1000))).unwrap();
+        }
+        for (n, side, amount, price) in [
+            (1, Side::Ask, dec!(1), dec!(101)),
+            (2, Side::Ask, dec!(2), dec!(100)),
+            (3, Side::Bid, dec!(3.5), dec!(101)),
+            (1, Side::Ask, dec!(1), dec!(99)),
+        ] {
+            clock.advance(10);
+            let timestamp = clock.now();
+            engine
+                .create_order(perp_order(n, side, amount, price, timestamp))
+                .unwrap();
+        }
+        drop(engine);
+
+        let records = Journal::read(&path).unwrap();
+        let (_, fills) = Engine::replay(records.clone(), |_| false).unwrap();
+        let (_, again) = Engine::replay(records.clone(), |_| false).unwrap();
+        assert_eq!(fills.len(), 3);
+        assert_eq!(
+            serde_json::to_vec(&fills).unwrap(),
+            serde_json::to_vec(&again).unwrap()
+        );
+
+        // stopping before the last order leaves it out
+        let (engine, fills) = Engine::replay(records, |record| record.seq > 7).unwrap();
+        assert_eq!(fills.len(), 2);
+        assert_eq!(engine.get_book(Symbol::DdxPerp).bids[0].amount, dec!(0.5));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // moves on every read, like a real clock does while a command is applied
+    struct SteppingClock(ManualClock);
+
+    impl Clock for SteppingClock {
+        fn now(&self) -> u128 {
+            self.0.advance(1);
+            self.0.now()
+        }
+    }
+
+    #[test]
+    fn test_replay_sees_the_time_commands_were_applied_at() {
+        let path = temp_path("stepping.log");
+        let clock = ManualClock::new(1);
+        let mut engine = Engine::recover(&path, Box::new(SteppingClock(clock.clone()))).unwrap();
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        engine.create_account(account(2, dec!(1000))).unwrap();
+        engine
+            .create_order(perp_order(1, Side::Ask, dec!(1), dec!(100), 1))
+            .unwrap();
+        engine
+            .create_order(perp_order(2, Side::Bid, dec!(1), dec!(100), 2))
+            .unwrap();
+        let trades = engine.get_trades(Symbol::DdxPerp, None, 10);
+        drop(engine);
+
+        let records = Journal::read(&path).unwrap();
+        assert_eq!(trades[0].timestamp, records.last().unwrap().timestamp);
+        let engine = Engine::recover(&path, Box::new(SteppingClock(clock))).unwrap();
+        assert_eq!(engine.get_trades(Symbol::DdxPerp, None, 10), trades);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn stop(e: JournalError) -> ! {
+        panic!("journal failure hook: {}", e)
+    }
+
+    #[test]
+    #[should_panic(expected = "journal failure hook")]
+    fn test_unloggable_commands_go_to_the_failure_hook() {
+        let path = temp_path("read-only.log");
+        let mut engine = Engine::recover(&path, Box::new(ManualClock::new(1))).unwrap();
+        engine.set_journal_failure(stop);
+        // a handle the log cannot be written through
+        let journal = engine.journal.as_mut().unwrap();
+        journal.file = File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let _ = engine.create_account(account(1, dec!(1000)));
+    }
+}
@@ -0,0 +1,29 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use web3::types::Address;
+
+use crate::Symbol;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LedgerKind {
+    Funding,
+    // collateral left over (or owed) by a liquidated account
+    Liquidation,
+    // the insurance fund's side of a liquidation
+    InsuranceFund,
+}
+
+// a change to an account's usd balance that did not come from a fill
+#[derive(Debug, Copy, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LedgerEntry {
+    pub timestamp: u128,
+    pub trader_address: Address,
+    // None for account level entries
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symbol: Option<Symbol>,
+    pub kind: LedgerKind,
+    // positive is a credit
+    pub amount: Decimal,
+}
@@ -0,0 +1,357 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use web3::types::{Address, H256};
+
+use super::{
+    adl::DeleverageFill,
+    error::Result,
+    ledger::{LedgerEntry, LedgerKind},
+    order_status::TerminalReason,
+    Engine, MarginMode,
+};
+use crate::{Fill, FillKind, Nonce, Order, Side, Symbol, TimeInForce};
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LiquidatedPosition {
+    pub symbol: Symbol,
+    // signed size held before the liquidation
+    pub size: Decimal,
+    pub mark_price: Decimal,
+    pub bankruptcy_price: Decimal,
+    pub fills: Vec<Fill>,
+    pub deleveraged: Vec<DeleverageFill>,
+    // size the insurance fund took over because the book could not absorb it
+    pub insurance_takeover: Decimal,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LiquidationEvent {
+    pub timestamp: u128,
+    pub trader_address: Address,
+    // cross liquidations close every cross position, isolated ones a single position
+    pub margin_mode: MarginMode,
+    pub cancelled_orders: Vec<H256>,
+    pub positions: Vec<LiquidatedPosition>,
+    // positive when collateral was left over, negative when the fund covered a deficit
+    pub insurance_fund_delta: Decimal,
+}
+
+impl Engine {
+    pub fn insurance_fund(&self) -> Address {
+        self.insurance_fund
+    }
+
+    // checks the cross account only, see `isolated_liquidations` for the rest
+    pub fn is_liquidatable(&self, address: Address) -> Result<bool> {
+        let margin = self.get_margin(address)?;
+        Ok(margin.maintenance_margin > Decimal::ZERO && margin.equity < margin.maintenance_margin)
+    }
+
+    // isolated positions whose own margin no longer covers maintenance
+    pub fn isolated_liquidations(&self, address: Address) -> Result<Vec<Symbol>> {
+        Ok(self
+            .get_positions(address)?
+            .into_iter()
+            .filter(|position| {
+                position.margin_mode == MarginMode::Isolated
+                    && position.isolated_margin + position.unrealized_pnl
+                        < position.maintenance_margin
+            })
+            .map(|position| position.symbol)
+            .collect())
+    }
+
+    // price at which closing the position would take the equity backing it to
+    // exactly zero, other cross positions held at their marks
+    pub fn bankruptcy_price(&self, address: Address, symbol: Symbol) -> Result<Option<Decimal>> {
+        let equity = self.position_equity(address, symbol)?;
+        Ok(self
+            .get_positions(address)?
+            .into_iter()
+            .find(|position| position.symbol == symbol)
+            .map(|position| position.mark_price - equity / position.size))
+    }
+
+    pub fn check_liquidations(&mut self) -> Vec<LiquidationEvent> {
+        let candidates: Vec<Address> = self
+            .positions
+            .keys()
+            .filter(|address| **address != self.insurance_fund)
+            .copied()
+            .collect();
+        // checked one at a time, each liquidation moves the book and so the marks
+        let mut events = vec![];
+        for address in candidates {
+            if self.is_liquidatable(address).unwrap_or(false) {
+                if let Ok(event) = self.liquidate(address, None) {
+                    events.push(event);
+                }
+            }
+            for symbol in self.isolated_liquidations(address).unwrap_or_default() {
+                if let Ok(event) = self.liquidate(address, Some(symbol)) {
+                    events.push(event);
+                }
+            }
+        }
+        events
+    }
+
+    // liquidates the cross account when `isolated` is None, otherwise only the
+    // isolated position in that market
+    fn liquidate(
+        &mut self,
+        address: Address,
+        isolated: Option<Symbol>,
+    ) -> Result<LiquidationEvent> {
+        let now = self.now;
+
+        // pull every resting order in scope first so that the account cannot
+        // match against itself and its reservations are freed
+        let mut hashes: Vec<H256> = self
+            .markets
+            .iter()
+            .filter(|(symbol, _)| isolated.is_none() || isolated.as_ref() == Some(*symbol))
+            .flat_map(|(_, market)| market.book.trader_orders(address))
+            .collect();
+        hashes.sort();
+        let cancelled_orders = hashes
+            .into_iter()
+            .filter(|hash| {
+                self.cancel_order(*hash, TerminalReason::Liquidation)
+                    .is_ok()
+            })
+            .collect();
+
+        let margin_mode = match isolated {
+            Some(_) => MarginMode::Isolated,
+            None => MarginMode::Cross,
+        };
+        let usd_balance = self.accounts[&address].usd_balance;
+        let mut positions = vec![];
+        for view in self.get_positions(address)? {
+            if view.margin_mode != margin_mode
+                || isolated.is_some_and(|symbol| symbol != view.symbol)
+            {
+                continue;
+            }
+            let symbol = view.symbol;
+            let bankruptcy_price = self.bankruptcy_price(address, symbol)?.unwrap();
+            let slippage = self.markets[&symbol].max_liquidation_slippage;
+            let (side, price) = if view.size.is_sign_positive() {
+                (Side::Ask, view.mark_price * (Decimal::ONE - slippage))
+            } else {
+                (Side::Bid, view.mark_price * (Decimal::ONE + slippage))
+            };
+            let order = Order {
+                amount: view.size.abs(),
+                nonce: Nonce(H256::from_low_u64_be(now as u64)),
+                price,
+                side,
+                trader_address: address,
+                symbol,
+                time_in_force: TimeInForce::Ioc,
+                reduce_only: true,
+                close_position: false,
+                timestamp: now,
+            };
+            let hash = self.markets[&symbol].book.order_hash(&order);
+            let mut fills = self.execute_perp_order(hash, order)?;
+
+            fills
+                .iter_mut()
+                .for_each(|fill| fill.kind = FillKind::Liquidation);
+
+            // whatever the book could not absorb is closed at the price that
+            // leaves the account with nothing, by the fund if it can carry the
+            // loss and otherwise against the most profitable opposing traders
+            let mut remaining = self.positions[&address][&symbol].size;
+            let mut deleveraged = vec![];
+            if !remaining.is_zero() {
+                let takeover_price = self.bankruptcy_price(address, symbol)?.unwrap();
+                let loss = self.position_equity(address, symbol)?;
+                let fund_equity = self.get_margin(self.insurance_fund)?.equity;
+                if fund_equity + loss < Decimal::ZERO {
+                    deleveraged = self.deleverage(address, symbol, remaining, takeover_price);
+                    remaining = self.positions[&address][&symbol].size;
+                }
+            }
+            if !remaining.is_zero() {
+                let takeover_price = self.bankruptcy_price(address, symbol)?.unwrap();
+                self.apply_perp_fill(address, symbol, -remaining, takeover_price);
+                self.apply_perp_fill(self.insurance_fund, symbol, remaining, takeover_price);
+            }
+            positions.push(LiquidatedPosition {
+                symbol,
+                size: view.size,
+                mark_price: view.mark_price,
+                bankruptcy_price,
+                fills,
+                deleveraged,
+                insurance_takeover: remaining,
+            });
+        }
+
+        // the account forfeits whatever collateral is left, and the fund
+        // absorbs any shortfall, an isolated position only forfeits the
+        // margin it handed back when it was closed
+        let residual = match isolated {
+            Some(_) => self.accounts[&address].usd_balance - usd_balance,
+            None => self.accounts[&address].usd_balance,
+        };
+        if !residual.is_zero() {
+            self.accounts.get_mut(&address).unwrap().usd_balance -= residual;
+            self.accounts
+                .get_mut(&self.insurance_fund)
+                .unwrap()
+                .usd_balance += residual;
+            self.state_tree.touch_account(address);
+            self.state_tree.touch_account(self.insurance_fund);
+            self.ledger.push(LedgerEntry {
+                timestamp: now,
+                trader_address: address,
+                symbol: isolated,
+                kind: LedgerKind::Liquidation,
+                amount: -residual,
+            });
+            self.ledger.push(LedgerEntry {
+                timestamp: now,
+                trader_address: self.insurance_fund,
+                symbol: isolated,
+                kind: LedgerKind::InsuranceFund,
+                amount: residual,
+            });
+        }
+
+        Ok(LiquidationEvent {
+            timestamp: now,
+            trader_address: address,
+            margin_mode,
+            cancelled_orders,
+            positions,
+            insurance_fund_delta: residual,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::engine::{
+        tests::{perp_order, priced_engine, trader},
+        Event, ManualClock,
+    };
+
+    // trader 1 is long 1 @ 100 with 20 of collateral against trader 2, and
+    // the index follows `prices` as the clock moves
+    fn setup(prices: &[(u128, Decimal)]) -> (Engine, ManualClock) {
+        let prices: Vec<(u128, Decimal)> = [(0, dec!(100))].iter().chain(prices).copied().collect();
+        let (mut engine, clock) = priced_engine(&prices, &[dec!(20), dec!(1000), dec!(1000)]);
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(1), dec!(100), 1))
+            .unwrap();
+        engine
+            .create_order(perp_order(2, Side::Ask, dec!(1), dec!(100), 2))
+            .unwrap();
+        (engine, clock)
+    }
+
+    fn liquidations(events: Vec<Event>) -> Vec<LiquidationEvent> {
+        events
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::Liquidation(liquidation) => Some(liquidation),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_healthy_account_is_left_alone() {
+        let (mut engine, clock) = setup(&[(10, dec!(85))]);
+        clock.set(10);
+        // equity 5 against maintenance 4.25
+        assert!(liquidations(engine.tick()).is_empty());
+        assert_eq!(engine.get_positions(trader(1)).unwrap()[0].size, dec!(1));
+    }
+
+    #[test]
+    fn test_liquidation_into_book_pays_surplus_to_fund() {
+        let (mut engine, clock) = setup(&[(10, dec!(84))]);
+        engine
+            .create_order(perp_order(1, Side::Ask, dec!(0.1), dec!(150), 3))
+            .unwrap();
+        engine
+            .create_order(perp_order(3, Side::Bid, dec!(1), dec!(83), 4))
+            .unwrap();
+
+        // equity 4 against maintenance 4.2
+        clock.set(10);
+        let events = liquidations(engine.tick());
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.trader_address, trader(1));
+        assert_eq!(event.cancelled_orders.len(), 1);
+        assert_eq!(event.positions[0].bankruptcy_price, dec!(80));
+        assert_eq!(event.positions[0].fills[0].price, dec!(83));
+        assert_eq!(event.positions[0].insurance_takeover, dec!(0));
+        assert_eq!(event.insurance_fund_delta, dec!(3));
+
+        assert!(engine.get_positions(trader(1)).unwrap().is_empty());
+        assert_eq!(
+            engine.get_margin(trader(1)).unwrap().book_outstanding,
+            dec!(0)
+        );
+        assert_eq!(engine.get_account(trader(1)).unwrap().usd_balance, dec!(0));
+        assert_eq!(
+            engine
+                .get_account(engine.insurance_fund())
+                .unwrap()
+                .usd_balance,
+            dec!(3)
+        );
+        assert_eq!(engine.get_positions(trader(3)).unwrap()[0].size, dec!(1));
+        let ledger = engine.get_ledger(engine.insurance_fund()).unwrap();
+        assert_eq!(ledger[0].kind, LedgerKind::InsuranceFund);
+        assert_eq!(ledger[0].amount, dec!(3));
+    }
+
+    #[test]
+    fn test_fund_covers_deficit() {
+        let (mut engine, clock) = setup(&[(10, dec!(70))]);
+        let fund = engine.insurance_fund();
+        engine.accounts.get_mut(&fund).unwrap().usd_balance = dec!(100);
+        engine
+            .create_order(perp_order(3, Side::Bid, dec!(1), dec!(75), 3))
+            .unwrap();
+
+        clock.set(10);
+        let events = liquidations(engine.tick());
+        assert_eq!(events[0].positions[0].fills[0].price, dec!(75));
+        assert_eq!(events[0].insurance_fund_delta, dec!(-5));
+        assert_eq!(engine.get_account(trader(1)).unwrap().usd_balance, dec!(0));
+        assert_eq!(engine.get_account(fund).unwrap().usd_balance, dec!(95));
+    }
+
+    #[test]
+    fn test_fund_takes_over_what_book_cannot_absorb() {
+        let (mut engine, clock) = setup(&[(10, dec!(70))]);
+        let fund = engine.insurance_fund();
+        engine.accounts.get_mut(&fund).unwrap().usd_balance = dec!(100);
+        clock.set(10);
+        let events = liquidations(engine.tick());
+        assert!(events[0].positions[0].fills.is_empty());
+        assert!(events[0].positions[0].deleveraged.is_empty());
+        assert_eq!(events[0].positions[0].insurance_takeover, dec!(1));
+        assert_eq!(events[0].insurance_fund_delta, dec!(0));
+
+        assert!(engine.get_positions(trader(1)).unwrap().is_empty());
+        assert_eq!(engine.get_account(trader(1)).unwrap().usd_balance, dec!(0));
+        let fund = engine.get_positions(engine.insurance_fund()).unwrap();
+        assert_eq!(fund[0].size, dec!(1));
+        assert_eq!(fund[0].entry_price, dec!(80));
+    }
+}
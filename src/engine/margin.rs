@@ -0,0 +1,203 @@
+use rust_decimal::Decimal;
+use web3::types::Address;
+
+use super::{
+    error::{EngineError as Error, Result},
+    Command, Engine, MarginMode,
+};
+use crate::{MarketKind, Symbol};
+
+impl Engine {
+    pub fn margin_mode(&self, address: Address, symbol: Symbol) -> MarginMode {
+        self.positions
+            .get(&address)
+            .and_then(|positions| positions.get(&symbol))
+            .map(|position| position.margin_mode)
+            .unwrap_or_default()
+    }
+
+    // the mode only applies to positions opened after the switch, so the
+    // trader has to be flat and have nothing resting in the market
+    pub fn set_margin_mode(
+        &mut self,
+        address: Address,
+        symbol: Symbol,
+        mode: MarginMode,
+    ) -> Result<()> {
+        self.start_command();
+        self.get_account(address)?;
+        if symbol.kind() != MarketKind::Perpetual {
+            return Err(Error::NotPerpetual(symbol));
+        }
+        if address == self.insurance_fund {
+            return Err(Error::ReservedAccount(address));
+        }
+        if self.margin_mode(address, symbol) == mode {
+            return Ok(());
+        }
+        let open = self
+            .positions
+            .get(&address)
+            .and_then(|positions| positions.get(&symbol))
+            .is_some_and(|position| !position.size.is_zero());
+        let resting = self
+            .markets
+            .get(&symbol)
+            .is_some_and(|market| market.book.trader_orders(address).next().is_some());
+        if open || resting {
+            return Err(Error::PositionOpen(symbol));
+        }
+        self.positions
+            .entry(address)
+            .or_default()
+            .entry(symbol)
+            .or_default()
+            .margin_mode = mode;
+        self.record(Command::SetMarginMode {
+            trader_address: address,
+            symbol,
+            mode,
+        });
+        Ok(())
+    }
+
+    // moves collateral into (positive) or out of (negative) an open isolated
+    // position, which must keep at least its initial margin
+    pub fn adjust_isolated_margin(
+        &mut self,
+        address: Address,
+        symbol: Symbol,
+        amount: Decimal,
+    ) -> Result<Decimal> {
+        self.start_command();
+        self.get_account(address)?;
+        if self.margin_mode(address, symbol) != MarginMode::Isolated {
+            return Err(Error::NotIsolated(symbol));
+        }
+        let view = self
+            .get_positions(address)?
+            .into_iter()
+            .find(|position| position.symbol == symbol)
+            .ok_or(Error::NoPosition(symbol))?;
+        if amount.is_sign_positive() {
+            let available = self.get_margin(address)?.free_collateral;
+            if available < amount {
+                return Err(Error::InsufficientMargin(available, amount));
+            }
+        } else {
+            let available =
+                view.isolated_margin + view.unrealized_pnl.min(Decimal::ZERO) - view.initial_margin;
+            if available < -amount {
+                return Err(Error::InsufficientMargin(available, -amount));
+            }
+        }
+        self.accounts.get_mut(&address).unwrap().usd_balance -= amount;
+        self.state_tree.touch_account(address);
+        let position = self
+            .positions
+            .get_mut(&address)
+            .unwrap()
+            .get_mut(&symbol)
+            .unwrap();
+        position.isolated_margin += amount;
+        let isolated_margin = position.isolated_margin;
+        self.record(Command::AdjustIsolatedMargin {
+            trader_address: address,
+            symbol,
+            amount,
+        });
+        Ok(isolated_margin)
+    }
+
+    // equity backing a single position, the isolated margin for isolated
+    // positions and the account's cross equity otherwise
+    pub(super) fn position_equity(&self, address: Address, symbol: Symbol) -> Result<Decimal> {
+        let position = self
+            .positions
+            .get(&address)
+            .and_then(|positions| positions.get(&symbol))
+            .copied()
+            .unwrap_or_default();
+        match position.margin_mode {
+            MarginMode::Cross => Ok(self.get_margin(address)?.equity),
+            MarginMode::Isolated => {
+                let mark_price = self.mark_price(symbol).unwrap_or(position.entry_price);
+                Ok(position.isolated_equity(mark_price))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{
+        engine::{
+            tests::{perp_order, priced_engine, trader},
+            Event,
+        },
+        Side,
+    };
+
+    #[test]
+    fn test_isolated_liquidation_leaves_cross_collateral_alone() {
+        let (mut engine, clock) =
+            priced_engine(&[(0, dec!(100)), (10, dec!(86))], &[dec!(1000), dec!(1000)]);
+        engine
+            .set_margin_mode(trader(1), Symbol::DdxPerp, MarginMode::Isolated)
+            .unwrap();
+        engine
+            .create_order(perp_order(2, Side::Ask, dec!(1), dec!(100), 1))
+            .unwrap();
+        // a resting order blocks the switch, which leaves no position behind
+        assert!(matches!(
+            engine.set_margin_mode(trader(2), Symbol::DdxPerp, MarginMode::Isolated),
+            Err(Error::PositionOpen(_))
+        ));
+        assert!(!engine.positions.contains_key(&trader(2)));
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(1), dec!(100), 2))
+            .unwrap();
+
+        // opening moved the initial margin out of the cross balance
+        let position = &engine.get_positions(trader(1)).unwrap()[0];
+        assert_eq!(position.isolated_margin, dec!(10));
+        assert_eq!(
+            engine.get_account(trader(1)).unwrap().usd_balance,
+            dec!(990)
+        );
+        assert!(matches!(
+            engine.set_margin_mode(trader(1), Symbol::DdxPerp, MarginMode::Cross),
+            Err(Error::PositionOpen(_))
+        ));
+
+        assert_eq!(
+            engine
+                .adjust_isolated_margin(trader(1), Symbol::DdxPerp, dec!(5))
+                .unwrap(),
+            dec!(15)
+        );
+        assert!(engine
+            .adjust_isolated_margin(trader(1), Symbol::DdxPerp, dec!(-6))
+            .is_err());
+        assert_eq!(engine.get_margin(trader(1)).unwrap().equity, dec!(985));
+
+        // isolated equity 1 against maintenance 4.3, the fund takes the
+        // position over at 85 and the rest of the account is untouched
+        clock.set(10);
+        let events = engine.tick();
+        let Event::Liquidation(event) = &events[0] else {
+            panic!("expected a liquidation");
+        };
+        assert_eq!(event.margin_mode, MarginMode::Isolated);
+        assert_eq!(event.positions[0].bankruptcy_price, dec!(85));
+        assert_eq!(event.insurance_fund_delta, dec!(0));
+        assert!(engine.get_positions(trader(1)).unwrap().is_empty());
+        assert_eq!(
+            engine.get_account(trader(1)).unwrap().usd_balance,
+            dec!(985)
+        );
+    }
+}
@@ -0,0 +1,62 @@
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use std::collections::VecDeque;
+
+use super::{
+    candles::Candles,
+    funding::{FundingParams, FundingSettlement},
+    orderbook::OrderBook,
+    trades::Trade,
+};
+use crate::Symbol;
+
+pub struct Market {
+    pub book: OrderBook,
+    // fractions of notional, only meaningful for perpetual markets
+    pub initial_margin: Decimal,
+    pub maintenance_margin: Decimal,
+    // how far from the mark a liquidation order may trade
+    pub max_liquidation_slippage: Decimal,
+    pub last_price: Option<Decimal>,
+
+    pub funding: FundingParams,
+    pub next_funding_time: u128,
+    pub index_price: Option<Decimal>,
+    pub last_funding: Option<FundingSettlement>,
+    // seq of the last published l2 update, restarts at 0 with the process
+    pub l2_seq: u64,
+    // same for l3 updates
+    pub l3_seq: u64,
+    // seq of the last trade and the most recent trades
+    pub trade_seq: u64,
+    pub trades: VecDeque<Trade>,
+    pub candles: Candles,
+}
+
+impl Market {
+    pub fn new(symbol: Symbol, now: u128) -> Self {
+        let domain_name = match symbol {
+            // kept as-is so that spot order hashes are unchanged
+            Symbol::DdxUsd => "DDX take-home",
+            Symbol::DdxPerp => "DDX take-home DDXPERP",
+        };
+        let funding = FundingParams::default();
+        Self {
+            book: OrderBook::new(domain_name),
+            initial_margin: dec!(0.1),
+            maintenance_margin: dec!(0.05),
+            max_liquidation_slippage: dec!(0.1),
+            last_price: None,
+            funding,
+            // settlements fall on multiples of the interval since the epoch
+            next_funding_time: (now / funding.interval + 1) * funding.interval,
+            index_price: None,
+            last_funding: None,
+            l2_seq: 0,
+            l3_seq: 0,
+            trade_seq: 0,
+            trades: VecDeque::new(),
+            candles: Candles::default(),
+        }
+    }
+}
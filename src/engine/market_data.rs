@@ -0,0 +1,399 @@
+use serde::Serialize;
+use std::hash::{BuildHasher, RandomState};
+use web3::{signing::keccak256, types::H256};
+
+use super::{
+    account_updates::AccountUpdate,
+    event::Event,
+    funding::FundingSettlement,
+    liquidation::LiquidationEvent,
+    orderbook::{L3Event, L3Order, Levels},
+    stats::MarketStats,
+    trades::Trade,
+    Engine,
+};
+use crate::Symbol;
+
+// full depth l2 book, or the levels that changed in one update. amounts are
+// the new aggregate at the price, zero for a level that emptied
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct L2Update {
+    pub symbol: Symbol,
+    // one per update, a snapshot carries the seq of the last update it
+    // includes so that a client applies only later ones
+    pub seq: u64,
+    pub bids: Levels,
+    pub asks: Levels,
+    // OrderBook::checksum of the whole book once the update is applied
+    pub checksum: u32,
+}
+
+// every resting order, or the order level changes of one update, on the
+// same seq rules as the l2 book
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct L3Snapshot {
+    pub symbol: Symbol,
+    pub seq: u64,
+    pub bids: Vec<L3Order>,
+    pub asks: Vec<L3Order>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct L3Update {
+    pub symbol: Symbol,
+    pub seq: u64,
+    pub events: Vec<L3Event>,
+}
+
+// stands in for order hashes on anonymised feeds, which could otherwise be
+// looked up to find the trader. ids are keyed with a secret so they cannot be
+// matched against hashes, and only stay the same while the key does
+#[derive(Clone, Copy)]
+pub struct FeedKey(H256);
+
+impl Default for FeedKey {
+    // RandomState is seeded by the os
+    fn default() -> Self {
+        let state = RandomState::new();
+        let mut key = H256::zero();
+        for (i, chunk) in key.0.chunks_mut(8).enumerate() {
+            chunk.copy_from_slice(&state.hash_one(i).to_le_bytes());
+        }
+        Self(key)
+    }
+}
+
+impl FeedKey {
+    fn order_id(&self, hash: H256) -> H256 {
+        H256(keccak256(&[self.0.as_bytes(), hash.as_bytes()].concat()))
+    }
+}
+
+impl L3Snapshot {
+    // leaves out who placed the orders and the hashes that lead back to them
+    pub fn anonymise(&mut self, key: &FeedKey) {
+        for order in self.bids.iter_mut().chain(self.asks.iter_mut()) {
+            order.hash = key.order_id(order.hash);
+            order.trader_address = None;
+        }
+    }
+}
+
+impl L3Update {
+    pub fn anonymise(&mut self, key: &FeedKey) {
+        for event in self.events.iter_mut() {
+            match event {
+                L3Event::Add(order) => {
+                    order.hash = key.order_id(order.hash);
+                    order.trader_address = None;
+                }
+                L3Event::Modify { hash, .. } | L3Event::Delete { hash } => {
+                    *hash = key.order_id(*hash);
+                }
+            }
+        }
+    }
+}
+
+// market data published as commands are accepted
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "camelCase")]
+pub enum MarketUpdate {
+    L2Update(L2Update),
+    L3Update(L3Update),
+    Trade(Trade),
+    // 24h stats of a market that just traded
+    Ticker(MarketStats),
+    Funding(FundingSettlement),
+    Liquidation(LiquidationEvent),
+}
+
+// receives market data while the engine is still applying the command that
+// produced it, so implementations must hand it off rather than block
+pub trait Publisher: Send {
+    fn publish(&mut self, update: MarketUpdate);
+
+    // private updates, dropped unless the publisher has somewhere to send them
+    fn publish_account(&mut self, _update: AccountUpdate) {}
+}
+
+impl Engine {
+    pub fn set_publisher(&mut self, publisher: Box<dyn Publisher>) {
+        self.publisher = Some(publisher);
+    }
+
+    pub fn get_l2_snapshot(&self, symbol: Symbol) -> L2Update {
+        let market = &self.markets[&symbol];
+        let (bids, asks) = market.book.levels();
+        L2Update {
+            symbol,
+            seq: market.l2_seq,
+            bids,
+            asks,
+            checksum: market.book.checksum(),
+        }
+    }
+
+    pub fn get_l3_snapshot(&self, symbol: Symbol) -> L3Snapshot {
+        let market = &self.markets[&symbol];
+        let (bids, asks) = market.book.l3_orders();
+        L3Snapshot {
+            symbol,
+            seq: market.l3_seq,
+            bids,
+            asks,
+        }
+    }
+
+    // called with every accepted command, sequences are advanced whether or
+    // not anyone is listening
+    pub(super) fn publish_market_data(&mut self) {
+        let trades = std::mem::take(&mut self.pending_trades);
+        let mut traded: Vec<Symbol> = vec![];
+        for trade in &trades {
+            if !traded.contains(&trade.symbol) {
+                traded.push(trade.symbol);
+            }
+        }
+        let mut updates: Vec<MarketUpdate> = trades.into_iter().map(MarketUpdate::Trade).collect();
+        updates.extend(
+            traded
+                .into_iter()
+                .map(|symbol| MarketUpdate::Ticker(self.get_market_stats(symbol))),
+        );
+        for (symbol, market) in self.markets.iter_mut() {
+            let (bids, asks) = market.book.take_level_changes();
+            if !(bids.is_empty() && asks.is_empty()) {
+                market.l2_seq += 1;
+                updates.push(MarketUpdate::L2Update(L2Update {
+                    symbol: *symbol,
+                    seq: market.l2_seq,
+                    bids,
+                    asks,
+                    checksum: market.book.checksum(),
+                }));
+            }
+            let events = market.book.take_order_changes();
+            if !events.is_empty() {
+                market.l3_seq += 1;
+                updates.push(MarketUpdate::L3Update(L3Update {
+                    symbol: *symbol,
+                    seq: market.l3_seq,
+                    events,
+                }));
+            }
+        }
+        if let Some(publisher) = self.publisher.as_mut() {
+            for update in updates {
+                publisher.publish(update);
+            }
+        }
+    }
+
+    // what a tick did on its own, after the trades and book changes it made.
+    // liquidated traders also hear of it on their private stream
+    pub(super) fn publish_events(&mut self, events: &[Event]) {
+        let Some(publisher) = self.publisher.as_mut() else {
+            return;
+        };
+        for event in events {
+            match event {
+                Event::Funding(settlement) => publisher.publish(MarketUpdate::Funding(*settlement)),
+                Event::Liquidation(liquidation) => {
+                    publisher.publish(MarketUpdate::Liquidation(liquidation.clone()));
+                    publisher.publish_account(AccountUpdate::Liquidation(liquidation.clone()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use std::{collections::BTreeMap, sync::mpsc};
+
+    use super::*;
+    use rust_decimal::Decimal;
+    use web3::types::H256;
+
+    use crate::{
+        engine::{
+            funding::NANOS_PER_HOUR,
+            tests::{account, perp_order},
+        },
+        ManualClock, Side,
+    };
+
+    struct Channel(mpsc::Sender<MarketUpdate>);
+
+    impl Publisher for Channel {
+        fn publish(&mut self, update: MarketUpdate) {
+            self.0.send(update).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_l2_updates_rebuild_the_book() {
+        let mut engine = Engine::new();
+        let (sender, receiver) = mpsc::channel();
+        engine.set_publisher(Box::new(Channel(sender)));
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        engine.create_account(account(2, dec!(1000))).unwrap();
+        for (n, side, amount, price, timestamp) in [
+            (1, Side::Bid, dec!(2), dec!(100), 1),
+            (1, Side::Bid, dec!(1), dec!(99), 2),
+            (2, Side::Ask, dec!(1), dec!(102), 3),
+        ] {
+            engine
+                .create_order(perp_order(n, side, amount, price, timestamp))
+                .unwrap();
+        }
+        let snapshot = engine.get_l2_snapshot(Symbol::DdxPerp);
+        assert_eq!(snapshot.seq, 3);
+        assert_eq!(snapshot.checksum, crc32fast::hash(b"100:2:99:1:102:1"));
+
+        // takes out the 100 level and part of the 99 one
+        engine
+            .create_order(perp_order(2, Side::Ask, dec!(2.5), dec!(99), 4))
+            .unwrap();
+
+        // a client applying the updates in order ends up with the same book,
+        // and can tell so from the checksums
+        let mut bids = BTreeMap::new();
+        let mut asks = BTreeMap::new();
+        let mut seq = 0;
+        for update in receiver.try_iter() {
+            let MarketUpdate::L2Update(update) = update else {
+                continue;
+            };
+            assert_eq!(update.seq, seq + 1);
+            seq = update.seq;
+            for (levels, changes) in [(&mut bids, update.bids), (&mut asks, update.asks)] {
+                for (price, amount) in changes {
+                    match amount.is_zero() {
+                        true => levels.remove(&price),
+                        false => levels.insert(price, amount),
+                    };
+                }
+            }
+            let levels: Vec<String> = bids
+                .iter()
+                .rev()
+                .chain(asks.iter())
+                .map(|(price, amount)| format!("{price}:{amount}"))
+                .collect();
+            assert_eq!(
+                update.checksum,
+                crc32fast::hash(levels.join(":").as_bytes())
+            );
+        }
+        assert_eq!(seq, 4);
+        assert_eq!(
+            bids.into_iter().rev().collect::<Vec<_>>(),
+            engine.get_l2_snapshot(Symbol::DdxPerp).bids
+        );
+        assert_eq!(
+            engine.get_l2_snapshot(Symbol::DdxPerp).bids,
+            vec![(dec!(99), dec!(0.5))]
+        );
+    }
+
+    #[test]
+    fn test_l3_updates_rebuild_the_book() {
+        // (side, price) -> queue of (hash, amount)
+        type Queues = BTreeMap<(u8, Decimal), Vec<(H256, Decimal)>>;
+        fn queues(snapshot: &L3Snapshot) -> Queues {
+            let mut queues = Queues::new();
+            for order in snapshot.bids.iter().chain(&snapshot.asks) {
+                let queue = queues.entry((order.side as u8, order.price)).or_default();
+                assert_eq!(queue.len(), order.position);
+                queue.push((order.hash, order.amount));
+            }
+            queues
+        }
+
+        let mut engine = Engine::new();
+        let (sender, receiver) = mpsc::channel();
+        engine.set_publisher(Box::new(Channel(sender)));
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        engine.create_account(account(2, dec!(1000))).unwrap();
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(1), dec!(100), 10))
+            .unwrap();
+        let key = FeedKey::default();
+        let mut snapshot = engine.get_l3_snapshot(Symbol::DdxPerp);
+        snapshot.anonymise(&key);
+        let mut book = queues(&snapshot);
+
+        // an earlier timestamp queues ahead of the order already there, the
+        // ask then fills it and part of the next one
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(1), dec!(100), 5))
+            .unwrap();
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(1), dec!(99), 11))
+            .unwrap();
+        engine
+            .create_order(perp_order(2, Side::Ask, dec!(1.5), dec!(100), 12))
+            .unwrap();
+        let hash = engine.get_l3_snapshot(Symbol::DdxPerp).bids[1].hash;
+        engine.delete_order(hash).unwrap();
+
+        for update in receiver.try_iter() {
+            let MarketUpdate::L3Update(mut update) = update else {
+                continue;
+            };
+            if update.seq <= snapshot.seq {
+                continue;
+            }
+            assert_eq!(update.seq, snapshot.seq + 1);
+            snapshot.seq = update.seq;
+            update.anonymise(&key);
+            for event in update.events {
+                match event {
+                    L3Event::Add(order) => {
+                        assert_eq!(order.trader_address, None);
+                        assert!(engine.get_order_status(order.hash).is_err());
+                        book.entry((order.side as u8, order.price))
+                            .or_default()
+                            .insert(order.position, (order.hash, order.amount));
+                    }
+                    L3Event::Modify { hash, amount } => book
+                        .values_mut()
+                        .flatten()
+                        .filter(|(order, _)| *order == hash)
+                        .for_each(|order| order.1 = amount),
+                    L3Event::Delete { hash } => book
+                        .values_mut()
+                        .for_each(|queue| queue.retain(|(order, _)| *order != hash)),
+                }
+            }
+        }
+        book.retain(|_, queue| !queue.is_empty());
+        let mut last = engine.get_l3_snapshot(Symbol::DdxPerp);
+        last.anonymise(&key);
+        assert_eq!(last.seq, snapshot.seq);
+        assert_eq!(book, queues(&last));
+        assert_eq!(book.len(), 1);
+    }
+
+    #[test]
+    fn test_tick_events_are_published() {
+        let clock = ManualClock::new(0);
+        let mut engine = Engine::with_clock(Box::new(clock.clone()));
+        let (sender, receiver) = mpsc::channel();
+        engine.set_publisher(Box::new(Channel(sender)));
+        engine.set_index_price(Symbol::DdxPerp, dec!(100)).unwrap();
+        clock.set(NANOS_PER_HOUR as u64 * 8);
+
+        assert_eq!(engine.tick().len(), 1);
+        assert!(receiver.try_iter().any(|update| matches!(
+            update,
+            MarketUpdate::Funding(settlement) if settlement.symbol == Symbol::DdxPerp
+        )));
+    }
+}
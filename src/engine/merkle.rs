@@ -0,0 +1,397 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use web3::{
+    signing::keccak256,
+    types::{Address, H256, U256},
+};
+
+use super::{error::Result, Engine};
+use crate::Account;
+
+const DEPTH: usize = 256;
+
+// parent of two subtrees, an empty pair stays empty (zero) so that a verifier
+// needs no table of default hashes
+fn hash_pair(left: H256, right: H256) -> H256 {
+    if left.is_zero() && right.is_zero() {
+        return H256::zero();
+    }
+    H256(keccak256(&[left.as_bytes(), right.as_bytes()].concat()))
+}
+
+// sibling hashes from the leaf up, a set bit h in `bitmap` means the sibling
+// at height h is the next entry of `siblings`, a clear bit an empty subtree
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MerkleProof {
+    pub key: H256,
+    pub leaf: H256,
+    pub bitmap: U256,
+    pub siblings: Vec<H256>,
+}
+
+impl MerkleProof {
+    pub fn root(&self) -> H256 {
+        let path = U256::from_big_endian(self.key.as_bytes());
+        let mut siblings = self.siblings.iter();
+        let mut node = self.leaf;
+        for height in 0..DEPTH {
+            let sibling = match self.bitmap.bit(height) {
+                true => siblings.next().copied().unwrap_or_default(),
+                false => H256::zero(),
+            };
+            node = match path.bit(height) {
+                true => hash_pair(sibling, node),
+                false => hash_pair(node, sibling),
+            };
+        }
+        node
+    }
+
+    pub fn verify(&self, root: H256) -> bool {
+        self.root() == root
+    }
+}
+
+// keyed by 256 bit paths, only non-empty nodes are stored
+#[derive(Default)]
+pub struct SparseMerkleTree {
+    // (height above the leaves, path >> height) to node hash
+    nodes: HashMap<(usize, U256), H256>,
+}
+
+impl SparseMerkleTree {
+    fn node(&self, height: usize, index: U256) -> H256 {
+        self.nodes
+            .get(&(height, index))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn root(&self) -> H256 {
+        self.node(DEPTH, U256::zero())
+    }
+
+    pub fn get(&self, key: H256) -> H256 {
+        self.node(0, U256::from_big_endian(key.as_bytes()))
+    }
+
+    // a zero leaf removes the key, rehashes the path up to the root
+    pub fn update(&mut self, key: H256, leaf: H256) {
+        let mut index = U256::from_big_endian(key.as_bytes());
+        let mut node = leaf;
+        for height in 0..=DEPTH {
+            match node.is_zero() {
+                true => self.nodes.remove(&(height, index)),
+                false => self.nodes.insert((height, index), node),
+            };
+            if height == DEPTH {
+                break;
+            }
+            let sibling = self.node(height, index ^ U256::one());
+            node = match index.bit(0) {
+                true => hash_pair(sibling, node),
+                false => hash_pair(node, sibling),
+            };
+            index >>= 1;
+        }
+    }
+
+    pub fn prove(&self, key: H256) -> MerkleProof {
+        let mut index = U256::from_big_endian(key.as_bytes());
+        let mut bitmap = U256::zero();
+        let mut siblings = vec![];
+        for height in 0..DEPTH {
+            let sibling = self.node(height, index ^ U256::one());
+            if !sibling.is_zero() {
+                bitmap |= U256::one() << height;
+                siblings.push(sibling);
+            }
+            index >>= 1;
+        }
+        MerkleProof {
+            key,
+            leaf: self.get(key),
+            bitmap,
+            siblings,
+        }
+    }
+}
+
+// two's complement int256 with 18 decimals, the way a contract holds balances
+pub fn decimal_to_int256(value: Decimal) -> [u8; 32] {
+    let scale = value.scale() as usize;
+    let magnitude = U256::from(value.mantissa().unsigned_abs());
+    let magnitude = if scale <= 18 {
+        magnitude * U256::exp10(18 - scale)
+    } else {
+        magnitude / U256::exp10(scale - 18)
+    };
+    let word = match value.is_sign_negative() {
+        true => (!magnitude).overflowing_add(U256::one()).0,
+        false => magnitude,
+    };
+    let mut bytes = [0u8; 32];
+    word.to_big_endian(&mut bytes);
+    bytes
+}
+
+// keccak256(abi.encodePacked(trader))
+pub fn account_key(address: Address) -> H256 {
+    H256(keccak256(address.as_bytes()))
+}
+
+// keccak256(abi.encode(trader, ddxBalance, usdBalance)) with int256 balances
+pub fn account_leaf(account: &Account) -> H256 {
+    H256(keccak256(
+        &[
+            H256::from(account.trader_address).as_bytes(),
+            &decimal_to_int256(account.ddx_balance),
+            &decimal_to_int256(account.usd_balance),
+        ]
+        .concat(),
+    ))
+}
+
+// the tree over account balances, resting order hashes and withdrawal hashes,
+// each hash is its own key and leaf. commands mark the accounts and hashes
+// they touch, a sync rehashes only the paths of those leaves
+#[derive(Default)]
+pub struct StateTree {
+    tree: SparseMerkleTree,
+    balances: HashMap<Address, (Decimal, Decimal)>,
+    dirty_accounts: HashSet<Address>,
+    dirty_keys: HashSet<H256>,
+}
+
+impl StateTree {
+    pub fn touch_account(&mut self, address: Address) {
+        self.dirty_accounts.insert(address);
+    }
+
+    // an order or withdrawal hash that may have appeared or gone away
+    pub fn touch_key(&mut self, key: H256) {
+        self.dirty_keys.insert(key);
+    }
+
+    // returns the touched accounts whose balances changed, `live` tells
+    // whether a touched hash is still resting or withdrawn
+    pub fn sync(
+        &mut self,
+        accounts: &HashMap<Address, Account>,
+        live: impl Fn(H256) -> bool,
+    ) -> Vec<Address> {
+        let mut moved = vec![];
+        for address in std::mem::take(&mut self.dirty_accounts) {
+            match accounts.get(&address) {
+                Some(account) => {
+                    let balances = (account.ddx_balance, account.usd_balance);
+                    if self.balances.get(&address) != Some(&balances) {
+                        moved.push(address);
+                        self.balances.insert(address, balances);
+                        self.tree
+                            .update(account_key(address), account_leaf(account));
+                    }
+                }
+                None => {
+                    if self.balances.remove(&address).is_some() {
+                        self.tree.update(account_key(address), H256::zero());
+                    }
+                }
+            }
+        }
+        for key in std::mem::take(&mut self.dirty_keys) {
+            let leaf = match live(key) {
+                true => key,
+                false => H256::zero(),
+            };
+            if self.tree.get(key) != leaf {
+                self.tree.update(key, leaf);
+            }
+        }
+        moved
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountProof {
+    pub root: H256,
+    pub trader_address: Address,
+    pub ddx_balance: Decimal,
+    pub usd_balance: Decimal,
+    pub proof: MerkleProof,
+}
+
+impl Engine {
+    // called after every accepted command, brings the leaves the command
+    // touched up to date. the books keep their own record of the orders that
+    // came, went or were modified
+    pub(super) fn sync_state_tree(&mut self) -> Vec<Address> {
+        for market in self.markets.values() {
+            for hash in market.book.changed_order_hashes() {
+                self.state_tree.touch_key(hash);
+            }
+        }
+        let (markets, withdrawals) = (&self.markets, &self.withdrawals);
+        self.state_tree.sync(&self.accounts, |key| {
+            withdrawals.contains_key(&key)
+                || markets
+                    .values()
+                    .any(|market| market.book.get_order(key).is_ok())
+        })
+    }
+
+    // hashes every leaf from scratch, for a new or restored engine
+    pub(super) fn rebuild_state_tree(&mut self) {
+        self.state_tree = StateTree::default();
+        for address in self.accounts.keys() {
+            self.state_tree.touch_account(*address);
+        }
+        for market in self.markets.values() {
+            for (hash, _) in market.book.orders() {
+                self.state_tree.touch_key(*hash);
+            }
+        }
+        for hash in self.withdrawals.keys() {
+            self.state_tree.touch_key(*hash);
+        }
+        self.sync_state_tree();
+    }
+
+    pub fn state_root(&self) -> H256 {
+        self.state_tree.tree.root()
+    }
+
+    // proof of a key against the current state root
+    pub(super) fn state_proof(&self, key: H256) -> MerkleProof {
+        self.state_tree.tree.prove(key)
+    }
+
+    pub fn get_account_proof(&self, address: Address) -> Result<AccountProof> {
+        let account = self.get_account(address)?;
+        Ok(AccountProof {
+            root: self.state_root(),
+            trader_address: address,
+            ddx_balance: account.ddx_balance,
+            usd_balance: account.usd_balance,
+            proof: self.state_proof(account_key(address)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{
+        engine::{
+            deposit::Asset,
+            tests::{account, perp_order},
+        },
+        Side, Symbol,
+    };
+
+    #[test]
+    fn test_updates_and_proofs() {
+        let mut tree = SparseMerkleTree::default();
+        assert!(tree.root().is_zero());
+        let (a, b) = (H256::repeat_byte(0xaa), H256::repeat_byte(0x0b));
+        tree.update(a, H256::from_low_u64_be(1));
+        let only_a = tree.root();
+        tree.update(b, H256::from_low_u64_be(2));
+        assert_ne!(tree.root(), only_a);
+
+        let proof = tree.prove(a);
+        assert!(proof.verify(tree.root()));
+        assert_eq!(proof.siblings.len(), 1);
+        let forged = MerkleProof {
+            leaf: H256::from_low_u64_be(3),
+            ..proof
+        };
+        assert!(!forged.verify(tree.root()));
+
+        // absent keys prove to an empty leaf
+        assert!(tree.prove(H256::repeat_byte(1)).leaf.is_zero());
+        assert!(tree.prove(H256::repeat_byte(1)).verify(tree.root()));
+
+        tree.update(b, H256::zero());
+        assert_eq!(tree.root(), only_a);
+        tree.update(a, H256::zero());
+        assert!(tree.root().is_zero());
+    }
+
+    #[test]
+    fn test_int256_encoding() {
+        assert_eq!(
+            U256::from_big_endian(&decimal_to_int256(dec!(1.5))),
+            U256::from(1_500_000_000_000_000_000u64)
+        );
+        assert_eq!(decimal_to_int256(dec!(-0.000000000000000001)), [0xff; 32]);
+    }
+
+    #[test]
+    fn test_engine_root_follows_state() {
+        let mut engine = Engine::new();
+        let empty = engine.state_root();
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        engine.create_account(account(2, dec!(1000))).unwrap();
+        let funded = engine.state_root();
+        assert_ne!(funded, empty);
+
+        // a resting order changes the root without touching balances
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(1), dec!(100), 1))
+            .unwrap();
+        let resting = engine.state_root();
+        assert_ne!(resting, funded);
+
+        let address = Address::from_low_u64_be(1);
+        let proof = engine.get_account_proof(address).unwrap();
+        assert!(proof.proof.verify(resting));
+        assert_eq!(
+            proof.proof.leaf,
+            account_leaf(&engine.get_account(address).unwrap())
+        );
+
+        engine.delete_account(Address::from_low_u64_be(2)).unwrap();
+        assert!(!proof.proof.verify(engine.state_root()));
+    }
+
+    #[test]
+    fn test_incremental_sync_matches_a_rebuild() {
+        let mut engine = Engine::new();
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        engine.create_account(account(2, dec!(1000))).unwrap();
+        engine.create_account(account(3, dec!(1000))).unwrap();
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(2), dec!(100), 1))
+            .unwrap();
+        engine
+            .create_order(perp_order(3, Side::Bid, dec!(1), dec!(90), 2))
+            .unwrap();
+        // a partial fill, a cancel, a fill that realises a loss and a
+        // withdrawal
+        engine
+            .create_order(perp_order(2, Side::Ask, dec!(1), dec!(100), 3))
+            .unwrap();
+        let (hash, _) = *engine.markets[&Symbol::DdxPerp]
+            .book
+            .orders()
+            .find(|(_, order)| order.trader_address == Address::from_low_u64_be(1))
+            .unwrap();
+        engine.delete_order(hash).unwrap();
+        engine
+            .create_order(perp_order(1, Side::Ask, dec!(1), dec!(90), 4))
+            .unwrap();
+        engine
+            .withdraw(Address::from_low_u64_be(2), Asset::Usd, dec!(10))
+            .unwrap();
+
+        let incremental = engine.state_root();
+        engine.rebuild_state_tree();
+        assert_eq!(engine.state_root(), incremental);
+    }
+}
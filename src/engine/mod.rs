@@ -1,32 +1,266 @@
 mod orderbook;
-use orderbook::{L2OrderBook, OrderBook};
+use orderbook::{L2Order, L2OrderBook, OrderBookError, DEFAULT_BOOK_DEPTH, MAX_BOOK_DEPTH};
+pub use orderbook::{L3Event, L3Order};
+
+mod deposit;
+pub use deposit::{Asset, Deposit};
+
+mod deposit_source;
+pub use deposit_source::{
+    DepositError, DepositLog, DepositSource, DepositWatcher, FixtureBlock, FixtureDepositSource,
+    RpcDepositSource,
+};
 
 mod error;
 pub use error::EngineError;
 use error::{EngineError as Error, Result};
 
-use std::collections::HashMap;
+mod account_updates;
+pub use account_updates::{AccountUpdate, FillUpdate, Liquidity, OrderStatus, OrderUpdate};
+
+mod adl;
+pub use adl::{AdlRank, DeleverageFill};
+
+mod auth;
+pub use auth::{
+    account_stream_message, cancel_all_message, isolated_margin_message, margin_mode_message,
+    recover_signer, withdrawal_message,
+};
+
+mod batch;
+
+mod cancel_all;
+
+mod candles;
+pub use candles::{Candle, Interval};
+
+mod checkpoint;
+use checkpoint::Checkpoints;
+pub use checkpoint::{BalanceDelta, Checkpoint, LocalSettlement, SettlementError};
+
+mod clock;
+pub use clock::{Clock, ManualClock, SystemClock};
+
+mod event;
+pub use event::Event;
+
+mod funding;
+pub use funding::{FundingParams, FundingSettlement};
+
+mod journal;
+pub use journal::{Command, Journal, JournalError, JournalFailure, Record, ReplayedFill};
+
+mod ledger;
+pub use ledger::{LedgerEntry, LedgerKind};
+
+mod liquidation;
+pub use liquidation::{LiquidatedPosition, LiquidationEvent};
+
+mod market;
+
+mod market_data;
+pub use market_data::{FeedKey, L2Update, L3Snapshot, L3Update, MarketUpdate, Publisher};
+
+mod merkle;
+use market::Market;
+use merkle::StateTree;
+pub use merkle::{AccountProof, MerkleProof};
+
+mod margin;
+
+mod order_status;
+pub use order_status::OrderRecord;
+use order_status::TerminalReason;
+
+mod position;
+pub use position::{MarginMode, Position, PositionView};
+
+mod reduce_only;
+
+mod snapshot;
+pub use snapshot::SnapshotError;
+
+mod stats;
+pub use stats::MarketStats;
+
+mod trades;
+pub use trades::Trade;
+
+mod withdrawal;
+pub use withdrawal::{Withdrawal, WithdrawalClaim};
+
+mod price_feed;
+pub use price_feed::{PriceFeed, PriceFeedError, ReplayPriceFeed};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use web3::types::{Address, H256};
 
-use crate::{Account, Fill, Order, Side};
+use crate::{Account, Fill, MarketKind, Order, Side, Symbol};
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarginSummary {
+    pub collateral: Decimal,
+    pub equity: Decimal,
+    pub initial_margin: Decimal,
+    pub maintenance_margin: Decimal,
+    // held by isolated positions, not part of the figures above
+    pub isolated_margin: Decimal,
+    pub book_outstanding: Decimal,
+    pub free_collateral: Decimal,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarkPrice {
+    pub symbol: Symbol,
+    pub mark_price: Option<Decimal>,
+    pub index_price: Option<Decimal>,
+    pub best_bid: Option<Decimal>,
+    pub best_ask: Option<Decimal>,
+    pub last_price: Option<Decimal>,
+}
+
+// best price and the amount resting at it on each side
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookTicker {
+    pub symbol: Symbol,
+    pub bid: Option<L2Order>,
+    pub ask: Option<L2Order>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FundingInfo {
+    pub symbol: Symbol,
+    pub index_price: Option<Decimal>,
+    pub premium_index: Option<Decimal>,
+    // rate that would be charged if funding settled now
+    pub predicted_rate: Option<Decimal>,
+    pub next_funding_time: u128,
+    pub last_funding: Option<FundingSettlement>,
+}
 
 pub struct Engine {
     accounts: HashMap<Address, Account>,
     // order hash to trader address, for updating balances
     hash_to_address: HashMap<H256, Address>,
-    book: OrderBook,
+    // order hash to the market whose book it rests in
+    hash_to_symbol: HashMap<H256, Symbol>,
+    // resting orders that hold no margin and must never grow their position
+    reduce_only_orders: BTreeSet<H256>,
+    markets: BTreeMap<Symbol, Market>,
+    // ordered so that funding and other sweeps over positions are deterministic
+    positions: BTreeMap<Address, BTreeMap<Symbol, Position>>,
+    ledger: Vec<LedgerEntry>,
+    clock: Box<dyn Clock>,
+    // time of the command being applied, read from the clock once as it
+    // starts so that everything the command does and its journal record agree
+    now: u128,
+    price_feed: Option<Box<dyn PriceFeed>>,
+    // account that absorbs liquidation surpluses and deficits
+    insurance_fund: Address,
+    // where accepted commands are persisted, None for engines that only live
+    // in memory
+    journal: Option<Journal>,
+    // what to do when `journal` cannot take a command
+    journal_failure: JournalFailure,
+    // commitment to balances and resting orders, kept in step with every
+    // accepted command
+    state_tree: StateTree,
+    // batches handed to the settlement contract, each netting balances
+    // against the one before
+    checkpoints: Checkpoints,
+    // (tx hash, log index) of every deposit credited
+    deposits: HashSet<(H256, u64)>,
+    // every withdrawal by hash, with its proof once a checkpoint committed
+    // to it
+    withdrawals: BTreeMap<H256, (Withdrawal, Option<MerkleProof>)>,
+    // where market data goes, None when nobody listens
+    publisher: Option<Box<dyn Publisher>>,
+    // trades not published yet
+    pending_trades: Vec<Trade>,
+    // order, fill and balance updates not published yet
+    pending_account_updates: Vec<AccountUpdate>,
+    // every accepted order, and each trader's in the order they came in
+    order_records: HashMap<H256, OrderRecord>,
+    account_orders: HashMap<Address, Vec<H256>>,
+    // orders whose record was dropped from the history, kept so that they
+    // cannot be submitted again
+    retired_orders: HashSet<H256>,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Engine {
     pub fn new() -> Self {
-        Self {
-            accounts: HashMap::new(),
+        Self::with_clock(Box::new(SystemClock))
+    }
+
+    pub fn with_clock(clock: Box<dyn Clock>) -> Self {
+        let now = clock.now();
+        let insurance_fund = Address::zero();
+        let mut engine = Self {
+            accounts: HashMap::from([(
+                insurance_fund,
+                Account {
+                    ddx_balance: Decimal::ZERO,
+                    usd_balance: Decimal::ZERO,
+                    trader_address: insurance_fund,
+                    ddx_book_outstanding: Decimal::ZERO,
+                    usd_book_outstanding: Decimal::ZERO,
+                },
+            )]),
             hash_to_address: HashMap::new(),
-            book: OrderBook::new(),
-        }
+            hash_to_symbol: HashMap::new(),
+            reduce_only_orders: BTreeSet::new(),
+            markets: Symbol::ALL
+                .iter()
+                .map(|symbol| (*symbol, Market::new(*symbol, now)))
+                .collect(),
+            positions: BTreeMap::new(),
+            ledger: vec![],
+            clock,
+            price_feed: None,
+            insurance_fund,
+            journal: None,
+            journal_failure: journal::panic_on_journal_failure,
+            state_tree: StateTree::default(),
+            checkpoints: Checkpoints::default(),
+            deposits: HashSet::new(),
+            withdrawals: BTreeMap::new(),
+            publisher: None,
+            pending_trades: vec![],
+            pending_account_updates: vec![],
+            order_records: HashMap::new(),
+            account_orders: HashMap::new(),
+            retired_orders: HashSet::new(),
+            now,
+        };
+        engine.rebuild_state_tree();
+        engine
+    }
+
+    pub fn set_price_feed(&mut self, price_feed: Box<dyn PriceFeed>) {
+        self.price_feed = Some(price_feed);
+    }
+
+    // called first by every command, replay sets the clock to the time that
+    // was journaled so the command sees the same time again
+    fn start_command(&mut self) -> u128 {
+        self.now = self.clock.now();
+        self.now
     }
 
     pub fn create_account(&mut self, mut account: Account) -> Result<Address> {
+        self.start_command();
         if self.accounts.contains_key(&account.trader_address) {
             return Err(Error::AccountAlreadyExists(account.trader_address));
         }
@@ -41,6 +275,8 @@ impl Engine {
         account.usd_balance.rescale(18);
         account.ddx_balance.rescale(18);
         self.accounts.insert(account.trader_address, account);
+        self.state_tree.touch_account(account.trader_address);
+        self.record(Command::CreateAccount(account));
         Ok(account.trader_address)
     }
 
@@ -51,16 +287,64 @@ impl Engine {
         Err(Error::AccountNotFound(address))
     }
 
+    // ordered by address
+    pub fn get_accounts(&self) -> Vec<Account> {
+        let mut accounts: Vec<Account> = self.accounts.values().copied().collect();
+        accounts.sort_by_key(|account| account.trader_address);
+        accounts
+    }
+
     pub fn delete_account(&mut self, address: Address) -> Result<()> {
-        if self.accounts.remove(&address).is_some() {
-            return Ok(());
+        self.start_command();
+        if address == self.insurance_fund {
+            return Err(Error::ReservedAccount(address));
+        }
+        self.get_account(address)?;
+        // settlement expects every position, resting order and pending
+        // withdrawal to have an account behind it
+        let open_position = self.positions.get(&address).is_some_and(|positions| {
+            positions
+                .values()
+                .any(|position| !position.size.is_zero() || !position.isolated_margin.is_zero())
+        });
+        let resting_order = self
+            .markets
+            .values()
+            .any(|market| market.book.trader_orders(address).next().is_some());
+        let pending_withdrawal = self.withdrawals.values().any(|(withdrawal, _)| {
+            withdrawal.trader_address == address && withdrawal.checkpoint_id.is_none()
+        });
+        if open_position || resting_order || pending_withdrawal {
+            return Err(Error::AccountInUse(address));
         }
+        self.accounts.remove(&address);
+        self.state_tree.touch_account(address);
+        self.record(Command::DeleteAccount(address));
+        Ok(())
+    }
 
-        Err(Error::AccountNotFound(address))
+    pub fn create_order(&mut self, order: Order) -> Result<Vec<Fill>> {
+        self.start_command();
+        let fills = self.place_order(order)?;
+        self.record(Command::CreateOrder {
+            order,
+            timestamp: order.timestamp,
+        });
+        Ok(fills)
+    }
+
+    // matches and rests an order without recording it
+    fn place_order(&mut self, order: Order) -> Result<Vec<Fill>> {
+        match order.symbol.kind() {
+            MarketKind::Spot => self.create_spot_order(order),
+            MarketKind::Perpetual => self.create_perp_order(order),
+        }
     }
 
     // TODO: make more modular, code for Bid and Ask are similar
-    pub fn create_order(&mut self, order: Order) -> Result<Vec<Fill>> {
+    fn create_spot_order(&mut self, order: Order) -> Result<Vec<Fill>> {
+        self.check_new_order(&order)?;
+        let taker_hash = self.markets[&order.symbol].book.order_hash(&order);
         let taker = self.accounts[&order.trader_address];
         match order.side {
             Side::Bid => {
@@ -74,18 +358,27 @@ impl Engine {
                     .get_mut(&order.trader_address)
                     .unwrap()
                     .usd_book_outstanding += usd_cost;
-                self.book
-                    .add_bid(order)
+                self.markets
+                    .get_mut(&order.symbol)
+                    .unwrap()
+                    .book
+                    .add_bid(taker_hash, order)
                     .map(|(hash_opt, fills)| {
                         // TODO: do hash_to_address removals when the order is completely fulfilled
                         if let Some(hash) = hash_opt {
                             self.hash_to_address.insert(hash, order.trader_address);
+                            self.hash_to_symbol.insert(hash, order.symbol);
+                        } else {
+                            self.release_unfilled(&order, &fills);
                         }
+                        self.record_trades(&order, &fills);
+                        self.report_fills(taker_hash, &order, &fills);
                         fills.iter().for_each(|fill| {
                             let taker = self.accounts.get_mut(&order.trader_address).unwrap();
                             let usd_cost = fill.fill_amount * fill.price;
                             taker.usd_balance -= usd_cost;
-                            taker.usd_book_outstanding -= usd_cost;
+                            // the reservation was taken at the limit price
+                            taker.usd_book_outstanding -= fill.fill_amount * order.price;
                             taker.ddx_balance += fill.fill_amount;
                             let maker = self
                                 .accounts
@@ -94,6 +387,9 @@ impl Engine {
                             maker.ddx_balance -= fill.fill_amount;
                             maker.ddx_book_outstanding -= fill.fill_amount;
                             maker.usd_balance += usd_cost;
+                            self.state_tree.touch_account(order.trader_address);
+                            self.state_tree
+                                .touch_account(self.hash_to_address[&fill.maker_hash]);
                         });
                         fills
                     })
@@ -110,12 +406,20 @@ impl Engine {
                     .get_mut(&order.trader_address)
                     .unwrap()
                     .ddx_book_outstanding += ddx_cost;
-                self.book
-                    .add_ask(order)
+                self.markets
+                    .get_mut(&order.symbol)
+                    .unwrap()
+                    .book
+                    .add_ask(taker_hash, order)
                     .map(|(hash_opt, fills)| {
                         if let Some(hash) = hash_opt {
                             self.hash_to_address.insert(hash, order.trader_address);
+                            self.hash_to_symbol.insert(hash, order.symbol);
+                        } else {
+                            self.release_unfilled(&order, &fills);
                         }
+                        self.record_trades(&order, &fills);
+                        self.report_fills(taker_hash, &order, &fills);
                         fills.iter().for_each(|fill| {
                             let taker = self.accounts.get_mut(&order.trader_address).unwrap();
                             let usd_cost = fill.fill_amount * fill.price;
@@ -129,6 +433,9 @@ impl Engine {
                             maker.usd_balance -= usd_cost;
                             maker.usd_book_outstanding -= usd_cost;
                             maker.ddx_balance += fill.fill_amount;
+                            self.state_tree.touch_account(order.trader_address);
+                            self.state_tree
+                                .touch_account(self.hash_to_address[&fill.maker_hash]);
                         });
                         fills
                     })
@@ -137,15 +444,823 @@ impl Engine {
         }
     }
 
+    fn create_perp_order(&mut self, order: Order) -> Result<Vec<Fill>> {
+        self.check_new_order(&order)?;
+        // reduce-only orders may be resized, they keep the hash they came with
+        let hash = self.markets[&order.symbol].book.order_hash(&order);
+        let order = self.check_reduce_only(order)?;
+        // margin is checked against the full order, but only the part that
+        // rests in the book keeps a reservation, reduce-only orders need none
+        if !order.reduce_only {
+            let initial_margin = self.markets[&order.symbol].initial_margin;
+            let required = order.amount * order.price * initial_margin;
+            let available = self.get_margin(order.trader_address)?.free_collateral;
+            if available < required {
+                return Err(Error::InsufficientMargin(available, required));
+            }
+        }
+        self.execute_perp_order(hash, order)
+    }
+
+    // matches a perpetual order without any margin check, shared with the liquidator
+    fn execute_perp_order(&mut self, hash: H256, order: Order) -> Result<Vec<Fill>> {
+        let initial_margin = self.markets[&order.symbol].initial_margin;
+        let book = &mut self.markets.get_mut(&order.symbol).unwrap().book;
+        let (hash_opt, fills) = match order.side {
+            Side::Bid => book.add_bid(hash, order)?,
+            Side::Ask => book.add_ask(hash, order)?,
+        };
+        if let Some(hash) = hash_opt {
+            self.hash_to_address.insert(hash, order.trader_address);
+            self.hash_to_symbol.insert(hash, order.symbol);
+            if order.reduce_only {
+                self.reduce_only_orders.insert(hash);
+            } else {
+                let resting =
+                    order.amount - fills.iter().map(|fill| fill.fill_amount).sum::<Decimal>();
+                self.accounts
+                    .get_mut(&order.trader_address)
+                    .unwrap()
+                    .usd_book_outstanding += resting * order.price * initial_margin;
+            }
+        }
+        self.record_trades(&order, &fills);
+        self.report_fills(hash, &order, &fills);
+
+        let mut moved = BTreeSet::from([order.trader_address]);
+        for fill in &fills {
+            let taker_amount = match order.side {
+                Side::Bid => fill.fill_amount,
+                Side::Ask => -fill.fill_amount,
+            };
+            let maker_address = self.hash_to_address[&fill.maker_hash];
+            self.apply_perp_fill(order.trader_address, order.symbol, taker_amount, fill.price);
+            self.apply_perp_fill(maker_address, order.symbol, -taker_amount, fill.price);
+            if !self.reduce_only_orders.contains(&fill.maker_hash) {
+                self.accounts
+                    .get_mut(&maker_address)
+                    .unwrap()
+                    .usd_book_outstanding -= fill.fill_amount * fill.price * initial_margin;
+            } else if self.get_order(fill.maker_hash).is_err() {
+                // fully filled
+                self.reduce_only_orders.remove(&fill.maker_hash);
+            }
+            moved.insert(maker_address);
+        }
+        for address in moved {
+            self.enforce_reduce_only(address, order.symbol);
+        }
+        Ok(fills)
+    }
+
+    // realised pnl settles straight into the usd collateral for cross
+    // positions, isolated ones keep it (and the initial margin of anything
+    // they open) until they are flat
+    fn apply_perp_fill(
+        &mut self,
+        address: Address,
+        symbol: Symbol,
+        amount: Decimal,
+        price: Decimal,
+    ) {
+        let initial_margin = self.markets[&symbol].initial_margin;
+        self.state_tree.touch_account(address);
+        let account = self.accounts.get_mut(&address).unwrap();
+        let position = self
+            .positions
+            .entry(address)
+            .or_default()
+            .entry(symbol)
+            .or_default();
+        let before = position.size;
+        let pnl = position.apply_fill(amount, price);
+        match position.margin_mode {
+            MarginMode::Cross => account.usd_balance += pnl,
+            MarginMode::Isolated => {
+                position.isolated_margin += pnl;
+                let opened = if before.is_zero()
+                    || before.is_sign_positive() == position.size.is_sign_positive()
+                {
+                    (position.size.abs() - before.abs()).max(Decimal::ZERO)
+                } else {
+                    position.size.abs()
+                };
+                let top_up = opened * price * initial_margin;
+                position.isolated_margin += top_up;
+                account.usd_balance -= top_up;
+                if position.size.is_zero() {
+                    account.usd_balance += position.isolated_margin;
+                    position.isolated_margin = Decimal::ZERO;
+                }
+            }
+        }
+    }
+
+    // median of the index and the best bid and ask, so that a thin or spoofed
+    // book can only move the mark as far as the spread allows; last trades are
+    // deliberately not used
+    pub fn mark_price(&self, symbol: Symbol) -> Option<Decimal> {
+        let market = &self.markets[&symbol];
+        let book = &market.book;
+        match (market.index_price, book.best_bid(), book.best_ask()) {
+            (Some(index), Some(bid), Some(ask)) => {
+                let mut prices = [index, bid, ask];
+                prices.sort();
+                Some(prices[1])
+            }
+            (Some(index), _, _) => Some(index),
+            (None, _, _) => book.mid_price(),
+        }
+    }
+
+    pub fn get_mark(&self, symbol: Symbol) -> MarkPrice {
+        let market = &self.markets[&symbol];
+        MarkPrice {
+            symbol,
+            mark_price: self.mark_price(symbol),
+            index_price: market.index_price,
+            best_bid: market.book.best_bid(),
+            best_ask: market.book.best_ask(),
+            last_price: market.last_price,
+        }
+    }
+
+    pub fn get_positions(&self, address: Address) -> Result<Vec<PositionView>> {
+        self.get_account(address)?;
+        Ok(self.position_views(address))
+    }
+
+    // every open position by trader, whether or not the trader still has an
+    // account
+    pub fn get_all_positions(&self) -> BTreeMap<Address, Vec<PositionView>> {
+        self.positions
+            .keys()
+            .map(|address| (*address, self.position_views(*address)))
+            .filter(|(_, positions)| !positions.is_empty())
+            .collect()
+    }
+
+    fn position_views(&self, address: Address) -> Vec<PositionView> {
+        let Some(positions) = self.positions.get(&address) else {
+            return vec![];
+        };
+        positions
+            .iter()
+            .filter(|(_, position)| !position.size.is_zero())
+            .map(|(symbol, position)| {
+                let market = &self.markets[symbol];
+                let mark_price = self.mark_price(*symbol).unwrap_or(position.entry_price);
+                PositionView {
+                    symbol: *symbol,
+                    size: position.size,
+                    entry_price: position.entry_price,
+                    mark_price,
+                    realized_pnl: position.realized_pnl,
+                    unrealized_pnl: position.unrealized_pnl(mark_price),
+                    initial_margin: position.notional(mark_price) * market.initial_margin,
+                    maintenance_margin: position.notional(mark_price) * market.maintenance_margin,
+                    margin_mode: position.margin_mode,
+                    isolated_margin: position.isolated_margin,
+                }
+            })
+            .collect()
+    }
+
+    // isolated positions are left out of the account level figures, they can
+    // neither draw on nor drag down the shared collateral
+    pub fn get_margin(&self, address: Address) -> Result<MarginSummary> {
+        let account = self.get_account(address)?;
+        let (positions, isolated): (Vec<PositionView>, Vec<PositionView>) = self
+            .get_positions(address)?
+            .into_iter()
+            .partition(|position| position.margin_mode == MarginMode::Cross);
+        let equity = account.usd_balance
+            + positions
+                .iter()
+                .map(|position| position.unrealized_pnl)
+                .sum::<Decimal>();
+        let initial_margin = positions
+            .iter()
+            .map(|position| position.initial_margin)
+            .sum::<Decimal>();
+        Ok(MarginSummary {
+            collateral: account.usd_balance,
+            equity,
+            initial_margin,
+            maintenance_margin: positions
+                .iter()
+                .map(|position| position.maintenance_margin)
+                .sum(),
+            isolated_margin: isolated
+                .iter()
+                .map(|position| position.isolated_margin)
+                .sum(),
+            book_outstanding: account.usd_book_outstanding,
+            free_collateral: equity - initial_margin - account.usd_book_outstanding,
+        })
+    }
+
+    fn perp_market(&self, symbol: Symbol) -> Result<&Market> {
+        match symbol.kind() {
+            MarketKind::Perpetual => Ok(&self.markets[&symbol]),
+            MarketKind::Spot => Err(Error::NotPerpetual(symbol)),
+        }
+    }
+
+    pub fn set_index_price(&mut self, symbol: Symbol, index_price: Decimal) -> Result<()> {
+        self.start_command();
+        if index_price <= Decimal::ZERO {
+            return Err(Error::InvalidPrice(index_price));
+        }
+        self.markets.get_mut(&symbol).unwrap().index_price = Some(index_price);
+        self.record(Command::SetIndexPrice {
+            symbol,
+            index_price,
+        });
+        Ok(())
+    }
+
+    pub fn get_funding(&self, symbol: Symbol) -> Result<FundingInfo> {
+        let market = self.perp_market(symbol)?;
+        let premium_index = market.index_price.map(|index_price| {
+            funding::premium_index(&market.book, index_price, market.funding.impact_notional)
+        });
+        Ok(FundingInfo {
+            symbol,
+            index_price: market.index_price,
+            premium_index,
+            predicted_rate: premium_index
+                .map(|premium_index| funding::funding_rate(premium_index, &market.funding)),
+            next_funding_time: market.next_funding_time,
+            last_funding: market.last_funding,
+        })
+    }
+
+    // settles funding for the market if an interval boundary has passed,
+    // missed intervals are not charged retroactively
+    fn settle_funding(&mut self, symbol: Symbol) -> Result<Option<FundingSettlement>> {
+        let now = self.now;
+        let market = self.perp_market(symbol)?;
+        if now < market.next_funding_time {
+            return Ok(None);
+        }
+        let info = self.get_funding(symbol)?;
+        let (Some(index_price), Some(premium_index), Some(funding_rate)) =
+            (info.index_price, info.premium_index, info.predicted_rate)
+        else {
+            // nothing to price against yet, stay due until an index arrives
+            return Ok(None);
+        };
+
+        for (address, positions) in self.positions.iter_mut() {
+            let Some(position) = positions.get_mut(&symbol) else {
+                continue;
+            };
+            if position.size.is_zero() {
+                continue;
+            }
+            let amount = -position.size * index_price * funding_rate;
+            match position.margin_mode {
+                MarginMode::Cross => {
+                    self.accounts.get_mut(address).unwrap().usd_balance += amount;
+                    self.state_tree.touch_account(*address);
+                }
+                MarginMode::Isolated => position.isolated_margin += amount,
+            }
+            self.ledger.push(LedgerEntry {
+                timestamp: now,
+                trader_address: *address,
+                symbol: Some(symbol),
+                kind: LedgerKind::Funding,
+                amount,
+            });
+        }
+
+        let settlement = FundingSettlement {
+            symbol,
+            timestamp: now,
+            index_price,
+            premium_index,
+            funding_rate,
+        };
+        let market = self.markets.get_mut(&symbol).unwrap();
+        let interval = market.funding.interval;
+        market.next_funding_time = (now / interval + 1) * interval;
+        market.last_funding = Some(settlement);
+        Ok(Some(settlement))
+    }
+
+    // periodic housekeeping, driven by the server on a timer
+    pub fn tick(&mut self) -> Vec<Event> {
+        let now = self.start_command();
+        let index_prices: Vec<(Symbol, Decimal)> = match self.price_feed.as_mut() {
+            Some(price_feed) => Symbol::ALL
+                .iter()
+                .filter_map(|symbol| Some((*symbol, price_feed.index_price(*symbol, now)?)))
+                .collect(),
+            None => vec![],
+        };
+        let repriced = index_prices
+            .iter()
+            .any(|(symbol, price)| self.markets[symbol].index_price != Some(*price));
+        let events = self.apply_tick(&index_prices);
+        // most ticks change nothing and are not worth replaying
+        if repriced || !events.is_empty() {
+            self.record(Command::Tick { index_prices });
+        }
+        self.publish_events(&events);
+        events
+    }
+
+    fn apply_tick(&mut self, index_prices: &[(Symbol, Decimal)]) -> Vec<Event> {
+        for (symbol, index_price) in index_prices {
+            self.markets.get_mut(symbol).unwrap().index_price = Some(*index_price);
+        }
+
+        let mut events: Vec<Event> = Symbol::ALL
+            .iter()
+            .filter(|symbol| symbol.kind() == MarketKind::Perpetual)
+            .filter_map(|symbol| self.settle_funding(*symbol).ok().flatten())
+            .map(Event::Funding)
+            .collect();
+        events.extend(
+            self.check_liquidations()
+                .into_iter()
+                .map(Event::Liquidation),
+        );
+        events
+    }
+
+    pub fn get_ledger(&self, address: Address) -> Result<Vec<LedgerEntry>> {
+        self.get_account(address)?;
+        Ok(self
+            .ledger
+            .iter()
+            .filter(|entry| entry.trader_address == address)
+            .copied()
+            .collect())
+    }
+
     pub fn get_order(&self, order_hash: H256) -> Result<Order> {
-        self.book.get_order(order_hash).map_err(|e| e.into())
+        let symbol = self
+            .hash_to_symbol
+            .get(&order_hash)
+            .ok_or(OrderBookError::OrderNotFound(order_hash))?;
+        self.markets[symbol]
+            .book
+            .get_order(order_hash)
+            .map_err(|e| e.into())
     }
 
     pub fn delete_order(&mut self, order_hash: H256) -> Result<()> {
-        self.book.delete_order(order_hash).map_err(|e| e.into())
+        self.start_command();
+        self.cancel_order(order_hash, TerminalReason::Trader)?;
+        self.record(Command::DeleteOrder(order_hash));
+        Ok(())
+    }
+
+    // removes a resting order on the engine's own account, e.g. during a
+    // liquidation, which is not a command of its own
+    fn cancel_order(&mut self, order_hash: H256, reason: TerminalReason) -> Result<()> {
+        let order = self.get_order(order_hash)?;
+        self.markets
+            .get_mut(&order.symbol)
+            .unwrap()
+            .book
+            .delete_order(order_hash)?;
+        if !self.reduce_only_orders.remove(&order_hash) {
+            self.release_reservation(&order);
+        }
+        self.report_cancel(order_hash, &order, reason);
+        Ok(())
+    }
+
+    // spot orders reserve up front, so when the remainder of a taker does not
+    // make it into the book (ioc or self match) its share is handed back
+    fn release_unfilled(&mut self, order: &Order, fills: &[Fill]) {
+        let unfilled = Order {
+            amount: order.amount - fills.iter().map(|fill| fill.fill_amount).sum::<Decimal>(),
+            ..*order
+        };
+        self.release_reservation(&unfilled);
+    }
+
+    // gives back what a resting order was holding against its trader's balances
+    fn release_reservation(&mut self, order: &Order) {
+        let initial_margin = self.markets[&order.symbol].initial_margin;
+        let Some(account) = self.accounts.get_mut(&order.trader_address) else {
+            return;
+        };
+        match (order.symbol.kind(), order.side) {
+            (MarketKind::Spot, Side::Bid) => {
+                account.usd_book_outstanding -= order.amount * order.price
+            }
+            (MarketKind::Spot, Side::Ask) => account.ddx_book_outstanding -= order.amount,
+            (MarketKind::Perpetual, _) => {
+                account.usd_book_outstanding -= order.amount * order.price * initial_margin
+            }
+        }
+    }
+
+    pub fn get_book(&self, symbol: Symbol) -> L2OrderBook {
+        self.markets[&symbol]
+            .book
+            .l2_snapshot(DEFAULT_BOOK_DEPTH, None)
+    }
+
+    // depth is capped at MAX_BOOK_DEPTH levels per side
+    pub fn get_grouped_book(
+        &self,
+        symbol: Symbol,
+        depth: usize,
+        grouping: Option<Decimal>,
+    ) -> Result<L2OrderBook> {
+        if let Some(grouping) = grouping.filter(|grouping| *grouping <= Decimal::ZERO) {
+            return Err(Error::InvalidGrouping(grouping));
+        }
+        Ok(self.markets[&symbol]
+            .book
+            .l2_snapshot(depth.min(MAX_BOOK_DEPTH), grouping))
+    }
+
+    pub fn get_book_ticker(&self, symbol: Symbol) -> BookTicker {
+        let book = self.markets[&symbol].book.l2_snapshot(1, None);
+        BookTicker {
+            symbol,
+            bid: book.bids.first().copied(),
+            ask: book.asks.first().copied(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{Nonce, TimeInForce};
+
+    pub(super) fn trader(n: u64) -> Address {
+        Address::from_low_u64_be(n)
+    }
+
+    // a file in the temp dir that no other test or test run shares, removed
+    // in case an earlier run left it behind
+    pub(super) fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("derivadex-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    pub(super) fn account(n: u64, usd_balance: Decimal) -> Account {
+        Account {
+            ddx_balance: Decimal::ZERO,
+            usd_balance,
+            trader_address: Address::from_low_u64_be(n),
+            ddx_book_outstanding: Decimal::ZERO,
+            usd_book_outstanding: Decimal::ZERO,
+        }
+    }
+
+    pub(super) fn perp_order(
+        n: u64,
+        side: Side,
+        amount: Decimal,
+        price: Decimal,
+        timestamp: u128,
+    ) -> Order {
+        Order {
+            amount,
+            nonce: Nonce(H256::from_low_u64_be(timestamp as u64)),
+            price,
+            side,
+            trader_address: Address::from_low_u64_be(n),
+            symbol: Symbol::DdxPerp,
+            time_in_force: TimeInForce::Gtc,
+            reduce_only: false,
+            close_position: false,
+            timestamp,
+        }
+    }
+
+    // an engine on a manual clock whose DDXPERP index follows `prices`, ticked
+    // once at 0 to take the first one, with an account per balance for
+    // traders 1, 2, ...
+    pub(super) fn priced_engine(
+        prices: &[(u128, Decimal)],
+        balances: &[Decimal],
+    ) -> (Engine, ManualClock) {
+        let clock = ManualClock::new(0);
+        let mut engine = Engine::with_clock(Box::new(clock.clone()));
+        let mut feed = ReplayPriceFeed::new();
+        for (timestamp, price) in prices {
+            feed.push(Symbol::DdxPerp, *timestamp, *price);
+        }
+        engine.set_price_feed(Box::new(feed));
+        engine.tick();
+        for (n, balance) in balances.iter().enumerate() {
+            engine
+                .create_account(account(n as u64 + 1, *balance))
+                .unwrap();
+        }
+        (engine, clock)
+    }
+
+    #[test]
+    fn test_perp_fills_open_and_close_positions() {
+        let mut engine = Engine::new();
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        engine.create_account(account(2, dec!(1000))).unwrap();
+
+        let fills = engine
+            .create_order(perp_order(1, Side::Ask, dec!(10), dec!(100), 1))
+            .unwrap();
+        assert!(fills.is_empty());
+        assert_eq!(
+            engine
+                .get_margin(Address::from_low_u64_be(1))
+                .unwrap()
+                .book_outstanding,
+            dec!(100)
+        );
+
+        let fills = engine
+            .create_order(perp_order(2, Side::Bid, dec!(4), dec!(100), 2))
+            .unwrap();
+        assert_eq!(fills.len(), 1);
+        let maker = engine.get_positions(Address::from_low_u64_be(1)).unwrap();
+        let taker = engine.get_positions(Address::from_low_u64_be(2)).unwrap();
+        assert_eq!(maker[0].size, dec!(-4));
+        assert_eq!(taker[0].size, dec!(4));
+        assert_eq!(taker[0].entry_price, dec!(100));
+        // spot balances are untouched
+        assert_eq!(
+            engine
+                .get_account(Address::from_low_u64_be(2))
+                .unwrap()
+                .ddx_balance,
+            dec!(0)
+        );
+        assert_eq!(
+            engine
+                .get_margin(Address::from_low_u64_be(1))
+                .unwrap()
+                .book_outstanding,
+            dec!(60)
+        );
+
+        // maker pulls the rest of the quote and buys back higher, realising a loss
+        let hash = *engine.hash_to_symbol.keys().next().unwrap();
+        engine.delete_order(hash).unwrap();
+        assert_eq!(
+            engine
+                .get_margin(Address::from_low_u64_be(1))
+                .unwrap()
+                .book_outstanding,
+            dec!(0)
+        );
+        engine
+            .create_order(perp_order(2, Side::Ask, dec!(4), dec!(110), 3))
+            .unwrap();
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(4), dec!(110), 4))
+            .unwrap();
+        assert!(engine
+            .get_positions(Address::from_low_u64_be(1))
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            engine
+                .get_account(Address::from_low_u64_be(1))
+                .unwrap()
+                .usd_balance,
+            dec!(960)
+        );
+        assert_eq!(
+            engine
+                .get_account(Address::from_low_u64_be(2))
+                .unwrap()
+                .usd_balance,
+            dec!(1040)
+        );
     }
 
-    pub fn get_book(&self) -> L2OrderBook {
-        self.book.l2_snapshot()
+    #[test]
+    fn test_resting_order_fills_more_than_once() {
+        let mut engine = Engine::new();
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        engine.create_account(account(2, dec!(1000))).unwrap();
+        engine
+            .create_order(perp_order(1, Side::Ask, dec!(3), dec!(100), 1))
+            .unwrap();
+
+        let first = engine
+            .create_order(perp_order(2, Side::Bid, dec!(1), dec!(100), 2))
+            .unwrap();
+        // the maker keeps its submitted hash after shrinking, so it can fill again
+        let second = engine
+            .create_order(perp_order(2, Side::Bid, dec!(1), dec!(100), 3))
+            .unwrap();
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].maker_hash, first[0].maker_hash);
+        assert_eq!(
+            engine.get_order(first[0].maker_hash).unwrap().amount,
+            dec!(1)
+        );
+    }
+
+    #[test]
+    fn test_delete_account_waits_for_open_state() {
+        let mut engine = Engine::new();
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        engine.create_account(account(2, dec!(1000))).unwrap();
+        engine
+            .create_order(perp_order(1, Side::Ask, dec!(2), dec!(100), 1))
+            .unwrap();
+        engine
+            .create_order(perp_order(2, Side::Bid, dec!(1), dec!(100), 2))
+            .unwrap();
+        // one is short and still quoting, the other long
+        assert!(matches!(
+            engine.delete_account(trader(1)),
+            Err(Error::AccountInUse(_))
+        ));
+        assert!(matches!(
+            engine.delete_account(trader(2)),
+            Err(Error::AccountInUse(_))
+        ));
+
+        engine.create_account(account(3, dec!(1000))).unwrap();
+        engine.withdraw(trader(3), Asset::Usd, dec!(10)).unwrap();
+        assert!(matches!(
+            engine.delete_account(trader(3)),
+            Err(Error::AccountInUse(_))
+        ));
+        engine.checkpoint().unwrap();
+        engine.delete_account(trader(3)).unwrap();
+
+        // positions are listed by trader, even one without an account
+        engine.accounts.remove(&trader(2));
+        assert!(engine.get_positions(trader(2)).is_err());
+        let positions = engine.get_all_positions();
+        assert_eq!(positions.len(), 2);
+        assert_eq!(positions[&trader(2)][0].size, dec!(1));
+    }
+
+    #[test]
+    fn test_perp_order_requires_margin() {
+        let mut engine = Engine::new();
+        engine.create_account(account(1, dec!(100))).unwrap();
+        assert!(engine
+            .create_order(perp_order(1, Side::Bid, dec!(10), dec!(100), 1))
+            .is_ok());
+        assert!(matches!(
+            engine.create_order(perp_order(1, Side::Bid, dec!(1), dec!(100), 2)),
+            Err(Error::InsufficientMargin(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_funding_settles_on_schedule() {
+        let clock = ManualClock::new(0);
+        let mut engine = Engine::with_clock(Box::new(clock.clone()));
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        engine.create_account(account(2, dec!(1000))).unwrap();
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(10), dec!(100), 1))
+            .unwrap();
+        engine
+            .create_order(perp_order(2, Side::Ask, dec!(10), dec!(100), 2))
+            .unwrap();
+
+        // no index yet, and not due yet
+        clock.set(funding::NANOS_PER_HOUR as u64 * 8);
+        assert!(engine.tick().is_empty());
+        engine.set_index_price(Symbol::DdxPerp, dec!(100)).unwrap();
+        clock.set(funding::NANOS_PER_HOUR as u64 * 8 - 1);
+        assert!(engine.tick().is_empty());
+        assert!(engine.ledger.is_empty());
+
+        // empty book has no premium, so the interest rate applies
+        clock.advance(1);
+        let events = engine.tick();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            events[0],
+            Event::Funding(FundingSettlement { funding_rate, .. }) if funding_rate == dec!(0.0001)
+        ));
+        let long = engine.get_ledger(Address::from_low_u64_be(1)).unwrap();
+        let short = engine.get_ledger(Address::from_low_u64_be(2)).unwrap();
+        assert_eq!(long[0].amount, dec!(-0.1));
+        assert_eq!(short[0].amount, dec!(0.1));
+        assert_eq!(
+            engine
+                .get_funding(Symbol::DdxPerp)
+                .unwrap()
+                .next_funding_time,
+            funding::NANOS_PER_HOUR * 16
+        );
+
+        // already settled for this interval
+        assert!(engine.tick().is_empty());
+    }
+
+    #[test]
+    fn test_mark_price_from_feed_and_book() {
+        let (mut engine, clock) = priced_engine(
+            &[(10, dec!(100)), (20, dec!(90))],
+            &[dec!(1000), dec!(1000)],
+        );
+        assert_eq!(engine.mark_price(Symbol::DdxPerp), None);
+
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(1), dec!(99), 1))
+            .unwrap();
+        engine
+            .create_order(perp_order(2, Side::Ask, dec!(1), dec!(101), 2))
+            .unwrap();
+        // book only
+        assert_eq!(engine.mark_price(Symbol::DdxPerp), Some(dec!(100)));
+
+        clock.set(10);
+        engine.tick();
+        assert_eq!(engine.mark_price(Symbol::DdxPerp), Some(dec!(100)));
+
+        // index below the spread pulls the mark to the best bid
+        clock.set(20);
+        engine.tick();
+        assert_eq!(engine.mark_price(Symbol::DdxPerp), Some(dec!(99)));
+
+        // a far away print does not move the mark
+        engine
+            .create_order(perp_order(2, Side::Ask, dec!(1), dec!(50), 3))
+            .unwrap();
+        assert_eq!(engine.get_mark(Symbol::DdxPerp).last_price, Some(dec!(99)));
+        let long = engine.get_positions(Address::from_low_u64_be(1)).unwrap();
+        assert_eq!(long[0].mark_price, dec!(90));
+        assert_eq!(long[0].unrealized_pnl, dec!(-9));
+    }
+
+    #[test]
+    fn test_grouped_book_and_ticker() {
+        let mut engine = Engine::new();
+        engine.create_account(account(1, dec!(10000))).unwrap();
+        engine.create_account(account(2, dec!(10000))).unwrap();
+        for (n, side, price, timestamp) in [
+            (1, Side::Bid, dec!(99.5), 1),
+            (1, Side::Bid, dec!(99.2), 2),
+            (1, Side::Bid, dec!(98.9), 3),
+            (2, Side::Ask, dec!(100.1), 4),
+            (2, Side::Ask, dec!(100.7), 5),
+        ] {
+            engine
+                .create_order(perp_order(n, side, dec!(1), price, timestamp))
+                .unwrap();
+        }
+
+        let ticker = engine.get_book_ticker(Symbol::DdxPerp);
+        assert_eq!(ticker.bid.map(|bid| bid.price), Some(dec!(99.5)));
+        assert_eq!(ticker.ask.map(|ask| ask.amount), Some(dec!(1)));
+
+        // bids round down and asks up, depth counts grouped levels
+        let book = engine
+            .get_grouped_book(Symbol::DdxPerp, 1, Some(dec!(1)))
+            .unwrap();
+        assert_eq!(
+            book.bids,
+            vec![L2Order {
+                amount: dec!(2),
+                price: dec!(99)
+            }]
+        );
+        assert_eq!(
+            book.asks,
+            vec![L2Order {
+                amount: dec!(2),
+                price: dec!(101)
+            }]
+        );
+        // the checksum is the stream's, grouping or not
+        assert_eq!(
+            book.checksum,
+            engine.get_l2_snapshot(Symbol::DdxPerp).checksum
+        );
+        assert_eq!(
+            book.checksum,
+            crc32fast::hash(b"99.5:1:99.2:1:98.9:1:100.1:1:100.7:1")
+        );
+        assert_eq!(
+            engine
+                .get_grouped_book(Symbol::DdxPerp, usize::MAX, None)
+                .unwrap()
+                .bids
+                .len(),
+            3
+        );
+        assert!(matches!(
+            engine.get_grouped_book(Symbol::DdxPerp, 10, Some(dec!(0))),
+            Err(Error::InvalidGrouping(_))
+        ));
     }
 }
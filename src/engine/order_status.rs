@@ -0,0 +1,289 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use web3::types::{Address, H256};
+
+use super::{
+    account_updates::{AccountUpdate, OrderStatus},
+    error::Result,
+    orderbook::OrderBookError,
+    Engine,
+};
+use crate::Order;
+
+// most orders one page of an account's history holds
+pub const MAX_ORDERS: usize = 1_000;
+// orders kept per trader, past it the oldest that left the book are dropped
+pub const ORDER_HISTORY: usize = 10_000;
+
+// why an order left the book without filling completely
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TerminalReason {
+    // deleted by its trader
+    Trader,
+    // its trader was liquidated
+    Liquidation,
+    // a reduce-only order with no position left to reduce
+    ReduceOnly,
+    // the remainder would have matched its own trader's order
+    SelfMatch,
+    // the remainder of an ioc order
+    ImmediateOrCancel,
+}
+
+// an order as submitted and everything that happened to it since, kept after
+// it leaves the book
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrderRecord {
+    pub hash: H256,
+    // amount is the original amount
+    #[serde(flatten)]
+    pub order: Order,
+    pub filled: Decimal,
+    // still open in the book, less than amount - filled once reduce-only
+    // enforcement shrank the order
+    pub remaining: Decimal,
+    // None until the first fill
+    pub average_price: Option<Decimal>,
+    pub status: OrderStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<TerminalReason>,
+    // time of the last change
+    pub updated: u128,
+}
+
+impl Engine {
+    // a hash names one order for good, it cannot come back once it left the
+    // book
+    pub(super) fn check_new_order(&self, order: &Order) -> Result<()> {
+        let hash = self.markets[&order.symbol].book.order_hash(order);
+        if self.order_records.contains_key(&hash) || self.retired_orders.contains(&hash) {
+            return Err(OrderBookError::DuplicateOrder(hash, order.trader_address).into());
+        }
+        Ok(())
+    }
+
+    pub(super) fn open_order_record(&mut self, hash: H256, order: &Order) {
+        let record = OrderRecord {
+            hash,
+            order: *order,
+            filled: Decimal::ZERO,
+            remaining: order.amount,
+            average_price: None,
+            status: OrderStatus::Accepted,
+            reason: None,
+            updated: self.now,
+        };
+        if self.order_records.insert(hash, record).is_none() {
+            self.account_orders
+                .entry(order.trader_address)
+                .or_default()
+                .push(hash);
+        }
+    }
+
+    // drops the oldest orders that left the book from each history past
+    // ORDER_HISTORY, keeping only their hash. a history only grows with an
+    // order update for its trader, so only those traders are looked at
+    pub(super) fn trim_orders(&mut self) {
+        let traders: BTreeSet<Address> = self
+            .pending_account_updates
+            .iter()
+            .filter_map(|update| match update {
+                AccountUpdate::Order(update) => Some(update.trader_address),
+                _ => None,
+            })
+            .collect();
+        for trader in traders {
+            let Some(hashes) = self.account_orders.get_mut(&trader) else {
+                continue;
+            };
+            let mut excess = hashes.len().saturating_sub(ORDER_HISTORY);
+            hashes.retain(|hash| {
+                let done = matches!(
+                    self.order_records[hash].status,
+                    OrderStatus::Filled | OrderStatus::Cancelled | OrderStatus::Expired
+                );
+                if excess == 0 || !done {
+                    return true;
+                }
+                excess -= 1;
+                self.order_records.remove(hash);
+                self.retired_orders.insert(*hash);
+                false
+            });
+        }
+    }
+
+    // keeps the records in step with the updates their traders are sent
+    pub(super) fn track_order(&mut self, update: &AccountUpdate) {
+        match update {
+            AccountUpdate::Order(update) => {
+                if let Some(record) = self.order_records.get_mut(&update.hash) {
+                    record.remaining = update.remaining;
+                    record.status = update.status;
+                    record.reason = update.reason;
+                    record.updated = update.timestamp;
+                }
+            }
+            AccountUpdate::Fill(fill) => {
+                if let Some(record) = self.order_records.get_mut(&fill.order_hash) {
+                    let notional = record.average_price.unwrap_or_default() * record.filled
+                        + fill.price * fill.amount;
+                    record.filled += fill.amount;
+                    record.average_price = Some(notional / record.filled);
+                    record.updated = fill.timestamp;
+                }
+            }
+            AccountUpdate::Balance(_) | AccountUpdate::Liquidation(_) => {}
+        }
+    }
+
+    pub fn get_order_status(&self, hash: H256) -> Result<OrderRecord> {
+        self.order_records
+            .get(&hash)
+            .cloned()
+            .ok_or_else(|| OrderBookError::OrderNotFound(hash).into())
+    }
+
+    // newest first, `offset` orders in
+    pub fn get_account_orders(
+        &self,
+        address: Address,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<OrderRecord>> {
+        self.get_account(address)?;
+        let hashes = self
+            .account_orders
+            .get(&address)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        Ok(hashes
+            .iter()
+            .rev()
+            .skip(offset)
+            .take(limit.min(MAX_ORDERS))
+            .map(|hash| self.order_records[hash].clone())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{
+        engine::{
+            error::EngineError,
+            tests::{account, perp_order, trader},
+        },
+        Side, SystemClock, TimeInForce,
+    };
+
+    #[test]
+    fn test_completed_orders_keep_their_status() {
+        let mut engine = Engine::new();
+        let maker = Address::from_low_u64_be(1);
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        engine.create_account(account(2, dec!(1000))).unwrap();
+        engine
+            .create_order(perp_order(1, Side::Ask, dec!(1), dec!(100), 1))
+            .unwrap();
+        engine
+            .create_order(perp_order(1, Side::Ask, dec!(1), dec!(102), 2))
+            .unwrap();
+        let fills = engine
+            .create_order(perp_order(2, Side::Bid, dec!(3), dec!(102), 3))
+            .unwrap();
+        let ioc = engine
+            .create_order(Order {
+                time_in_force: TimeInForce::Ioc,
+                ..perp_order(1, Side::Bid, dec!(1), dec!(90), 4)
+            })
+            .unwrap();
+        assert!(ioc.is_empty());
+
+        let taker = engine.get_order_status(fills[0].taker_hash).unwrap();
+        assert_eq!(taker.order.amount, dec!(3));
+        assert_eq!(taker.filled, dec!(2));
+        assert_eq!(taker.average_price, Some(dec!(101)));
+        assert_eq!(taker.status, OrderStatus::PartiallyFilled);
+        assert_eq!(taker.remaining, dec!(1));
+        engine.delete_order(taker.hash).unwrap();
+
+        // a hash cannot be reused once its order left the book
+        assert!(matches!(
+            engine.create_order(perp_order(1, Side::Ask, dec!(1), dec!(100), 1)),
+            Err(EngineError::OrderBookError(OrderBookError::DuplicateOrder(
+                _,
+                _
+            )))
+        ));
+
+        // the snapshot carries the history over
+        let (engine, _) = Engine::from_snapshot(&engine.snapshot(), Box::new(SystemClock)).unwrap();
+        let taker = engine.get_order_status(taker.hash).unwrap();
+        assert_eq!(
+            (taker.status, taker.reason),
+            (OrderStatus::Cancelled, Some(TerminalReason::Trader))
+        );
+        let orders = engine.get_account_orders(maker, 0, 10).unwrap();
+        assert_eq!(
+            orders
+                .iter()
+                .map(|order| (order.status, order.reason))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    OrderStatus::Expired,
+                    Some(TerminalReason::ImmediateOrCancel)
+                ),
+                (OrderStatus::Filled, None),
+                (OrderStatus::Filled, None),
+            ]
+        );
+        assert_eq!(engine.get_account_orders(maker, 2, 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_old_orders_only_keep_their_hash() {
+        let mut engine = Engine::new();
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        // a full history, all done but the first order
+        let hashes: Vec<H256> = (1..=ORDER_HISTORY as u128)
+            .map(|timestamp| {
+                let order = perp_order(1, Side::Ask, dec!(1), dec!(100), timestamp);
+                let hash = engine.markets[&order.symbol].book.order_hash(&order);
+                engine.open_order_record(hash, &order);
+                hash
+            })
+            .collect();
+        for hash in &hashes[1..] {
+            engine.order_records.get_mut(hash).unwrap().status = OrderStatus::Filled;
+        }
+        engine
+            .create_order(perp_order(1, Side::Ask, dec!(1), dec!(100), 0))
+            .unwrap();
+
+        let orders = engine.get_account_orders(trader(1), 0, MAX_ORDERS).unwrap();
+        assert_eq!(engine.account_orders[&trader(1)].len(), ORDER_HISTORY);
+        assert_eq!(orders.len(), MAX_ORDERS);
+        assert!(engine.get_order_status(hashes[0]).is_ok());
+        assert!(engine.get_order_status(hashes[1]).is_err());
+
+        // dropped orders are still rejected, after a restore too
+        let (mut engine, _) =
+            Engine::from_snapshot(&engine.snapshot(), Box::new(SystemClock)).unwrap();
+        assert!(matches!(
+            engine.create_order(perp_order(1, Side::Ask, dec!(1), dec!(100), 2)),
+            Err(EngineError::OrderBookError(OrderBookError::DuplicateOrder(
+                _,
+                _
+            )))
+        ));
+    }
+}
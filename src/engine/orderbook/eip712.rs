@@ -33,6 +33,12 @@ impl EncodeDataable for u8 {
     }
 }
 
+impl EncodeDataable for bool {
+    fn encode_data(&self) -> Vec<u8> {
+        U256::from(*self as u8).encode_data()
+    }
+}
+
 impl EncodeDataable for Address {
     fn encode_data(&self) -> Vec<u8> {
         U256::from(self.as_bytes()).encode_data()
@@ -98,7 +104,7 @@ mod tests {
     use std::str::FromStr;
 
     use super::*;
-    use crate::{Nonce, Order, Side};
+    use crate::{Nonce, Order, Side, Symbol, TimeInForce};
 
     #[test]
     fn test_eip712() {
@@ -115,13 +121,34 @@ mod tests {
             side: Side::Bid,
             trader_address: Address::from_str("0x3A880652F47bFaa771908C07Dd8673A787dAEd3A")
                 .unwrap(),
+            symbol: Symbol::DdxUsd,
+            time_in_force: TimeInForce::Gtc,
+            reduce_only: false,
+            close_position: false,
             timestamp: 0,
         };
         let hash = eip712.encode(order);
         assert_eq!(
             hash,
-            H256::from_str("0x15a7b83cc86b50aaa2fa0c0871d5dbaae62f116436291e976c84b034b58cb728")
+            H256::from_str("0x528cbe76cfb45b98b9f2fce124d22f410ab330d7582bacf4f91f4fbe56a7a347")
                 .unwrap()
         );
+        // the flags are signed too
+        for flagged in [
+            Order {
+                reduce_only: true,
+                ..order
+            },
+            Order {
+                close_position: true,
+                ..order
+            },
+            Order {
+                time_in_force: TimeInForce::Ioc,
+                ..order
+            },
+        ] {
+            assert_ne!(eip712.encode(flagged), hash);
+        }
     }
 }
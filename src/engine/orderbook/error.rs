@@ -1,4 +1,5 @@
 use displaydoc::Display;
+use rust_decimal::Decimal;
 use thiserror::Error;
 use web3::types::{Address, H256};
 
@@ -11,4 +12,7 @@ pub enum OrderBookError {
 
     /// order with hash {0} not found,
     OrderNotFound(H256),
+
+    /// aggregated amount at price {0} does not match the orders resting there
+    InconsistentLevel(Decimal),
 }
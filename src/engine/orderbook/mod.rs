@@ -2,7 +2,7 @@ mod error;
 pub use error::OrderBookError;
 use error::{OrderBookError as Error, Result};
 
-mod eip712;
+pub(super) mod eip712;
 use eip712::{Eip712, Eip712Domain, EncodeDataable, TypeHashable};
 
 use lazy_static::lazy_static;
@@ -11,15 +11,15 @@ use rust_decimal_macros::dec;
 use serde::Serialize;
 use std::{
     cmp::Reverse,
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
     ops::Bound::{Included, Unbounded},
 };
 use web3::{
     signing::keccak256,
-    types::{H256, U256},
+    types::{Address, H256, U256},
 };
 
-use crate::{Fill, Order, Side};
+use crate::{Fill, FillKind, Order, Side, TimeInForce};
 
 fn decimal_to_u256(decimal: Decimal) -> U256 {
     // prob there is a more efficient way than this
@@ -28,8 +28,11 @@ fn decimal_to_u256(decimal: Decimal) -> U256 {
 
 lazy_static! {
     static ref ORDER_HASH: [u8; 32] = keccak256(
-        "Order(uint256 amount,uint256 nonce,uint256 price,uint8 side,address traderAddress)"
-            .as_bytes()
+        concat!(
+            "Order(uint256 amount,uint256 nonce,uint256 price,uint8 side,address traderAddress,",
+            "bool reduceOnly,bool closePosition,uint8 timeInForce)"
+        )
+        .as_bytes()
     );
 }
 
@@ -51,26 +54,79 @@ impl EncodeDataable for Order {
             }
             .encode_data(),
             self.trader_address.encode_data(),
+            self.reduce_only.encode_data(),
+            self.close_position.encode_data(),
+            match self.time_in_force {
+                TimeInForce::Gtc => 0u8,
+                TimeInForce::Ioc => 1u8,
+            }
+            .encode_data(),
         ]
         .concat()
     }
 }
 
-#[derive(Copy, Clone, Serialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
 pub struct L2Order {
-    amount: Decimal,
-    price: Decimal,
+    pub amount: Decimal,
+    pub price: Decimal,
 }
 
 #[derive(Clone, Serialize)]
 pub struct L2OrderBook {
-    asks: Vec<L2Order>,
-    bids: Vec<L2Order>,
+    pub asks: Vec<L2Order>,
+    pub bids: Vec<L2Order>,
+    // OrderBook::checksum, over the top CHECKSUM_DEPTH levels of the
+    // ungrouped book whatever the depth and grouping asked for
+    pub checksum: u32,
+}
+
+pub const DEFAULT_BOOK_DEPTH: usize = 50;
+pub const MAX_BOOK_DEPTH: usize = 500;
+// levels per side covered by the book checksum
+pub const CHECKSUM_DEPTH: usize = 25;
+
+// (price, aggregated amount) per level
+pub type Levels = Vec<(Decimal, Decimal)>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct L3Order {
+    // a feed id in its place once anonymised
+    pub hash: H256,
+    pub side: Side,
+    pub price: Decimal,
+    pub amount: Decimal,
+    // orders ahead of this one at its price
+    pub position: usize,
+    // None once anonymised
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trader_address: Option<Address>,
+}
+
+// a change to one resting order. fills and reduce-only clamping only ever
+// shrink an order in place, so a modify keeps the order's queue position
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "camelCase")]
+pub enum L3Event {
+    Add(L3Order),
+    Modify { hash: H256, amount: Decimal },
+    Delete { hash: H256 },
+}
+
+// levels and orders changed since they were last taken
+#[derive(Clone, Default)]
+pub struct BookChanges {
+    asks: BTreeSet<Decimal>,
+    bids: BTreeSet<Reverse<Decimal>>,
+    orders: HashMap<H256, bool>,
 }
 
 pub struct OrderBook {
-    asks: BTreeMap<(Decimal, u128), Order>,
-    bids: BTreeMap<(Reverse<Decimal>, u128), Order>,
+    // resting orders keep the hash they were submitted under, their amount
+    // shrinks as they fill so it cannot be recomputed
+    asks: BTreeMap<(Decimal, u128), (H256, Order)>,
+    bids: BTreeMap<(Reverse<Decimal>, u128), (H256, Order)>,
     // could have used Rc<RefCell<Order>> here, but seems unnecessary since Order is Copy
     // may be wrong
     hash_to_order: HashMap<H256, Order>,
@@ -80,27 +136,47 @@ pub struct OrderBook {
     // iter().take(n) is very slow, and this is a small tradeoff of space
     agg_ask_amt: BTreeMap<Decimal, Decimal>,
     agg_bid_amt: BTreeMap<Reverse<Decimal>, Decimal>,
+
+    // levels whose aggregate amount changed since they were last taken
+    changed_asks: BTreeSet<Decimal>,
+    changed_bids: BTreeSet<Reverse<Decimal>>,
+    // orders touched since they were last taken, true for ones that entered
+    // the book in that time
+    changed_orders: HashMap<H256, bool>,
+    // resting orders of each trader
+    trader_orders: HashMap<Address, BTreeSet<H256>>,
 }
 
 impl OrderBook {
-    pub fn new() -> Self {
+    // each market signs under its own domain name so that the same order
+    // parameters hash differently across markets
+    pub fn new(domain_name: &'static str) -> Self {
         Self {
             asks: BTreeMap::new(),
             bids: BTreeMap::new(),
             hash_to_order: HashMap::new(),
             eip712: Eip712::new(Eip712Domain {
-                name: "DDX take-home",
+                name: domain_name,
                 version: "0.1.0",
             }),
             agg_ask_amt: BTreeMap::new(),
             agg_bid_amt: BTreeMap::new(),
+            changed_asks: BTreeSet::new(),
+            changed_bids: BTreeSet::new(),
+            changed_orders: HashMap::new(),
+            trader_orders: HashMap::new(),
         }
     }
 
     // TODO: make more modular, code for Bid and Ask are similar
     // TODO: find better return than tuple of order hash (if it goes into book) and vector of fills
-    pub fn add_bid(&mut self, mut bid: Order) -> Result<(Option<H256>, Vec<Fill>)> {
-        let taker_hash = self.eip712.encode(bid);
+    // `taker_hash` is the hash the order was submitted under, it keeps it even
+    // when the engine resized the order before placing it
+    pub fn add_bid(
+        &mut self,
+        taker_hash: H256,
+        mut bid: Order,
+    ) -> Result<(Option<H256>, Vec<Fill>)> {
         if let Some(existing_bid) = self.hash_to_order.get(&taker_hash) {
             if existing_bid.trader_address == bid.trader_address {
                 return Err(Error::DuplicateOrder(taker_hash, bid.trader_address));
@@ -110,7 +186,7 @@ impl OrderBook {
         // get possible fills
         let mut self_match = false;
         let mut fills = vec![];
-        for (_, ask) in self
+        for (_, (maker_hash, ask)) in self
             .asks
             .range((Unbounded, Included((bid.price, bid.timestamp))))
         {
@@ -119,14 +195,14 @@ impl OrderBook {
                 break;
             }
 
-            // TODO: save so don't have to recompute
-            let maker_hash = self.eip712.encode(*ask);
+            let maker_hash = *maker_hash;
             let fill_amount = bid.amount.min(ask.amount);
             let fill = Fill {
                 maker_hash,
                 taker_hash,
                 fill_amount,
                 price: ask.price,
+                kind: FillKind::Trade,
             };
             fills.push(fill);
             bid.amount -= fill_amount;
@@ -138,10 +214,13 @@ impl OrderBook {
         // update book to reflect fills
         for fill in &fills {
             let ask = self.hash_to_order[&fill.maker_hash];
+            self.changed_asks.insert(ask.price);
+            self.changed_orders.entry(fill.maker_hash).or_insert(false);
             if ask.amount == fill.fill_amount {
                 // fill completely uses up ask, remove
                 self.asks.remove(&(ask.price, ask.timestamp));
                 self.hash_to_order.remove(&fill.maker_hash);
+                self.unindex_order(fill.maker_hash, ask.trader_address);
                 *self.agg_ask_amt.get_mut(&ask.price).unwrap() -= ask.amount;
                 if self.agg_ask_amt[&ask.price] == Decimal::ZERO {
                     self.agg_ask_amt.remove(&ask.price);
@@ -150,16 +229,22 @@ impl OrderBook {
                 self.asks
                     .get_mut(&(ask.price, ask.timestamp))
                     .unwrap()
+                    .1
                     .amount -= fill.fill_amount;
+                self.hash_to_order.get_mut(&fill.maker_hash).unwrap().amount -= fill.fill_amount;
                 *self.agg_ask_amt.get_mut(&ask.price).unwrap() -= fill.fill_amount;
             }
         }
 
         let mut opt = Some(taker_hash);
-        if !self_match && bid.amount > Decimal::ZERO {
+        if !self_match && bid.amount > Decimal::ZERO && bid.time_in_force == TimeInForce::Gtc {
             // add remaining bid to book
-            self.bids.insert((Reverse(bid.price), bid.timestamp), bid);
+            self.bids
+                .insert((Reverse(bid.price), bid.timestamp), (taker_hash, bid));
             self.hash_to_order.insert(taker_hash, bid);
+            self.index_order(taker_hash, bid.trader_address);
+            self.changed_orders.insert(taker_hash, true);
+            self.changed_bids.insert(Reverse(bid.price));
             *self
                 .agg_bid_amt
                 .entry(Reverse(bid.price))
@@ -171,8 +256,11 @@ impl OrderBook {
         Ok((opt, fills))
     }
 
-    pub fn add_ask(&mut self, mut ask: Order) -> Result<(Option<H256>, Vec<Fill>)> {
-        let taker_hash = self.eip712.encode(ask);
+    pub fn add_ask(
+        &mut self,
+        taker_hash: H256,
+        mut ask: Order,
+    ) -> Result<(Option<H256>, Vec<Fill>)> {
         if let Some(existing_ask) = self.hash_to_order.get(&taker_hash) {
             if existing_ask.trader_address == ask.trader_address {
                 return Err(Error::DuplicateOrder(taker_hash, ask.trader_address));
@@ -182,7 +270,7 @@ impl OrderBook {
         // get possible fills
         let mut self_match = false;
         let mut fills = vec![];
-        for (_, bid) in self
+        for (_, (maker_hash, bid)) in self
             .bids
             .range((Unbounded, Included((Reverse(ask.price), ask.timestamp))))
         {
@@ -191,14 +279,14 @@ impl OrderBook {
                 break;
             }
 
-            // TODO: save so don't have to recompute
-            let maker_hash = self.eip712.encode(*bid);
+            let maker_hash = *maker_hash;
             let fill_amount = ask.amount.min(bid.amount);
             let fill = Fill {
                 maker_hash,
                 taker_hash,
                 fill_amount,
                 price: bid.price,
+                kind: FillKind::Trade,
             };
             fills.push(fill);
             ask.amount -= fill_amount;
@@ -210,10 +298,13 @@ impl OrderBook {
         // update book to reflect fills
         for fill in &fills {
             let bid = self.hash_to_order[&fill.maker_hash];
+            self.changed_bids.insert(Reverse(bid.price));
+            self.changed_orders.entry(fill.maker_hash).or_insert(false);
             if bid.amount == fill.fill_amount {
                 // fill completely uses up bid, remove
                 self.bids.remove(&(Reverse(bid.price), bid.timestamp));
                 self.hash_to_order.remove(&fill.maker_hash);
+                self.unindex_order(fill.maker_hash, bid.trader_address);
                 *self.agg_bid_amt.get_mut(&Reverse(bid.price)).unwrap() -= bid.amount;
                 if self.agg_bid_amt[&Reverse(bid.price)] == Decimal::ZERO {
                     self.agg_bid_amt.remove(&Reverse(bid.price));
@@ -222,16 +313,22 @@ impl OrderBook {
                 self.bids
                     .get_mut(&(Reverse(bid.price), bid.timestamp))
                     .unwrap()
+                    .1
                     .amount -= fill.fill_amount;
+                self.hash_to_order.get_mut(&fill.maker_hash).unwrap().amount -= fill.fill_amount;
                 *self.agg_bid_amt.get_mut(&Reverse(bid.price)).unwrap() -= fill.fill_amount;
             }
         }
 
         let mut opt = Some(taker_hash);
-        if !self_match && ask.amount > Decimal::ZERO {
+        if !self_match && ask.amount > Decimal::ZERO && ask.time_in_force == TimeInForce::Gtc {
             // add remaining ask to book
-            self.asks.insert((ask.price, ask.timestamp), ask);
+            self.asks
+                .insert((ask.price, ask.timestamp), (taker_hash, ask));
             self.hash_to_order.insert(taker_hash, ask);
+            self.index_order(taker_hash, ask.trader_address);
+            self.changed_orders.insert(taker_hash, true);
+            self.changed_asks.insert(ask.price);
             *self.agg_ask_amt.entry(ask.price).or_insert(Decimal::ZERO) += ask.amount;
         } else {
             opt.take();
@@ -240,6 +337,11 @@ impl OrderBook {
         Ok((opt, fills))
     }
 
+    // the hash an order is submitted and rests under in this book
+    pub fn order_hash(&self, order: &Order) -> H256 {
+        self.eip712.encode(*order)
+    }
+
     pub fn get_order(&self, order_hash: H256) -> Result<Order> {
         if let Some(order) = self.hash_to_order.get(&order_hash) {
             return Ok(*order);
@@ -252,6 +354,7 @@ impl OrderBook {
             match order.side {
                 Side::Bid => {
                     self.bids.remove(&(Reverse(order.price), order.timestamp));
+                    self.changed_bids.insert(Reverse(order.price));
                     let bid = self.agg_bid_amt.get_mut(&Reverse(order.price)).unwrap();
                     *bid -= order.amount;
                     if *bid == Decimal::ZERO {
@@ -260,6 +363,7 @@ impl OrderBook {
                 }
                 Side::Ask => {
                     self.asks.remove(&(order.price, order.timestamp));
+                    self.changed_asks.insert(order.price);
                     let ask = self.agg_ask_amt.get_mut(&order.price).unwrap();
                     *ask -= order.amount;
                     if *ask == Decimal::ZERO {
@@ -267,31 +371,417 @@ impl OrderBook {
                     }
                 }
             }
+            let trader_address = order.trader_address;
             self.hash_to_order.remove(&order_hash);
+            self.unindex_order(order_hash, trader_address);
+            self.changed_orders.entry(order_hash).or_insert(false);
             return Ok(());
         }
         Err(Error::OrderNotFound(order_hash))
     }
 
-    pub fn l2_snapshot(&self) -> L2OrderBook {
-        let asks = self
-            .agg_ask_amt
-            .iter()
-            .take(50)
-            .map(|(price, amount)| L2Order {
-                amount: *amount,
-                price: *price,
+    // shrinks a resting order in place, keeping its time priority
+    pub fn reduce_order(&mut self, order_hash: H256, amount: Decimal) -> Result<()> {
+        let order = self
+            .hash_to_order
+            .get_mut(&order_hash)
+            .ok_or(Error::OrderNotFound(order_hash))?;
+        let reduction = order.amount - amount;
+        order.amount = amount;
+        self.changed_orders.entry(order_hash).or_insert(false);
+        match order.side {
+            Side::Bid => {
+                self.bids
+                    .get_mut(&(Reverse(order.price), order.timestamp))
+                    .unwrap()
+                    .1
+                    .amount = amount;
+                *self.agg_bid_amt.get_mut(&Reverse(order.price)).unwrap() -= reduction;
+                self.changed_bids.insert(Reverse(order.price));
+            }
+            Side::Ask => {
+                self.asks
+                    .get_mut(&(order.price, order.timestamp))
+                    .unwrap()
+                    .1
+                    .amount = amount;
+                *self.agg_ask_amt.get_mut(&order.price).unwrap() -= reduction;
+                self.changed_asks.insert(order.price);
+            }
+        }
+        Ok(())
+    }
+
+    // resting orders an incoming order would reach, in the order it would
+    // reach them, as far as its amount goes
+    pub fn crossing_orders(&self, order: &Order) -> Vec<(H256, Order)> {
+        let resting: Vec<&(H256, Order)> = match order.side {
+            Side::Bid => self
+                .asks
+                .range((Unbounded, Included((order.price, order.timestamp))))
+                .map(|(_, resting)| resting)
+                .collect(),
+            Side::Ask => self
+                .bids
+                .range((Unbounded, Included((Reverse(order.price), order.timestamp))))
+                .map(|(_, resting)| resting)
+                .collect(),
+        };
+        let mut left = order.amount;
+        resting
+            .into_iter()
+            .take_while(|(_, resting)| {
+                let reached = left > Decimal::ZERO;
+                left -= resting.amount;
+                reached
+            })
+            .copied()
+            .collect()
+    }
+
+    // puts an order back as it was, in its old place in the queue and without
+    // matching, for undoing a batch. the changes it leaves are put back with
+    // restore_changes once the batch is undone
+    pub fn reinstate(&mut self, hash: H256, order: Order) {
+        let _ = self.delete_order(hash);
+        self.hash_to_order.insert(hash, order);
+        self.index_order(hash, order.trader_address);
+        self.changed_orders.entry(hash).or_insert(false);
+        match order.side {
+            Side::Bid => {
+                self.bids
+                    .insert((Reverse(order.price), order.timestamp), (hash, order));
+                self.changed_bids.insert(Reverse(order.price));
+                *self
+                    .agg_bid_amt
+                    .entry(Reverse(order.price))
+                    .or_insert(Decimal::ZERO) += order.amount;
+            }
+            Side::Ask => {
+                self.asks
+                    .insert((order.price, order.timestamp), (hash, order));
+                self.changed_asks.insert(order.price);
+                *self.agg_ask_amt.entry(order.price).or_insert(Decimal::ZERO) += order.amount;
+            }
+        }
+    }
+
+    // hashes of the trader's resting orders
+    pub fn trader_orders(&self, trader_address: Address) -> impl Iterator<Item = H256> + '_ {
+        self.trader_orders
+            .get(&trader_address)
+            .into_iter()
+            .flatten()
+            .copied()
+    }
+
+    fn index_order(&mut self, hash: H256, trader_address: Address) {
+        self.trader_orders
+            .entry(trader_address)
+            .or_default()
+            .insert(hash);
+    }
+
+    fn unindex_order(&mut self, hash: H256, trader_address: Address) {
+        if let Some(hashes) = self.trader_orders.get_mut(&trader_address) {
+            hashes.remove(&hash);
+            if hashes.is_empty() {
+                self.trader_orders.remove(&trader_address);
+            }
+        }
+    }
+
+    // resting orders and their hashes in priority order, bids first
+    pub fn orders(&self) -> impl Iterator<Item = &(H256, Order)> {
+        self.bids.values().chain(self.asks.values())
+    }
+
+    // every resting order with its place in the queue, best first
+    pub fn l3_orders(&self) -> (Vec<L3Order>, Vec<L3Order>) {
+        fn with_positions<'a>(orders: impl Iterator<Item = &'a (H256, Order)>) -> Vec<L3Order> {
+            let mut level = None;
+            let mut position = 0;
+            orders
+                .map(|(hash, order)| {
+                    if level != Some(order.price) {
+                        level = Some(order.price);
+                        position = 0;
+                    }
+                    position += 1;
+                    L3Order {
+                        hash: *hash,
+                        side: order.side,
+                        price: order.price,
+                        amount: order.amount,
+                        position: position - 1,
+                        trader_address: Some(order.trader_address),
+                    }
+                })
+                .collect()
+        }
+        (
+            with_positions(self.bids.values()),
+            with_positions(self.asks.values()),
+        )
+    }
+
+    fn queue_position(&self, order: &Order) -> usize {
+        match order.side {
+            Side::Bid => self
+                .bids
+                .range((Reverse(order.price), 0)..(Reverse(order.price), order.timestamp))
+                .count(),
+            Side::Ask => self
+                .asks
+                .range((order.price, 0)..(order.price, order.timestamp))
+                .count(),
+        }
+    }
+
+    // what the next take_order_changes and take_level_changes would report,
+    // saved by a batch so that undoing it leaves nothing extra to publish
+    pub fn pending_changes(&self) -> BookChanges {
+        BookChanges {
+            asks: self.changed_asks.clone(),
+            bids: self.changed_bids.clone(),
+            orders: self.changed_orders.clone(),
+        }
+    }
+
+    pub fn restore_changes(&mut self, changes: BookChanges) {
+        self.changed_asks = changes.asks;
+        self.changed_bids = changes.bids;
+        self.changed_orders = changes.orders;
+    }
+
+    // orders added, modified or deleted since the last take_order_changes
+    pub fn changed_order_hashes(&self) -> impl Iterator<Item = H256> + '_ {
+        self.changed_orders.keys().copied()
+    }
+
+    // what happened to individual orders since the last call, deletes and
+    // modifies first so that adds land at their position in the final book
+    pub fn take_order_changes(&mut self) -> Vec<L3Event> {
+        let mut changes: Vec<(H256, bool)> = std::mem::take(&mut self.changed_orders)
+            .into_iter()
+            .collect();
+        changes.sort();
+        let mut events = vec![];
+        let mut adds = vec![];
+        for (hash, added) in changes {
+            match (self.hash_to_order.get(&hash), added) {
+                (Some(order), true) => adds.push(L3Order {
+                    hash,
+                    side: order.side,
+                    price: order.price,
+                    amount: order.amount,
+                    position: self.queue_position(order),
+                    trader_address: Some(order.trader_address),
+                }),
+                (Some(order), false) => events.push(L3Event::Modify {
+                    hash,
+                    amount: order.amount,
+                }),
+                (None, false) => events.push(L3Event::Delete { hash }),
+                // came and went in between
+                (None, true) => {}
+            }
+        }
+        adds.sort_by_key(|order| order.position);
+        events.extend(adds.into_iter().map(L3Event::Add));
+        events
+    }
+
+    // aggregated amount per price level, best first
+    pub fn levels(&self) -> (Levels, Levels) {
+        (
+            self.agg_bid_amt
+                .iter()
+                .map(|(price, amount)| (price.0, *amount))
+                .collect(),
+            self.agg_ask_amt
+                .iter()
+                .map(|(price, amount)| (*price, *amount))
+                .collect(),
+        )
+    }
+
+    // (price, new aggregate amount) of every level that changed since the
+    // last call, best first, zero for levels that emptied
+    pub fn take_level_changes(&mut self) -> (Levels, Levels) {
+        let bids = std::mem::take(&mut self.changed_bids)
+            .into_iter()
+            .map(|price| {
+                let amount = self.agg_bid_amt.get(&price).copied().unwrap_or_default();
+                (price.0, amount)
             })
             .collect();
+        let asks = std::mem::take(&mut self.changed_asks)
+            .into_iter()
+            .map(|price| {
+                let amount = self.agg_ask_amt.get(&price).copied().unwrap_or_default();
+                (price, amount)
+            })
+            .collect();
+        (bids, asks)
+    }
+
+    // rebuilds an empty book from saved orders and levels without matching,
+    // the levels must be exactly what the orders add up to
+    pub fn restore(
+        &mut self,
+        orders: Vec<(H256, Order)>,
+        bid_levels: Levels,
+        ask_levels: Levels,
+    ) -> Result<()> {
+        let mut agg_bid_amt = BTreeMap::new();
+        let mut agg_ask_amt = BTreeMap::new();
+        for (hash, order) in orders {
+            self.hash_to_order.insert(hash, order);
+            self.index_order(hash, order.trader_address);
+            match order.side {
+                Side::Bid => {
+                    self.bids
+                        .insert((Reverse(order.price), order.timestamp), (hash, order));
+                    *agg_bid_amt.entry(Reverse(order.price)).or_default() += order.amount;
+                }
+                Side::Ask => {
+                    self.asks
+                        .insert((order.price, order.timestamp), (hash, order));
+                    *agg_ask_amt.entry(order.price).or_default() += order.amount;
+                }
+            }
+        }
+        self.agg_bid_amt = bid_levels
+            .into_iter()
+            .map(|(price, amount)| (Reverse(price), amount))
+            .collect();
+        self.agg_ask_amt = ask_levels.into_iter().collect();
+
+        let bid_mismatch = self
+            .agg_bid_amt
+            .iter()
+            .chain(agg_bid_amt.iter())
+            .find(|(price, _)| self.agg_bid_amt.get(price) != agg_bid_amt.get(price))
+            .map(|(price, _)| price.0);
+        let ask_mismatch = self
+            .agg_ask_amt
+            .iter()
+            .chain(agg_ask_amt.iter())
+            .find(|(price, _)| self.agg_ask_amt.get(price) != agg_ask_amt.get(price))
+            .map(|(price, _)| *price);
+        match bid_mismatch.or(ask_mismatch) {
+            Some(price) => Err(Error::InconsistentLevel(price)),
+            None => Ok(()),
+        }
+    }
+
+    // crc32 (ieee) of the best CHECKSUM_DEPTH bid levels, best first,
+    // followed by the best CHECKSUM_DEPTH ask levels, best first, each written
+    // as `price:amount` and joined with `:`. decimals are written without
+    // trailing zeros, so 2.50 is `2.5` and 100.0 is `100`. e.g. a book
+    // with bids 2@99.5 and 1@99 and an ask 3@101 is `99.5:2:99:1:101:3`, and
+    // an empty book is the empty string, 0
+    pub fn checksum(&self) -> u32 {
         let bids = self
             .agg_bid_amt
             .iter()
-            .take(50)
-            .map(|(price, amount)| L2Order {
-                amount: *amount,
-                price: price.0,
-            })
+            .map(|(price, amount)| (price.0, *amount))
+            .take(CHECKSUM_DEPTH);
+        let asks = self
+            .agg_ask_amt
+            .iter()
+            .map(|(price, amount)| (*price, *amount))
+            .take(CHECKSUM_DEPTH);
+        let levels: Vec<String> = bids
+            .chain(asks)
+            .map(|(price, amount)| format!("{}:{}", price.normalize(), amount.normalize()))
             .collect();
-        L2OrderBook { asks, bids }
+        crc32fast::hash(levels.join(":").as_bytes())
+    }
+
+    pub fn best_bid(&self) -> Option<Decimal> {
+        self.agg_bid_amt.keys().next().map(|price| price.0)
+    }
+
+    pub fn best_ask(&self) -> Option<Decimal> {
+        self.agg_ask_amt.keys().next().copied()
+    }
+
+    pub fn mid_price(&self) -> Option<Decimal> {
+        Some((self.best_bid()? + self.best_ask()?) / dec!(2))
+    }
+
+    // average price to trade `notional` usd against the given side of the book,
+    // None if the side is not deep enough
+    pub fn impact_price(&self, side: Side, notional: Decimal) -> Option<Decimal> {
+        let levels: Box<dyn Iterator<Item = (Decimal, Decimal)>> = match side {
+            Side::Bid => Box::new(
+                self.agg_bid_amt
+                    .iter()
+                    .map(|(price, amount)| (price.0, *amount)),
+            ),
+            Side::Ask => Box::new(
+                self.agg_ask_amt
+                    .iter()
+                    .map(|(price, amount)| (*price, *amount)),
+            ),
+        };
+        let mut remaining = notional;
+        let mut filled = Decimal::ZERO;
+        for (price, amount) in levels {
+            let take = (remaining / price).min(amount);
+            filled += take;
+            remaining -= take * price;
+            if remaining <= Decimal::ZERO {
+                return Some(notional / filled);
+            }
+        }
+        None
+    }
+
+    // best `depth` levels per side. with a grouping, prices are bucketed to
+    // multiples of it, bids rounding down and asks up, so that a bucket never
+    // shows a better price than the orders in it
+    pub fn l2_snapshot(&self, depth: usize, grouping: Option<Decimal>) -> L2OrderBook {
+        fn group(
+            levels: impl Iterator<Item = (Decimal, Decimal)>,
+            bucket: impl Fn(Decimal) -> Decimal,
+            depth: usize,
+        ) -> Vec<L2Order> {
+            let mut grouped: Vec<L2Order> = vec![];
+            for (price, amount) in levels {
+                let price = bucket(price);
+                if let Some(last) = grouped.last_mut().filter(|last| last.price == price) {
+                    last.amount += amount;
+                } else if grouped.len() == depth {
+                    break;
+                } else {
+                    grouped.push(L2Order { amount, price });
+                }
+            }
+            grouped
+        }
+        let bucket = |price: Decimal, round: fn(&Decimal) -> Decimal| match grouping {
+            Some(grouping) => round(&(price / grouping)) * grouping,
+            None => price,
+        };
+        L2OrderBook {
+            asks: group(
+                self.agg_ask_amt
+                    .iter()
+                    .map(|(price, amount)| (*price, *amount)),
+                |price| bucket(price, Decimal::ceil),
+                depth,
+            ),
+            bids: group(
+                self.agg_bid_amt
+                    .iter()
+                    .map(|(price, amount)| (price.0, *amount)),
+                |price| bucket(price, Decimal::floor),
+                depth,
+            ),
+            checksum: self.checksum(),
+        }
     }
 }
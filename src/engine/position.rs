@@ -0,0 +1,120 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::Symbol;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MarginMode {
+    // backed by the account's whole usd balance, shared with other cross positions
+    #[default]
+    Cross,
+    // backed only by the collateral moved into the position
+    Isolated,
+}
+
+#[derive(Debug, Default, Copy, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Position {
+    // positive is long, negative is short
+    pub size: Decimal,
+    pub entry_price: Decimal,
+    pub realized_pnl: Decimal,
+    // kept while flat, it is the trader's choice for the market
+    pub margin_mode: MarginMode,
+    pub isolated_margin: Decimal,
+}
+
+impl Position {
+    // applies a signed fill to the position and returns the pnl realised by the
+    // part of the fill that closed existing exposure
+    pub fn apply_fill(&mut self, amount: Decimal, price: Decimal) -> Decimal {
+        if self.size.is_zero() || self.size.is_sign_positive() == amount.is_sign_positive() {
+            // opening or increasing, average into the entry price
+            let new_size = self.size + amount;
+            self.entry_price = (self.size * self.entry_price + amount * price) / new_size;
+            self.size = new_size;
+            return Decimal::ZERO;
+        }
+
+        // reducing, closing or flipping
+        let closed = amount.abs().min(self.size.abs());
+        let pnl = if self.size.is_sign_positive() {
+            closed * (price - self.entry_price)
+        } else {
+            closed * (self.entry_price - price)
+        };
+        self.realized_pnl += pnl;
+        self.size += amount;
+        if self.size.is_zero() {
+            self.entry_price = Decimal::ZERO;
+        } else if self.size.is_sign_positive() == amount.is_sign_positive() {
+            // flipped, the remainder was opened at the fill price
+            self.entry_price = price;
+        }
+        pnl
+    }
+
+    pub fn unrealized_pnl(&self, mark_price: Decimal) -> Decimal {
+        self.size * (mark_price - self.entry_price)
+    }
+
+    pub fn notional(&self, mark_price: Decimal) -> Decimal {
+        self.size.abs() * mark_price
+    }
+
+    // collateral backing an isolated position, meaningless for cross
+    pub fn isolated_equity(&self, mark_price: Decimal) -> Decimal {
+        self.isolated_margin + self.unrealized_pnl(mark_price)
+    }
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PositionView {
+    pub symbol: Symbol,
+    pub size: Decimal,
+    pub entry_price: Decimal,
+    pub mark_price: Decimal,
+    pub realized_pnl: Decimal,
+    pub unrealized_pnl: Decimal,
+    pub initial_margin: Decimal,
+    pub maintenance_margin: Decimal,
+    pub margin_mode: MarginMode,
+    pub isolated_margin: Decimal,
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_open_increase_and_close() {
+        let mut position = Position::default();
+        assert_eq!(position.apply_fill(dec!(2), dec!(10)), dec!(0));
+        assert_eq!(position.apply_fill(dec!(2), dec!(20)), dec!(0));
+        assert_eq!(position.size, dec!(4));
+        assert_eq!(position.entry_price, dec!(15));
+        assert_eq!(position.unrealized_pnl(dec!(16)), dec!(4));
+
+        assert_eq!(position.apply_fill(dec!(-1), dec!(17)), dec!(2));
+        assert_eq!(position.size, dec!(3));
+        assert_eq!(position.entry_price, dec!(15));
+        assert_eq!(position.apply_fill(dec!(-3), dec!(14)), dec!(-3));
+        assert_eq!(position.size, dec!(0));
+        assert_eq!(position.entry_price, dec!(0));
+        assert_eq!(position.realized_pnl, dec!(-1));
+    }
+
+    #[test]
+    fn test_flip() {
+        let mut position = Position::default();
+        position.apply_fill(dec!(-2), dec!(10));
+        assert_eq!(position.apply_fill(dec!(5), dec!(8)), dec!(4));
+        assert_eq!(position.size, dec!(3));
+        assert_eq!(position.entry_price, dec!(8));
+        assert_eq!(position.unrealized_pnl(dec!(7)), dec!(-3));
+    }
+}
@@ -0,0 +1,111 @@
+use displaydoc::Display;
+use rust_decimal::Decimal;
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound::{Included, Unbounded},
+    path::Path,
+    str::FromStr,
+};
+use thiserror::Error;
+
+use crate::Symbol;
+
+#[derive(Debug, Display, Error)]
+pub enum PriceFeedError {
+    /// could not read price records: {0}
+    Io(#[from] std::io::Error),
+
+    /// line {0}: {1}
+    InvalidRecord(usize, String),
+}
+
+// source of external index prices, polled by the engine on each tick
+pub trait PriceFeed: Send {
+    // latest index price published at or before `now`
+    fn index_price(&mut self, symbol: Symbol, now: u128) -> Option<Decimal>;
+}
+
+// replays a fixed series of prices against the engine clock, for tests and
+// for reproducing incidents from recorded prices
+#[derive(Default)]
+pub struct ReplayPriceFeed {
+    prices: HashMap<Symbol, BTreeMap<u128, Decimal>>,
+}
+
+impl ReplayPriceFeed {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, symbol: Symbol, timestamp: u128, price: Decimal) {
+        self.prices
+            .entry(symbol)
+            .or_default()
+            .insert(timestamp, price);
+    }
+
+    // one `timestamp,symbol,price` record per line, blank lines and lines
+    // starting with '#' are skipped
+    pub fn parse(records: &str) -> Result<Self, PriceFeedError> {
+        let mut feed = Self::new();
+        for (i, line) in records.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: String| PriceFeedError::InvalidRecord(i + 1, reason);
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let [timestamp, symbol, price] = fields[..] else {
+                return Err(invalid("expected timestamp,symbol,price".into()));
+            };
+            let timestamp = timestamp.parse().map_err(|e| invalid(format!("{}", e)))?;
+            let symbol = Symbol::ALL
+                .into_iter()
+                .find(|s| s.as_str() == symbol)
+                .ok_or_else(|| invalid(format!("unknown symbol {}", symbol)))?;
+            let price = Decimal::from_str(price).map_err(|e| invalid(format!("{}", e)))?;
+            feed.push(symbol, timestamp, price);
+        }
+        Ok(feed)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, PriceFeedError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+}
+
+impl PriceFeed for ReplayPriceFeed {
+    fn index_price(&mut self, symbol: Symbol, now: u128) -> Option<Decimal> {
+        self.prices
+            .get(&symbol)?
+            .range((Unbounded, Included(now)))
+            .next_back()
+            .map(|(_, price)| *price)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[test]
+    fn test_replay_feed() {
+        let mut feed = ReplayPriceFeed::parse(
+            "# timestamp,symbol,price
+            10,DDXPERP,100
+            20,DDXPERP,101.5
+
+            20,DDXUSD,99",
+        )
+        .unwrap();
+        assert_eq!(feed.index_price(Symbol::DdxPerp, 9), None);
+        assert_eq!(feed.index_price(Symbol::DdxPerp, 10), Some(dec!(100)));
+        assert_eq!(feed.index_price(Symbol::DdxPerp, 19), Some(dec!(100)));
+        assert_eq!(feed.index_price(Symbol::DdxPerp, 50), Some(dec!(101.5)));
+        assert_eq!(feed.index_price(Symbol::DdxUsd, 20), Some(dec!(99)));
+        assert!(ReplayPriceFeed::parse("10,BTCUSD,1").is_err());
+        assert!(ReplayPriceFeed::parse("10,DDXPERP").is_err());
+    }
+}
@@ -0,0 +1,248 @@
+use rust_decimal::Decimal;
+use web3::types::{Address, H256};
+
+use super::{
+    error::{EngineError as Error, Result},
+    order_status::TerminalReason,
+    Engine,
+};
+use crate::{MarketKind, Order, Side, Symbol};
+
+impl Engine {
+    fn position_size(&self, address: Address, symbol: Symbol) -> Decimal {
+        self.positions
+            .get(&address)
+            .and_then(|positions| positions.get(&symbol))
+            .map(|position| position.size)
+            .unwrap_or_default()
+    }
+
+    // side that shrinks the position, None when flat
+    fn closing_side(size: Decimal) -> Option<Side> {
+        if size.is_zero() {
+            None
+        } else if size.is_sign_positive() {
+            Some(Side::Ask)
+        } else {
+            Some(Side::Bid)
+        }
+    }
+
+    // the trader's resting reduce-only orders in the market, oldest first
+    pub(super) fn resting_reduce_only(
+        &self,
+        address: Address,
+        symbol: Symbol,
+    ) -> Vec<(H256, Order)> {
+        let book = &self.markets[&symbol].book;
+        let mut orders: Vec<(H256, Order)> = self
+            .reduce_only_orders
+            .iter()
+            .filter(|hash| self.hash_to_address.get(hash) == Some(&address))
+            .filter_map(|hash| Some((*hash, book.get_order(*hash).ok()?)))
+            .filter(|(_, order)| order.symbol == symbol)
+            .collect();
+        orders.sort_by_key(|(_, order)| order.timestamp);
+        orders
+    }
+
+    // validates reduce-only and close-position orders against the current
+    // position, sizing them to whatever resting reduce-only orders do not
+    // already cover
+    pub(super) fn check_reduce_only(&self, mut order: Order) -> Result<Order> {
+        if !order.reduce_only && !order.close_position {
+            return Ok(order);
+        }
+        if order.symbol.kind() != MarketKind::Perpetual {
+            return Err(Error::NotPerpetual(order.symbol));
+        }
+        let size = self.position_size(order.trader_address, order.symbol);
+        let Some(closing_side) = Self::closing_side(size) else {
+            return Err(Error::NoPosition(order.symbol));
+        };
+        if order.side != closing_side {
+            return Err(Error::WouldIncreasePosition(order.symbol));
+        }
+        let covered: Decimal = self
+            .resting_reduce_only(order.trader_address, order.symbol)
+            .iter()
+            .map(|(_, resting)| resting.amount)
+            .sum();
+        let available = size.abs() - covered;
+        if available <= Decimal::ZERO {
+            return Err(Error::WouldIncreasePosition(order.symbol));
+        }
+        order.reduce_only = true;
+        order.amount = if order.close_position {
+            available
+        } else {
+            order.amount.min(available)
+        };
+        Ok(order)
+    }
+
+    // after the position moves, cancels resting reduce-only orders on the wrong
+    // side and shrinks the newest ones until together they fit the position
+    pub(super) fn enforce_reduce_only(&mut self, address: Address, symbol: Symbol) {
+        let closing_side = Self::closing_side(self.position_size(address, symbol));
+        let mut budget = self.position_size(address, symbol).abs();
+        for (hash, order) in self.resting_reduce_only(address, symbol) {
+            if Some(order.side) != closing_side || budget.is_zero() {
+                let _ = self.cancel_order(hash, TerminalReason::ReduceOnly);
+            } else if order.amount > budget {
+                let book = &mut self.markets.get_mut(&symbol).unwrap().book;
+                if book.reduce_order(hash, budget).is_ok() {
+                    self.report_reduce(hash, &order, budget);
+                }
+                budget = Decimal::ZERO;
+            } else {
+                budget -= order.amount;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::engine::tests::{account, perp_order, trader};
+
+    fn reduce_only(mut order: Order) -> Order {
+        order.reduce_only = true;
+        order
+    }
+
+    // trader 1 long 2 @ 100 against trader 2
+    fn setup() -> Engine {
+        let mut engine = Engine::new();
+        engine.create_account(account(1, dec!(100))).unwrap();
+        engine.create_account(account(2, dec!(1000))).unwrap();
+        engine.create_account(account(3, dec!(1000))).unwrap();
+        engine
+            .create_order(perp_order(2, Side::Ask, dec!(2), dec!(100), 1))
+            .unwrap();
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(2), dec!(100), 2))
+            .unwrap();
+        engine
+    }
+
+    #[test]
+    fn test_rejects_orders_that_would_increase() {
+        let mut engine = setup();
+        assert!(matches!(
+            engine.create_order(reduce_only(perp_order(1, Side::Bid, dec!(1), dec!(90), 3))),
+            Err(Error::WouldIncreasePosition(_))
+        ));
+        assert!(matches!(
+            engine.create_order(reduce_only(perp_order(3, Side::Ask, dec!(1), dec!(90), 4))),
+            Err(Error::NoPosition(_))
+        ));
+    }
+
+    #[test]
+    fn test_clamps_to_position_and_reserves_nothing() {
+        let mut engine = setup();
+        engine
+            .create_order(reduce_only(perp_order(1, Side::Ask, dec!(5), dec!(110), 3)))
+            .unwrap();
+        let book = engine.get_book(Symbol::DdxPerp);
+        assert_eq!(book.asks[0].amount, dec!(2));
+        assert_eq!(
+            engine.get_margin(trader(1)).unwrap().book_outstanding,
+            dec!(0)
+        );
+
+        // already fully covered
+        assert!(engine
+            .create_order(reduce_only(perp_order(1, Side::Ask, dec!(1), dec!(120), 4)))
+            .is_err());
+
+        // filling it can never flip the position
+        engine
+            .create_order(perp_order(3, Side::Bid, dec!(5), dec!(110), 5))
+            .unwrap();
+        assert!(engine.get_positions(trader(1)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_close_position_sizes_to_position() {
+        let mut engine = setup();
+        let mut order = perp_order(1, Side::Ask, dec!(0), dec!(90), 3);
+        order.close_position = true;
+        engine
+            .create_order(perp_order(3, Side::Bid, dec!(5), dec!(95), 4))
+            .unwrap();
+        let fills = engine.create_order(order).unwrap();
+        assert_eq!(fills[0].fill_amount, dec!(2));
+        assert!(engine.get_positions(trader(1)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_resting_orders_follow_the_position() {
+        let mut engine = setup();
+        engine
+            .create_order(reduce_only(perp_order(1, Side::Ask, dec!(1), dec!(110), 3)))
+            .unwrap();
+        engine
+            .create_order(reduce_only(perp_order(1, Side::Ask, dec!(1), dec!(120), 4)))
+            .unwrap();
+
+        // a plain order closes half the position elsewhere, so the newest
+        // reduce-only order has to go
+        engine
+            .create_order(perp_order(3, Side::Bid, dec!(1), dec!(105), 5))
+            .unwrap();
+        engine
+            .create_order(perp_order(1, Side::Ask, dec!(1), dec!(105), 6))
+            .unwrap();
+        let book = engine.get_book(Symbol::DdxPerp);
+        assert_eq!(book.asks.len(), 1);
+        assert_eq!(book.asks[0].price, dec!(110));
+
+        // closing the rest leaves nothing to reduce
+        engine
+            .create_order(perp_order(3, Side::Bid, dec!(1), dec!(100), 7))
+            .unwrap();
+        engine
+            .create_order(perp_order(1, Side::Ask, dec!(1), dec!(100), 8))
+            .unwrap();
+        assert!(engine.get_book(Symbol::DdxPerp).asks.is_empty());
+    }
+
+    #[test]
+    fn test_shrinking_updates_the_order_record() {
+        let mut engine = setup();
+        let order = reduce_only(perp_order(1, Side::Ask, dec!(2), dec!(110), 3));
+        engine.create_order(order).unwrap();
+        let hash = engine.markets[&Symbol::DdxPerp].book.order_hash(&order);
+
+        engine
+            .create_order(perp_order(3, Side::Bid, dec!(1), dec!(105), 4))
+            .unwrap();
+        engine
+            .create_order(perp_order(1, Side::Ask, dec!(1), dec!(105), 5))
+            .unwrap();
+        let record = engine.get_order_status(hash).unwrap();
+        assert_eq!(engine.get_order(hash).unwrap().amount, dec!(1));
+        assert_eq!(record.remaining, dec!(1));
+        assert_eq!(record.filled, dec!(0));
+    }
+
+    #[test]
+    fn test_resized_order_keeps_its_hash() {
+        let mut engine = setup();
+        let order = Order {
+            close_position: true,
+            ..perp_order(1, Side::Ask, dec!(5), dec!(110), 3)
+        };
+        engine.create_order(order).unwrap();
+
+        // sized down to the position, but found under the hash it was sent with
+        let hash = engine.markets[&Symbol::DdxPerp].book.order_hash(&order);
+        assert_eq!(engine.get_order(hash).unwrap().amount, dec!(2));
+        assert_eq!(engine.get_order_status(hash).unwrap().remaining, dec!(2));
+    }
+}
@@ -0,0 +1,863 @@
+use displaydoc::Display;
+use rust_decimal::Decimal;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io::Write,
+    path::Path,
+};
+use thiserror::Error;
+use web3::types::{Address, H256, U256};
+
+use super::{
+    account_updates::OrderStatus,
+    candles::{Candle, Interval},
+    checkpoint::{BalanceDelta, Checkpoint},
+    deposit::Asset,
+    funding::FundingSettlement,
+    journal::{Journal, JournalError},
+    ledger::{LedgerEntry, LedgerKind},
+    market::Market,
+    merkle::MerkleProof,
+    order_status::{OrderRecord, TerminalReason},
+    orderbook::{Levels, OrderBookError},
+    trades::Trade,
+    withdrawal::Withdrawal,
+    Clock, Engine, ManualClock, MarginMode, Position,
+};
+use crate::{Account, Nonce, Order, Side, Symbol, TimeInForce};
+
+const MAGIC: &[u8; 8] = b"DDXSNAP\0";
+// bump whenever the layout below changes, older versions are rejected
+const VERSION: u32 = 10;
+
+#[derive(Debug, Display, Error)]
+pub enum SnapshotError {
+    /// could not access the snapshot: {0}
+    Io(#[from] std::io::Error),
+
+    /// not a snapshot file
+    BadMagic,
+
+    /// unsupported snapshot version {0}
+    UnsupportedVersion(u32),
+
+    /// snapshot checksum mismatch
+    Checksum,
+
+    /// snapshot is truncated
+    Truncated,
+
+    /// invalid {0} tag {1} in snapshot
+    InvalidTag(&'static str, u8),
+
+    /// snapshot book does not match its orders: {0}
+    OrderBook(#[from] OrderBookError),
+
+    /// snapshot is at record {0} but the event log ends at {1}
+    AheadOfLog(u64, u64),
+
+    /// could not replay the event log tail: {0}
+    Journal(#[from] JournalError),
+}
+
+type Result<T> = std::result::Result<T, SnapshotError>;
+
+// layout, all integers little endian:
+//   magic, u32 version, u32 crc32 of the body, body
+// the body is the last event log seq the snapshot includes followed by the
+// engine state, collections prefixed by a u32 length and written in a fixed
+// order so that the same state always gives the same bytes
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u128(&mut self, v: u128) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn len(&mut self, len: usize) {
+        self.u32(len as u32);
+    }
+
+    fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    fn decimal(&mut self, v: Decimal) {
+        self.0.extend_from_slice(&v.serialize());
+    }
+
+    fn opt_decimal(&mut self, v: Option<Decimal>) {
+        self.bool(v.is_some());
+        if let Some(v) = v {
+            self.decimal(v);
+        }
+    }
+
+    fn h256(&mut self, v: H256) {
+        self.0.extend_from_slice(v.as_bytes());
+    }
+
+    fn address(&mut self, v: Address) {
+        self.0.extend_from_slice(v.as_bytes());
+    }
+
+    fn u256(&mut self, v: U256) {
+        let mut bytes = [0u8; 32];
+        v.to_big_endian(&mut bytes);
+        self.0.extend_from_slice(&bytes);
+    }
+
+    fn symbol(&mut self, v: Symbol) {
+        self.u8(Symbol::ALL.iter().position(|s| *s == v).unwrap() as u8);
+    }
+
+    fn order(&mut self, order: &Order) {
+        self.decimal(order.amount);
+        self.h256(order.nonce.0);
+        self.decimal(order.price);
+        self.u8(order.side as u8);
+        self.address(order.trader_address);
+        self.symbol(order.symbol);
+        self.u8(order.time_in_force as u8);
+        self.bool(order.reduce_only);
+        self.bool(order.close_position);
+        self.u128(order.timestamp);
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < n {
+            return Err(SnapshotError::Truncated);
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn u128(&mut self) -> Result<u128> {
+        Ok(u128::from_le_bytes(self.take(16)?.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn bool(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(SnapshotError::InvalidTag("bool", tag)),
+        }
+    }
+
+    fn decimal(&mut self) -> Result<Decimal> {
+        Ok(Decimal::deserialize(self.take(16)?.try_into().unwrap()))
+    }
+
+    fn opt_decimal(&mut self) -> Result<Option<Decimal>> {
+        Ok(match self.bool()? {
+            true => Some(self.decimal()?),
+            false => None,
+        })
+    }
+
+    fn h256(&mut self) -> Result<H256> {
+        Ok(H256::from_slice(self.take(32)?))
+    }
+
+    fn address(&mut self) -> Result<Address> {
+        Ok(Address::from_slice(self.take(20)?))
+    }
+
+    fn u256(&mut self) -> Result<U256> {
+        Ok(U256::from_big_endian(self.take(32)?))
+    }
+
+    fn asset(&mut self) -> Result<Asset> {
+        match self.u8()? {
+            0 => Ok(Asset::Ddx),
+            1 => Ok(Asset::Usd),
+            tag => Err(SnapshotError::InvalidTag("asset", tag)),
+        }
+    }
+
+    fn side(&mut self) -> Result<Side> {
+        match self.u8()? {
+            0 => Ok(Side::Bid),
+            1 => Ok(Side::Ask),
+            tag => Err(SnapshotError::InvalidTag("side", tag)),
+        }
+    }
+
+    fn symbol(&mut self) -> Result<Symbol> {
+        let tag = self.u8()?;
+        Symbol::ALL
+            .get(tag as usize)
+            .copied()
+            .ok_or(SnapshotError::InvalidTag("symbol", tag))
+    }
+
+    fn order(&mut self) -> Result<Order> {
+        Ok(Order {
+            amount: self.decimal()?,
+            nonce: Nonce(self.h256()?),
+            price: self.decimal()?,
+            side: self.side()?,
+            trader_address: self.address()?,
+            symbol: self.symbol()?,
+            time_in_force: match self.u8()? {
+                0 => TimeInForce::Gtc,
+                1 => TimeInForce::Ioc,
+                tag => return Err(SnapshotError::InvalidTag("time in force", tag)),
+            },
+            reduce_only: self.bool()?,
+            close_position: self.bool()?,
+            timestamp: self.u128()?,
+        })
+    }
+}
+
+impl Engine {
+    // serializes the complete engine state, market parameters excepted since
+    // they are configuration rather than state
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut body = Encoder::default();
+        body.u64(self.journal.as_ref().map_or(0, Journal::last_seq));
+        body.address(self.insurance_fund);
+
+        let accounts: BTreeMap<_, _> = self.accounts.iter().collect();
+        body.len(accounts.len());
+        for account in accounts.values() {
+            body.address(account.trader_address);
+            body.decimal(account.ddx_balance);
+            body.decimal(account.usd_balance);
+            body.decimal(account.ddx_book_outstanding);
+            body.decimal(account.usd_book_outstanding);
+        }
+
+        let hash_to_address: BTreeMap<_, _> = self.hash_to_address.iter().collect();
+        body.len(hash_to_address.len());
+        for (hash, address) in hash_to_address {
+            body.h256(*hash);
+            body.address(*address);
+        }
+        let hash_to_symbol: BTreeMap<_, _> = self.hash_to_symbol.iter().collect();
+        body.len(hash_to_symbol.len());
+        for (hash, symbol) in hash_to_symbol {
+            body.h256(*hash);
+            body.symbol(*symbol);
+        }
+        body.len(self.reduce_only_orders.len());
+        for hash in &self.reduce_only_orders {
+            body.h256(*hash);
+        }
+
+        body.len(self.markets.len());
+        for (symbol, market) in &self.markets {
+            body.symbol(*symbol);
+            body.opt_decimal(market.last_price);
+            body.u128(market.next_funding_time);
+            body.opt_decimal(market.index_price);
+            body.bool(market.last_funding.is_some());
+            if let Some(funding) = market.last_funding {
+                body.symbol(funding.symbol);
+                body.u128(funding.timestamp);
+                body.decimal(funding.index_price);
+                body.decimal(funding.premium_index);
+                body.decimal(funding.funding_rate);
+            }
+            body.u64(market.trade_seq);
+            body.len(market.trades.len());
+            for trade in &market.trades {
+                body.u64(trade.seq);
+                body.decimal(trade.price);
+                body.decimal(trade.amount);
+                body.u8(trade.side as u8);
+                body.u128(trade.timestamp);
+            }
+            for interval in Interval::ALL {
+                let bars = market.candles.0.get(&interval);
+                body.len(bars.map_or(0, |bars| bars.len()));
+                for bar in bars.into_iter().flatten() {
+                    body.u128(bar.open_time);
+                    body.decimal(bar.open);
+                    body.decimal(bar.high);
+                    body.decimal(bar.low);
+                    body.decimal(bar.close);
+                    body.decimal(bar.volume);
+                    body.decimal(bar.quote_volume);
+                    body.u64(bar.trades);
+                }
+            }
+            let orders: Vec<&(H256, Order)> = market.book.orders().collect();
+            body.len(orders.len());
+            for (hash, order) in orders {
+                body.h256(*hash);
+                body.order(order);
+            }
+            let (bid_levels, ask_levels) = market.book.levels();
+            for levels in [bid_levels, ask_levels] {
+                body.len(levels.len());
+                for (price, amount) in levels {
+                    body.decimal(price);
+                    body.decimal(amount);
+                }
+            }
+        }
+
+        body.len(self.positions.len());
+        for (address, positions) in &self.positions {
+            body.address(*address);
+            body.len(positions.len());
+            for (symbol, position) in positions {
+                body.symbol(*symbol);
+                body.decimal(position.size);
+                body.decimal(position.entry_price);
+                body.decimal(position.realized_pnl);
+                body.u8(position.margin_mode as u8);
+                body.decimal(position.isolated_margin);
+            }
+        }
+
+        body.len(self.ledger.len());
+        for entry in &self.ledger {
+            body.u128(entry.timestamp);
+            body.address(entry.trader_address);
+            body.bool(entry.symbol.is_some());
+            if let Some(symbol) = entry.symbol {
+                body.symbol(symbol);
+            }
+            body.u8(entry.kind as u8);
+            body.decimal(entry.amount);
+        }
+
+        body.len(self.checkpoints.history.len());
+        for checkpoint in &self.checkpoints.history {
+            body.u128(checkpoint.timestamp);
+            body.h256(checkpoint.prev_root);
+            body.h256(checkpoint.state_root);
+            body.len(checkpoint.deltas.len());
+            for delta in &checkpoint.deltas {
+                body.address(delta.trader_address);
+                body.decimal(delta.ddx_delta);
+                body.decimal(delta.usd_delta);
+            }
+            body.len(checkpoint.withdrawals.len());
+            for hash in &checkpoint.withdrawals {
+                body.h256(*hash);
+            }
+        }
+        body.len(self.checkpoints.balances.len());
+        for (address, (ddx, usd)) in &self.checkpoints.balances {
+            body.address(*address);
+            body.decimal(*ddx);
+            body.decimal(*usd);
+        }
+
+        let deposits: BTreeSet<_> = self.deposits.iter().collect();
+        body.len(deposits.len());
+        for (tx_hash, log_index) in deposits {
+            body.h256(*tx_hash);
+            body.u64(*log_index);
+        }
+
+        // hashes are recomputed from the fields on restore
+        body.len(self.withdrawals.len());
+        for (withdrawal, proof) in self.withdrawals.values() {
+            body.address(withdrawal.trader_address);
+            body.u8(withdrawal.asset as u8);
+            body.decimal(withdrawal.amount);
+            body.u64(withdrawal.nonce);
+            body.u128(withdrawal.timestamp);
+            body.bool(withdrawal.checkpoint_id.is_some());
+            if let (Some(checkpoint_id), Some(proof)) = (withdrawal.checkpoint_id, proof) {
+                body.u64(checkpoint_id);
+                body.u256(proof.bitmap);
+                body.len(proof.siblings.len());
+                for sibling in &proof.siblings {
+                    body.h256(*sibling);
+                }
+            }
+        }
+
+        // per trader in the order they came in, each under the hash it was
+        // submitted with, which a resized reduce-only order no longer has
+        let account_orders: BTreeMap<_, _> = self.account_orders.iter().collect();
+        body.len(account_orders.len());
+        for (address, hashes) in account_orders {
+            body.address(*address);
+            body.len(hashes.len());
+            for hash in hashes {
+                let record = &self.order_records[hash];
+                body.h256(*hash);
+                body.order(&record.order);
+                body.decimal(record.filled);
+                body.decimal(record.remaining);
+                body.opt_decimal(record.average_price);
+                body.u8(record.status as u8);
+                body.bool(record.reason.is_some());
+                if let Some(reason) = record.reason {
+                    body.u8(reason as u8);
+                }
+                body.u128(record.updated);
+            }
+        }
+        let retired_orders: BTreeSet<_> = self.retired_orders.iter().collect();
+        body.len(retired_orders.len());
+        for hash in retired_orders {
+            body.h256(*hash);
+        }
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&crc32fast::hash(&body.0).to_le_bytes());
+        bytes.extend_from_slice(&body.0);
+        bytes
+    }
+
+    // returns the engine and the last event log seq it includes, every
+    // book's levels are checked against its orders
+    pub fn from_snapshot(bytes: &[u8], clock: Box<dyn Clock>) -> Result<(Self, u64)> {
+        let mut header = Decoder { bytes };
+        if header.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = header.u32()?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let crc = header.u32()?;
+        if crc32fast::hash(header.bytes) != crc {
+            return Err(SnapshotError::Checksum);
+        }
+
+        let mut body = header;
+        let seq = body.u64()?;
+        let mut engine = Self::with_clock(clock);
+        engine.insurance_fund = body.address()?;
+
+        engine.accounts = HashMap::new();
+        for _ in 0..body.len()? {
+            let account = Account {
+                trader_address: body.address()?,
+                ddx_balance: body.decimal()?,
+                usd_balance: body.decimal()?,
+                ddx_book_outstanding: body.decimal()?,
+                usd_book_outstanding: body.decimal()?,
+            };
+            engine.accounts.insert(account.trader_address, account);
+        }
+
+        for _ in 0..body.len()? {
+            engine.hash_to_address.insert(body.h256()?, body.address()?);
+        }
+        for _ in 0..body.len()? {
+            engine.hash_to_symbol.insert(body.h256()?, body.symbol()?);
+        }
+        engine.reduce_only_orders = BTreeSet::new();
+        for _ in 0..body.len()? {
+            engine.reduce_only_orders.insert(body.h256()?);
+        }
+
+        for _ in 0..body.len()? {
+            let symbol = body.symbol()?;
+            let mut market = Market::new(symbol, 0);
+            market.last_price = body.opt_decimal()?;
+            market.next_funding_time = body.u128()?;
+            market.index_price = body.opt_decimal()?;
+            if body.bool()? {
+                market.last_funding = Some(FundingSettlement {
+                    symbol: body.symbol()?,
+                    timestamp: body.u128()?,
+                    index_price: body.decimal()?,
+                    premium_index: body.decimal()?,
+                    funding_rate: body.decimal()?,
+                });
+            }
+            market.trade_seq = body.u64()?;
+            for _ in 0..body.len()? {
+                market.trades.push_back(Trade {
+                    symbol,
+                    seq: body.u64()?,
+                    price: body.decimal()?,
+                    amount: body.decimal()?,
+                    side: body.side()?,
+                    timestamp: body.u128()?,
+                });
+            }
+            for interval in Interval::ALL {
+                let bars = (0..body.len()?)
+                    .map(|_| {
+                        Ok(Candle {
+                            open_time: body.u128()?,
+                            open: body.decimal()?,
+                            high: body.decimal()?,
+                            low: body.decimal()?,
+                            close: body.decimal()?,
+                            volume: body.decimal()?,
+                            quote_volume: body.decimal()?,
+                            trades: body.u64()?,
+                        })
+                    })
+                    .collect::<Result<_>>()?;
+                market.candles.0.insert(interval, bars);
+            }
+            let orders = (0..body.len()?)
+                .map(|_| Ok((body.h256()?, body.order()?)))
+                .collect::<Result<Vec<(H256, Order)>>>()?;
+            let mut levels = || -> Result<Levels> {
+                (0..body.len()?)
+                    .map(|_| Ok((body.decimal()?, body.decimal()?)))
+                    .collect()
+            };
+            let bid_levels = levels()?;
+            let ask_levels = levels()?;
+            market.book.restore(orders, bid_levels, ask_levels)?;
+            engine.markets.insert(symbol, market);
+        }
+
+        for _ in 0..body.len()? {
+            let address = body.address()?;
+            let positions = engine.positions.entry(address).or_default();
+            for _ in 0..body.len()? {
+                let symbol = body.symbol()?;
+                positions.insert(
+                    symbol,
+                    Position {
+                        size: body.decimal()?,
+                        entry_price: body.decimal()?,
+                        realized_pnl: body.decimal()?,
+                        margin_mode: match body.u8()? {
+                            0 => MarginMode::Cross,
+                            1 => MarginMode::Isolated,
+                            tag => return Err(SnapshotError::InvalidTag("margin mode", tag)),
+                        },
+                        isolated_margin: body.decimal()?,
+                    },
+                );
+            }
+        }
+
+        for _ in 0..body.len()? {
+            engine.ledger.push(LedgerEntry {
+                timestamp: body.u128()?,
+                trader_address: body.address()?,
+                symbol: match body.bool()? {
+                    true => Some(body.symbol()?),
+                    false => None,
+                },
+                kind: match body.u8()? {
+                    0 => LedgerKind::Funding,
+                    1 => LedgerKind::Liquidation,
+                    2 => LedgerKind::InsuranceFund,
+                    tag => return Err(SnapshotError::InvalidTag("ledger kind", tag)),
+                },
+                amount: body.decimal()?,
+            });
+        }
+
+        for id in 1..=body.len()? as u64 {
+            let checkpoint = Checkpoint {
+                id,
+                timestamp: body.u128()?,
+                prev_root: body.h256()?,
+                state_root: body.h256()?,
+                deltas: (0..body.len()?)
+                    .map(|_| {
+                        Ok(BalanceDelta {
+                            trader_address: body.address()?,
+                            ddx_delta: body.decimal()?,
+                            usd_delta: body.decimal()?,
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+                withdrawals: (0..body.len()?)
+                    .map(|_| body.h256())
+                    .collect::<Result<_>>()?,
+            };
+            engine.checkpoints.history.push(checkpoint);
+        }
+        for _ in 0..body.len()? {
+            engine
+                .checkpoints
+                .balances
+                .insert(body.address()?, (body.decimal()?, body.decimal()?));
+        }
+        for _ in 0..body.len()? {
+            engine.deposits.insert((body.h256()?, body.u64()?));
+        }
+        for _ in 0..body.len()? {
+            let mut withdrawal = Withdrawal {
+                hash: H256::zero(),
+                trader_address: body.address()?,
+                asset: body.asset()?,
+                amount: body.decimal()?,
+                nonce: body.u64()?,
+                timestamp: body.u128()?,
+                checkpoint_id: None,
+            };
+            withdrawal.hash = withdrawal.digest();
+            let mut proof = None;
+            if body.bool()? {
+                withdrawal.checkpoint_id = Some(body.u64()?);
+                proof = Some(MerkleProof {
+                    key: withdrawal.hash,
+                    leaf: withdrawal.hash,
+                    bitmap: body.u256()?,
+                    siblings: (0..body.len()?)
+                        .map(|_| body.h256())
+                        .collect::<Result<_>>()?,
+                });
+            }
+            engine
+                .withdrawals
+                .insert(withdrawal.hash, (withdrawal, proof));
+        }
+
+        for _ in 0..body.len()? {
+            let address = body.address()?;
+            let mut hashes = vec![];
+            for _ in 0..body.len()? {
+                let hash = body.h256()?;
+                let order = body.order()?;
+                let record = OrderRecord {
+                    hash,
+                    order,
+                    filled: body.decimal()?,
+                    remaining: body.decimal()?,
+                    average_price: body.opt_decimal()?,
+                    status: match body.u8()? {
+                        0 => OrderStatus::Accepted,
+                        1 => OrderStatus::PartiallyFilled,
+                        2 => OrderStatus::Filled,
+                        3 => OrderStatus::Cancelled,
+                        4 => OrderStatus::Expired,
+                        tag => return Err(SnapshotError::InvalidTag("order status", tag)),
+                    },
+                    reason: match body.bool()? {
+                        true => Some(match body.u8()? {
+                            0 => TerminalReason::Trader,
+                            1 => TerminalReason::Liquidation,
+                            2 => TerminalReason::ReduceOnly,
+                            3 => TerminalReason::SelfMatch,
+                            4 => TerminalReason::ImmediateOrCancel,
+                            tag => return Err(SnapshotError::InvalidTag("terminal reason", tag)),
+                        }),
+                        false => None,
+                    },
+                    updated: body.u128()?,
+                };
+                engine.order_records.insert(hash, record);
+                hashes.push(hash);
+            }
+            engine.account_orders.insert(address, hashes);
+        }
+        for _ in 0..body.len()? {
+            engine.retired_orders.insert(body.h256()?);
+        }
+        engine.rebuild_state_tree();
+        Ok((engine, seq))
+    }
+
+    // written next to the destination and renamed over it, so a crash leaves
+    // either the old snapshot or the new one. the log is then rotated, so
+    // that restoring only has to read what comes after the snapshot
+    pub fn write_snapshot(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(&self.snapshot())?;
+        file.sync_all()?;
+        std::fs::rename(tmp, path)?;
+        if let Some(journal) = self.journal.as_mut() {
+            journal.rotate()?;
+        }
+        Ok(())
+    }
+
+    // restores the latest snapshot, if there is one, and replays the part of
+    // the event log written after it. a snapshot this build cannot read, or
+    // one older than what was rotated out of the log, is only a shortcut and
+    // the whole log is replayed instead
+    pub fn restore(
+        snapshot_path: impl AsRef<Path>,
+        log_path: impl AsRef<Path>,
+        clock: Box<dyn Clock>,
+    ) -> Result<Self> {
+        let bytes = match std::fs::read(snapshot_path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::recover(log_path, clock)?)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let replay_clock = ManualClock::new(clock.now() as u64);
+        let Ok((engine, seq)) = Self::from_snapshot(&bytes, Box::new(replay_clock.clone())) else {
+            return Ok(Self::recover(log_path, clock)?);
+        };
+        let (journal, records) = Journal::open(&log_path)?;
+        if journal.last_seq() < seq {
+            return Err(SnapshotError::AheadOfLog(seq, journal.last_seq()));
+        }
+        if journal.first_seq() > seq + 1 {
+            drop(journal);
+            return Ok(Self::recover(log_path, clock)?);
+        }
+        let tail = records
+            .into_iter()
+            .filter(|record| record.seq > seq)
+            .collect();
+        Ok(engine.resume(journal, tail, replay_clock, clock)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::engine::tests::{account, perp_order, temp_path, trader};
+
+    #[test]
+    fn test_restores_snapshot_and_log_tail() {
+        let log_path = temp_path("snapshot.log");
+        let snapshot_path = temp_path("snapshot.snapshot");
+
+        let clock = ManualClock::new(1);
+        let mut engine =
+            Engine::restore(&snapshot_path, &log_path, Box::new(clock.clone())).unwrap();
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        engine.create_account(account(2, dec!(1000))).unwrap();
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(2), dec!(100), 2))
+            .unwrap();
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(1), dec!(99), 3))
+            .unwrap();
+        engine
+            .create_order(perp_order(2, Side::Ask, dec!(1), dec!(100), 4))
+            .unwrap();
+        engine.write_snapshot(&snapshot_path).unwrap();
+        // what the snapshot covers was rotated out of the log
+        let segment = segment_path(&log_path, 6);
+        assert_eq!(std::fs::metadata(&log_path).unwrap().len(), 0);
+        assert_eq!(Journal::read(&segment).unwrap().len(), 6);
+
+        // only in the log
+        engine
+            .create_order(perp_order(2, Side::Ask, dec!(3), dec!(105), 5))
+            .unwrap();
+        engine.create_account(account(3, dec!(10))).unwrap();
+
+        let restored = Engine::restore(&snapshot_path, &log_path, Box::new(clock.clone())).unwrap();
+        assert_eq!(restored.snapshot(), engine.snapshot());
+        assert_eq!(restored.get_book(Symbol::DdxPerp).asks[0].amount, dec!(3));
+        assert_eq!(restored.get_positions(trader(2)).unwrap()[0].size, dec!(-1));
+        assert!(restored.get_account(trader(3)).is_ok());
+        assert_eq!(Journal::read(&log_path).unwrap().len(), 8);
+
+        std::fs::remove_file(&log_path).unwrap();
+        std::fs::remove_file(&segment).unwrap();
+        std::fs::remove_file(&snapshot_path).unwrap();
+    }
+
+    fn segment_path(log_path: &Path, seq: u64) -> PathBuf {
+        let mut path = log_path.to_path_buf().into_os_string();
+        path.push(format!(".{}", seq));
+        path.into()
+    }
+
+    #[test]
+    fn test_unusable_snapshots_fall_back_to_the_log() {
+        let log_path = temp_path("fallback.log");
+        let snapshot_path = temp_path("fallback.snapshot");
+
+        let clock = ManualClock::new(1);
+        let mut engine =
+            Engine::restore(&snapshot_path, &log_path, Box::new(clock.clone())).unwrap();
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        engine.write_snapshot(&snapshot_path).unwrap();
+        let stale = std::fs::read(&snapshot_path).unwrap();
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(2), dec!(100), 2))
+            .unwrap();
+        engine.write_snapshot(&snapshot_path).unwrap();
+        engine.create_account(account(2, dec!(1000))).unwrap();
+
+        // written by another version
+        let mut bytes = engine.snapshot();
+        bytes[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        std::fs::write(&snapshot_path, bytes).unwrap();
+        let restored = Engine::restore(&snapshot_path, &log_path, Box::new(clock.clone())).unwrap();
+        assert_eq!(restored.snapshot(), engine.snapshot());
+        drop(restored);
+
+        // from before the last rotation, the order is only in a segment
+        std::fs::write(&snapshot_path, stale).unwrap();
+        let restored = Engine::restore(&snapshot_path, &log_path, Box::new(clock.clone())).unwrap();
+        assert_eq!(restored.snapshot(), engine.snapshot());
+
+        std::fs::remove_file(&log_path).unwrap();
+        std::fs::remove_file(segment_path(&log_path, 2)).unwrap();
+        std::fs::remove_file(segment_path(&log_path, 3)).unwrap();
+        std::fs::remove_file(&snapshot_path).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_damaged_snapshots() {
+        let mut engine = Engine::with_clock(Box::new(ManualClock::new(0)));
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(2), dec!(100), 1))
+            .unwrap();
+        let mut bytes = engine.snapshot();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(matches!(
+            Engine::from_snapshot(&bytes, Box::new(ManualClock::new(0))),
+            Err(SnapshotError::Checksum)
+        ));
+
+        // levels that disagree with the orders resting at them
+        let book = &engine.markets[&Symbol::DdxPerp].book;
+        let orders = book.orders().copied().collect();
+        let mut restored = Market::new(Symbol::DdxPerp, 0);
+        assert!(matches!(
+            restored
+                .book
+                .restore(orders, vec![(dec!(100), dec!(1))], vec![]),
+            Err(OrderBookError::InconsistentLevel(_))
+        ));
+    }
+}
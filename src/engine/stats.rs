@@ -0,0 +1,126 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use super::{
+    candles::{Interval, MINUTE},
+    Engine,
+};
+use crate::Symbol;
+
+// the window the ticker covers, made up of whole 1m candles so it may reach
+// up to a minute further back
+pub const STATS_WINDOW: u128 = 24 * 60 * MINUTE;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MarketStats {
+    pub symbol: Symbol,
+    // price fields are None until the market has traded, open, high, low and
+    // change also once it has not traded within the window
+    pub open: Option<Decimal>,
+    pub high: Option<Decimal>,
+    pub low: Option<Decimal>,
+    pub last: Option<Decimal>,
+    // last minus open
+    pub change: Option<Decimal>,
+    pub volume: Decimal,
+    // usd traded
+    pub quote_volume: Decimal,
+    pub trades: u64,
+    // open time of the first minute counted
+    pub window_start: u128,
+    pub timestamp: u128,
+}
+
+impl Engine {
+    pub fn get_market_stats(&self, symbol: Symbol) -> MarketStats {
+        let market = &self.markets[&symbol];
+        let now = self.clock.now();
+        let window_start = now.saturating_sub(STATS_WINDOW) / MINUTE * MINUTE;
+        let mut stats = MarketStats {
+            symbol,
+            open: None,
+            high: None,
+            low: None,
+            last: market.last_price,
+            change: None,
+            volume: Decimal::ZERO,
+            quote_volume: Decimal::ZERO,
+            trades: 0,
+            window_start,
+            timestamp: now,
+        };
+        let bars = market.candles.0.get(&Interval::OneMinute).into_iter();
+        for bar in bars.flatten().filter(|bar| bar.open_time >= window_start) {
+            stats.open.get_or_insert(bar.open);
+            stats.high = Some(stats.high.map_or(bar.high, |high| high.max(bar.high)));
+            stats.low = Some(stats.low.map_or(bar.low, |low| low.min(bar.low)));
+            stats.volume += bar.volume;
+            stats.quote_volume += bar.quote_volume;
+            stats.trades += bar.trades;
+        }
+        stats.change = stats.open.zip(stats.last).map(|(open, last)| last - open);
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{
+        engine::tests::{account, perp_order},
+        ManualClock, Side,
+    };
+
+    #[test]
+    fn test_stats_roll_with_the_window() {
+        let clock = ManualClock::new(0);
+        let mut engine = Engine::with_clock(Box::new(clock.clone()));
+        engine.create_account(account(1, dec!(10000))).unwrap();
+        engine.create_account(account(2, dec!(10000))).unwrap();
+        let hour = 60 * MINUTE;
+        for (time, price, amount) in [
+            (0, dec!(90), dec!(1)),
+            (2 * hour, dec!(100), dec!(1)),
+            (10 * hour, dec!(120), dec!(2)),
+            (20 * hour, dec!(110), dec!(1)),
+        ] {
+            clock.set(time as u64);
+            engine
+                .create_order(perp_order(1, Side::Ask, amount, price, time + 1))
+                .unwrap();
+            engine
+                .create_order(perp_order(2, Side::Bid, amount, price, time + 2))
+                .unwrap();
+        }
+
+        // the first trade has left the window
+        clock.set(25 * hour as u64);
+        let stats = engine.get_market_stats(Symbol::DdxPerp);
+        assert_eq!(stats.window_start, hour);
+        assert_eq!(
+            (stats.open, stats.high, stats.low, stats.last, stats.change),
+            (
+                Some(dec!(100)),
+                Some(dec!(120)),
+                Some(dec!(100)),
+                Some(dec!(110)),
+                Some(dec!(10))
+            )
+        );
+        assert_eq!(
+            (stats.volume, stats.quote_volume, stats.trades),
+            (dec!(4), dec!(450), 3)
+        );
+
+        // a quiet day keeps the last price only
+        clock.set(45 * hour as u64);
+        let stats = engine.get_market_stats(Symbol::DdxPerp);
+        assert_eq!(
+            (stats.open, stats.last, stats.trades),
+            (None, Some(dec!(110)), 0)
+        );
+    }
+}
@@ -0,0 +1,126 @@
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use super::Engine;
+use crate::{Fill, Order, Side, Symbol};
+
+// trades kept per market, older ones are only in the event log
+pub const TRADE_HISTORY: usize = 10_000;
+// most trades one query returns
+pub const MAX_TRADES: usize = 1_000;
+
+// a fill on the public tape, without the hashes of the orders behind it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Trade {
+    pub symbol: Symbol,
+    // starts at 1 per market and has no gaps
+    pub seq: u64,
+    pub price: Decimal,
+    pub amount: Decimal,
+    // side of the taker
+    pub side: Side,
+    pub timestamp: u128,
+}
+
+impl Engine {
+    // every fill of a taker order against the book goes through here
+    pub(super) fn record_trades(&mut self, order: &Order, fills: &[Fill]) {
+        let now = self.now;
+        let market = self.markets.get_mut(&order.symbol).unwrap();
+        for fill in fills {
+            market.trade_seq += 1;
+            let trade = Trade {
+                symbol: order.symbol,
+                seq: market.trade_seq,
+                price: fill.price,
+                amount: fill.fill_amount,
+                side: order.side,
+                timestamp: now,
+            };
+            market.candles.record(&trade);
+            market.trades.push_back(trade.clone());
+            self.pending_trades.push(trade);
+        }
+        if let Some(fill) = fills.last() {
+            market.last_price = Some(fill.price);
+        }
+    }
+
+    // drops what no longer fits the history, once a command is recorded so
+    // that until then undoing a batch only has to cut off what it added
+    pub(super) fn trim_history(&mut self) {
+        for market in self.markets.values_mut() {
+            let excess = market.trades.len().saturating_sub(TRADE_HISTORY);
+            market.trades.drain(..excess);
+            market.candles.trim();
+        }
+        self.trim_orders();
+    }
+
+    // oldest first, trades after seq `since` or the latest ones without it
+    pub fn get_trades(&self, symbol: Symbol, since: Option<u64>, limit: usize) -> Vec<Trade> {
+        let trades = &self.markets[&symbol].trades;
+        let limit = limit.min(MAX_TRADES);
+        match since {
+            Some(since) => trades
+                .iter()
+                .filter(|trade| trade.seq > since)
+                .take(limit)
+                .cloned()
+                .collect(),
+            None => trades
+                .iter()
+                .skip(trades.len().saturating_sub(limit))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{
+        engine::tests::{account, perp_order},
+        SystemClock,
+    };
+
+    #[test]
+    fn test_trade_history_pages_by_seq() {
+        let mut engine = Engine::new();
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        engine.create_account(account(2, dec!(1000))).unwrap();
+        for (price, timestamp) in [(dec!(100), 1), (dec!(101), 2), (dec!(102), 3)] {
+            engine
+                .create_order(perp_order(1, Side::Ask, dec!(1), price, timestamp))
+                .unwrap();
+        }
+        // one taker sweeping three levels prints three trades
+        engine
+            .create_order(perp_order(2, Side::Bid, dec!(3), dec!(102), 4))
+            .unwrap();
+
+        let trades = engine.get_trades(Symbol::DdxPerp, None, 2);
+        assert_eq!(trades.len(), 2);
+        assert_eq!((trades[0].seq, trades[0].price), (2, dec!(101)));
+        assert_eq!(trades[1].side, Side::Bid);
+        let trades = engine.get_trades(Symbol::DdxPerp, Some(1), 10);
+        assert_eq!(
+            trades.iter().map(|trade| trade.seq).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert!(engine.get_trades(Symbol::DdxUsd, None, 10).is_empty());
+
+        // the tape survives a snapshot and keeps counting from where it was
+        let (restored, _) =
+            Engine::from_snapshot(&engine.snapshot(), Box::new(SystemClock)).unwrap();
+        assert_eq!(
+            restored.get_trades(Symbol::DdxPerp, None, 10),
+            engine.get_trades(Symbol::DdxPerp, None, 10)
+        );
+        assert_eq!(restored.markets[&Symbol::DdxPerp].trade_seq, 3);
+    }
+}
@@ -0,0 +1,222 @@
+use lazy_static::lazy_static;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use web3::{
+    signing::keccak256,
+    types::{Address, H256, U256},
+};
+
+use super::{
+    deposit::Asset,
+    error::{EngineError, Result},
+    journal::Command,
+    merkle::{decimal_to_int256, MerkleProof},
+    orderbook::eip712::{Eip712, Eip712Domain, EncodeDataable, TypeHashable},
+    Engine,
+};
+
+lazy_static! {
+    static ref WITHDRAWAL_HASH: [u8; 32] = keccak256(
+        "Withdrawal(address traderAddress,uint8 asset,uint256 amount,uint256 nonce)".as_bytes()
+    );
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Withdrawal {
+    // eip-712 hash of the fields below, also the withdrawal's key and leaf in
+    // the state tree
+    pub hash: H256,
+    pub trader_address: Address,
+    pub asset: Asset,
+    pub amount: Decimal,
+    // starts at 1 and has no gaps, keeps equal withdrawals apart
+    pub nonce: u64,
+    pub timestamp: u128,
+    // checkpoint that committed to it, None until one has
+    pub checkpoint_id: Option<u64>,
+}
+
+impl TypeHashable for Withdrawal {
+    fn type_hash(&self) -> [u8; 32] {
+        *WITHDRAWAL_HASH
+    }
+}
+
+impl EncodeDataable for Withdrawal {
+    fn encode_data(&self) -> Vec<u8> {
+        [
+            self.trader_address.encode_data(),
+            (self.asset as u8).encode_data(),
+            U256::from_big_endian(&decimal_to_int256(self.amount)).encode_data(),
+            U256::from(self.nonce).encode_data(),
+        ]
+        .concat()
+    }
+}
+
+impl Withdrawal {
+    pub fn digest(&self) -> H256 {
+        Eip712::new(Eip712Domain {
+            name: "DDX withdrawals",
+            version: "0.1.0",
+        })
+        .encode(self.clone())
+    }
+}
+
+// what the trader submits on chain, the settlement contract pays out once the
+// proof places the withdrawal under the root of the checkpoint it names
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WithdrawalClaim {
+    pub withdrawal: Withdrawal,
+    pub state_root: H256,
+    pub proof: MerkleProof,
+}
+
+impl Engine {
+    // takes the amount out of the trader's balance straight away so that it
+    // can no longer back orders or positions, the next checkpoint settles it
+    pub fn withdraw(
+        &mut self,
+        address: Address,
+        asset: Asset,
+        amount: Decimal,
+    ) -> Result<Withdrawal> {
+        self.start_command();
+        if address == self.insurance_fund {
+            return Err(EngineError::ReservedAccount(address));
+        }
+        if !amount.is_sign_positive() || amount.is_zero() {
+            return Err(EngineError::InvalidAmount(amount));
+        }
+        let account = self.get_account(address)?;
+        let available = match asset {
+            Asset::Ddx => account.ddx_balance - account.ddx_book_outstanding,
+            // unrealized profit cannot be withdrawn, unrealized losses and
+            // margin in use hold collateral back
+            Asset::Usd => (account.usd_balance - account.usd_book_outstanding)
+                .min(self.get_margin(address)?.free_collateral),
+        };
+        if available < amount {
+            return Err(EngineError::InsufficientBalance(available, amount));
+        }
+
+        let account = self.accounts.get_mut(&address).unwrap();
+        match asset {
+            Asset::Ddx => account.ddx_balance -= amount,
+            Asset::Usd => account.usd_balance -= amount,
+        }
+        self.state_tree.touch_account(address);
+        let mut withdrawal = Withdrawal {
+            hash: H256::zero(),
+            trader_address: address,
+            asset,
+            amount,
+            nonce: self.withdrawals.len() as u64 + 1,
+            timestamp: self.now,
+            checkpoint_id: None,
+        };
+        withdrawal.hash = withdrawal.digest();
+        self.withdrawals
+            .insert(withdrawal.hash, (withdrawal.clone(), None));
+        self.state_tree.touch_key(withdrawal.hash);
+        self.record(Command::Withdraw {
+            trader_address: address,
+            asset,
+            amount,
+        });
+        Ok(withdrawal)
+    }
+
+    pub fn get_withdrawals(&self, address: Address) -> Result<Vec<Withdrawal>> {
+        self.get_account(address)?;
+        let mut withdrawals: Vec<Withdrawal> = self
+            .withdrawals
+            .values()
+            .filter(|(withdrawal, _)| withdrawal.trader_address == address)
+            .map(|(withdrawal, _)| withdrawal.clone())
+            .collect();
+        withdrawals.sort_by_key(|withdrawal| withdrawal.nonce);
+        Ok(withdrawals)
+    }
+
+    pub fn get_withdrawal_claim(&self, hash: H256) -> Result<WithdrawalClaim> {
+        let (withdrawal, proof) = self
+            .withdrawals
+            .get(&hash)
+            .ok_or(EngineError::WithdrawalNotFound(hash))?;
+        let (Some(checkpoint_id), Some(proof)) = (withdrawal.checkpoint_id, proof) else {
+            return Err(EngineError::WithdrawalPending(hash));
+        };
+        Ok(WithdrawalClaim {
+            withdrawal: withdrawal.clone(),
+            state_root: self.get_checkpoint(checkpoint_id)?.state_root,
+            proof: proof.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal_macros::dec;
+
+    use super::*;
+    use crate::{
+        engine::{
+            checkpoint::{LocalSettlement, SettlementError},
+            tests::{account, perp_order},
+        },
+        ManualClock, Side,
+    };
+
+    #[test]
+    fn test_withdrawal_claims_settle_once() {
+        let mut engine = Engine::with_clock(Box::new(ManualClock::new(0)));
+        let trader = Address::from_low_u64_be(1);
+        engine.create_account(account(1, dec!(1000))).unwrap();
+        let mut settlement = LocalSettlement::default();
+        settlement
+            .submit(&engine.checkpoint().unwrap().abi_encode())
+            .unwrap();
+
+        // margin held by a resting order cannot leave
+        engine
+            .create_order(perp_order(1, Side::Bid, dec!(10), dec!(100), 1))
+            .unwrap();
+        assert!(matches!(
+            engine.withdraw(trader, Asset::Usd, dec!(950)),
+            Err(EngineError::InsufficientBalance(_, _))
+        ));
+        let withdrawal = engine.withdraw(trader, Asset::Usd, dec!(600)).unwrap();
+        assert_eq!(engine.get_account(trader).unwrap().usd_balance, dec!(400));
+        assert!(matches!(
+            engine.get_withdrawal_claim(withdrawal.hash),
+            Err(EngineError::WithdrawalPending(_))
+        ));
+
+        let checkpoint = engine.checkpoint().unwrap();
+        assert_eq!(checkpoint.withdrawals, vec![withdrawal.hash]);
+        assert_eq!(checkpoint.deltas[0].usd_delta, dec!(-600));
+        settlement.submit(&checkpoint.abi_encode()).unwrap();
+
+        // the claim survives a snapshot
+        let (engine, _) =
+            Engine::from_snapshot(&engine.snapshot(), Box::new(ManualClock::new(0))).unwrap();
+        let claim = engine.get_withdrawal_claim(withdrawal.hash).unwrap();
+        assert_eq!(claim.state_root, checkpoint.state_root);
+
+        let mut forged = claim.clone();
+        forged.withdrawal.amount = dec!(6000);
+        assert!(matches!(
+            settlement.claim(&forged),
+            Err(SettlementError::InvalidClaim(_))
+        ));
+        settlement.claim(&claim).unwrap();
+        assert_eq!(
+            settlement.claim(&claim),
+            Err(SettlementError::AlreadyClaimed(withdrawal.hash))
+        );
+    }
+}
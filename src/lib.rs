@@ -2,4 +2,15 @@ mod common;
 mod engine;
 
 pub use common::*;
-pub use engine::{Engine, EngineError};
+pub use engine::{
+    account_stream_message, cancel_all_message, isolated_margin_message, margin_mode_message,
+    recover_signer, withdrawal_message, AccountProof, AccountUpdate, AdlRank, Asset, BalanceDelta,
+    Candle, Checkpoint, Clock, Command, DeleverageFill, Deposit, DepositError, DepositLog,
+    DepositSource, DepositWatcher, Engine, EngineError, Event, FeedKey, FillUpdate, FixtureBlock,
+    FixtureDepositSource, FundingParams, FundingSettlement, Interval, Journal, JournalError,
+    JournalFailure, L2Update, L3Event, L3Order, L3Snapshot, L3Update, LedgerEntry, LedgerKind,
+    LiquidatedPosition, LiquidationEvent, Liquidity, LocalSettlement, ManualClock, MarginMode,
+    MarketStats, MarketUpdate, MerkleProof, OrderRecord, OrderStatus, OrderUpdate, PositionView,
+    PriceFeed, PriceFeedError, Publisher, Record, ReplayPriceFeed, ReplayedFill, RpcDepositSource,
+    SettlementError, SnapshotError, SystemClock, Trade, Withdrawal, WithdrawalClaim,
+};
@@ -1,34 +1,69 @@
 use actix_web::{
     delete, get, post,
     web::{self, JsonConfig},
-    App, HttpResponse, HttpServer, Responder,
+    App, HttpRequest, HttpResponse, HttpServer, Responder,
+};
+use derivadex::{
+    account_stream_message, cancel_all_message, isolated_margin_message, margin_mode_message,
+    recover_signer, withdrawal_message, Account, AccountUpdate, Asset, Checkpoint, DepositError,
+    DepositSource, DepositWatcher, Engine, EngineError, FeedKey, FixtureDepositSource, Interval,
+    JournalError, L2Update, L3Snapshot, MarginMode, MarketUpdate, Order, Publisher,
+    ReplayPriceFeed, RpcDepositSource, Side, Symbol, SystemClock,
 };
-use derivadex::{Account, Engine, EngineError, Order};
 use displaydoc::Display;
-use std::{sync::Mutex, time::SystemTime};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
 use thiserror::Error;
-use web3::types::{Address, H256};
+use tokio::sync::broadcast;
+use web3::types::{Address, Bytes, H256};
 
 #[derive(Debug, Display, Error)]
 enum DerivadexError {
     /// engine error: {0}
     EngineError(#[from] EngineError),
+
+    /// balances are credited from on-chain deposits, accounts open empty
+    UntrustedBalance,
+
+    /// missing, stale, reused or invalid signature
+    Unauthorized,
+
+    /// a batch holds at most {0} items
+    BatchTooLarge(usize),
 }
 
 impl actix_web::error::ResponseError for DerivadexError {
     fn status_code(&self) -> actix_web::http::StatusCode {
         match self {
             DerivadexError::EngineError(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+            DerivadexError::UntrustedBalance => actix_web::http::StatusCode::BAD_REQUEST,
+            DerivadexError::Unauthorized => actix_web::http::StatusCode::UNAUTHORIZED,
+            DerivadexError::BatchTooLarge(_) => actix_web::http::StatusCode::BAD_REQUEST,
         }
     }
 }
 
+struct Settings {
+    // balances only come from deposits on chain
+    chain_deposits: bool,
+}
+
 #[post("/")]
 async fn create_account(
     engine: web::Data<Mutex<Engine>>,
+    settings: web::Data<Settings>,
     request: web::Json<Account>,
 ) -> impl Responder {
     let account = request.into_inner();
+    if settings.chain_deposits && !(account.ddx_balance.is_zero() && account.usd_balance.is_zero())
+    {
+        return Err(DerivadexError::UntrustedBalance);
+    }
     let address = engine.lock().unwrap().create_account(account)?;
     Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().body(format!("{:#x}", address)))
 }
@@ -42,6 +77,231 @@ async fn get_account(
     Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(account))
 }
 
+#[get("/{traderAddress}/positions")]
+async fn get_positions(
+    engine: web::Data<Mutex<Engine>>,
+    trader_address: web::Path<Address>,
+) -> impl Responder {
+    let positions = engine.lock().unwrap().get_positions(*trader_address)?;
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(positions))
+}
+
+#[get("/{traderAddress}/margin")]
+async fn get_margin(
+    engine: web::Data<Mutex<Engine>>,
+    trader_address: web::Path<Address>,
+) -> impl Responder {
+    let margin = engine.lock().unwrap().get_margin(*trader_address)?;
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(margin))
+}
+
+#[derive(Deserialize)]
+struct MarginModeRequest {
+    mode: MarginMode,
+    timestamp: u64,
+    // personal_sign of margin_mode_message(trader, symbol, mode, timestamp)
+    signature: Bytes,
+}
+
+#[post("/{traderAddress}/positions/{symbol}/mode")]
+async fn set_margin_mode(
+    engine: web::Data<Mutex<Engine>>,
+    seen: web::Data<SeenMessages>,
+    path: web::Path<(Address, Symbol)>,
+    request: web::Json<MarginModeRequest>,
+) -> impl Responder {
+    let (trader_address, symbol) = path.into_inner();
+    let timestamp = request.timestamp as u128;
+    let message = margin_mode_message(trader_address, symbol, request.mode, timestamp);
+    check_signature(
+        &seen,
+        trader_address,
+        &message,
+        timestamp,
+        &request.signature.0,
+    )?;
+    engine
+        .lock()
+        .unwrap()
+        .set_margin_mode(trader_address, symbol, request.mode)?;
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().finish())
+}
+
+#[derive(Deserialize)]
+struct IsolatedMarginRequest {
+    // positive adds collateral to the position, negative takes it out
+    amount: Decimal,
+    timestamp: u64,
+    // personal_sign of isolated_margin_message(trader, symbol, amount, timestamp)
+    signature: Bytes,
+}
+
+#[post("/{traderAddress}/positions/{symbol}/margin")]
+async fn adjust_isolated_margin(
+    engine: web::Data<Mutex<Engine>>,
+    seen: web::Data<SeenMessages>,
+    path: web::Path<(Address, Symbol)>,
+    request: web::Json<IsolatedMarginRequest>,
+) -> impl Responder {
+    let (trader_address, symbol) = path.into_inner();
+    let timestamp = request.timestamp as u128;
+    let message = isolated_margin_message(trader_address, symbol, request.amount, timestamp);
+    check_signature(
+        &seen,
+        trader_address,
+        &message,
+        timestamp,
+        &request.signature.0,
+    )?;
+    let isolated_margin =
+        engine
+            .lock()
+            .unwrap()
+            .adjust_isolated_margin(trader_address, symbol, request.amount)?;
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(isolated_margin))
+}
+
+#[derive(Deserialize)]
+struct WithdrawalRequest {
+    asset: Asset,
+    amount: Decimal,
+    timestamp: u64,
+    // personal_sign of withdrawal_message(trader, asset, amount, timestamp)
+    signature: Bytes,
+}
+
+#[post("/{traderAddress}/withdrawals")]
+async fn create_withdrawal(
+    engine: web::Data<Mutex<Engine>>,
+    seen: web::Data<SeenMessages>,
+    trader_address: web::Path<Address>,
+    request: web::Json<WithdrawalRequest>,
+) -> impl Responder {
+    let timestamp = request.timestamp as u128;
+    let message = withdrawal_message(*trader_address, request.asset, request.amount, timestamp);
+    check_signature(
+        &seen,
+        *trader_address,
+        &message,
+        timestamp,
+        &request.signature.0,
+    )?;
+    let withdrawal =
+        engine
+            .lock()
+            .unwrap()
+            .withdraw(*trader_address, request.asset, request.amount)?;
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(withdrawal))
+}
+
+#[get("/{traderAddress}/withdrawals")]
+async fn get_withdrawals(
+    engine: web::Data<Mutex<Engine>>,
+    trader_address: web::Path<Address>,
+) -> impl Responder {
+    let withdrawals = engine.lock().unwrap().get_withdrawals(*trader_address)?;
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(withdrawals))
+}
+
+// available once a checkpoint committed to the withdrawal
+#[get("/{hash}/claim")]
+async fn get_withdrawal_claim(
+    engine: web::Data<Mutex<Engine>>,
+    hash: web::Path<H256>,
+) -> impl Responder {
+    let claim = engine.lock().unwrap().get_withdrawal_claim(*hash)?;
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(claim))
+}
+
+#[get("/{traderAddress}/proof")]
+async fn get_account_proof(
+    engine: web::Data<Mutex<Engine>>,
+    trader_address: web::Path<Address>,
+) -> impl Responder {
+    let proof = engine.lock().unwrap().get_account_proof(*trader_address)?;
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(proof))
+}
+
+#[get("/{traderAddress}/ledger")]
+async fn get_ledger(
+    engine: web::Data<Mutex<Engine>>,
+    trader_address: web::Path<Address>,
+) -> impl Responder {
+    let ledger = engine.lock().unwrap().get_ledger(*trader_address)?;
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(ledger))
+}
+
+#[derive(Deserialize)]
+struct OrdersQuery {
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_orders_limit")]
+    limit: usize,
+}
+
+fn default_orders_limit() -> usize {
+    100
+}
+
+// the trader's orders, newest first, whether or not they are still open
+#[get("/{traderAddress}/orders")]
+async fn get_account_orders(
+    engine: web::Data<Mutex<Engine>>,
+    trader_address: web::Path<Address>,
+    query: web::Query<OrdersQuery>,
+) -> impl Responder {
+    let orders =
+        engine
+            .lock()
+            .unwrap()
+            .get_account_orders(*trader_address, query.offset, query.limit)?;
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(orders))
+}
+
+#[derive(Deserialize)]
+struct CancelAll {
+    // every market and both sides when left out
+    symbol: Option<Symbol>,
+    side: Option<Side>,
+    timestamp: u64,
+    // personal_sign of cancel_all_message(trader, symbol, side, timestamp)
+    signature: Bytes,
+}
+
+// deletes the trader's resting orders and returns their hashes
+#[delete("/{traderAddress}/orders")]
+async fn cancel_all(
+    engine: web::Data<Mutex<Engine>>,
+    seen: web::Data<SeenMessages>,
+    trader_address: web::Path<Address>,
+    request: web::Json<CancelAll>,
+) -> impl Responder {
+    let timestamp = request.timestamp as u128;
+    let message = cancel_all_message(*trader_address, request.symbol, request.side, timestamp);
+    check_signature(
+        &seen,
+        *trader_address,
+        &message,
+        timestamp,
+        &request.signature.0,
+    )?;
+    let hashes =
+        engine
+            .lock()
+            .unwrap()
+            .cancel_all(*trader_address, request.symbol, request.side)?;
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(hashes))
+}
+
+#[get("/{traderAddress}/adl")]
+async fn get_adl_ranks(
+    engine: web::Data<Mutex<Engine>>,
+    trader_address: web::Path<Address>,
+) -> impl Responder {
+    let ranks = engine.lock().unwrap().get_adl_ranks(*trader_address)?;
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(ranks))
+}
+
 #[delete("/{traderAddress}")]
 async fn delete_account(
     engine: web::Data<Mutex<Engine>>,
@@ -64,12 +324,95 @@ async fn create_order(
     Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(fills))
 }
 
+// most items one batch may hold
+const MAX_BATCH: usize = 50;
+
+// outcome of one item of a batch
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+enum BatchResult<T> {
+    Ok(T),
+    Error(String),
+}
+
+impl<T> From<Result<T, EngineError>> for BatchResult<T> {
+    fn from(result: Result<T, EngineError>) -> Self {
+        match result {
+            Ok(value) => BatchResult::Ok(value),
+            Err(e) => BatchResult::Error(e.to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateOrders {
+    orders: Vec<Order>,
+    // place all of the orders or none
+    #[serde(default)]
+    atomic: bool,
+}
+
+#[post("/batch")]
+async fn create_orders(
+    engine: web::Data<Mutex<Engine>>,
+    request: web::Json<CreateOrders>,
+) -> impl Responder {
+    let CreateOrders { mut orders, atomic } = request.into_inner();
+    if orders.len() > MAX_BATCH {
+        return Err(DerivadexError::BatchTooLarge(MAX_BATCH));
+    }
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    // keeps the orders of a batch in time priority in the order they came in
+    for (n, order) in orders.iter_mut().enumerate() {
+        order.timestamp = now + n as u128;
+    }
+    let results: Vec<BatchResult<_>> = engine
+        .lock()
+        .unwrap()
+        .create_orders(orders, atomic)
+        .into_iter()
+        .map(BatchResult::from)
+        .collect();
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(results))
+}
+
+#[derive(Deserialize)]
+struct DeleteOrders {
+    hashes: Vec<H256>,
+    // delete all of the orders or none
+    #[serde(default)]
+    atomic: bool,
+}
+
+#[delete("/batch")]
+async fn delete_orders(
+    engine: web::Data<Mutex<Engine>>,
+    request: web::Json<DeleteOrders>,
+) -> impl Responder {
+    let DeleteOrders { hashes, atomic } = request.into_inner();
+    if hashes.len() > MAX_BATCH {
+        return Err(DerivadexError::BatchTooLarge(MAX_BATCH));
+    }
+    let results: Vec<BatchResult<_>> = engine
+        .lock()
+        .unwrap()
+        .delete_orders(hashes, atomic)
+        .into_iter()
+        .map(BatchResult::from)
+        .collect();
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(results))
+}
+
 #[get("/{hash}")]
 async fn get_order(
     engine: web::Data<Mutex<Engine>>,
     order_hash: web::Path<H256>,
 ) -> impl Responder {
-    let order = engine.lock().unwrap().get_order(*order_hash)?;
+    // still answers once the order left the book
+    let order = engine.lock().unwrap().get_order_status(*order_hash)?;
     Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(order))
 }
 
@@ -82,15 +425,656 @@ async fn delete_order(
     Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().finish())
 }
 
+#[derive(Deserialize)]
+struct BookQuery {
+    #[serde(default)]
+    symbol: Symbol,
+    // levels per side, capped by the engine
+    #[serde(default = "default_book_depth")]
+    depth: usize,
+    // bucket size prices are grouped into
+    grouping: Option<Decimal>,
+}
+
+fn default_book_depth() -> usize {
+    50
+}
+
 #[get("/book")]
-async fn get_book(engine: web::Data<Mutex<Engine>>) -> impl Responder {
-    let l2_order_book = engine.lock().unwrap().get_book();
+async fn get_book(
+    engine: web::Data<Mutex<Engine>>,
+    query: web::Query<BookQuery>,
+) -> impl Responder {
+    let l2_order_book =
+        engine
+            .lock()
+            .unwrap()
+            .get_grouped_book(query.symbol, query.depth, query.grouping)?;
     Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(l2_order_book))
 }
 
+#[derive(Deserialize)]
+struct TickerQuery {
+    #[serde(default)]
+    symbol: Symbol,
+}
+
+#[get("/book/ticker")]
+async fn get_book_ticker(
+    engine: web::Data<Mutex<Engine>>,
+    query: web::Query<TickerQuery>,
+) -> impl Responder {
+    let ticker = engine.lock().unwrap().get_book_ticker(query.symbol);
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(ticker))
+}
+
+// fans market data out to websocket subscribers
+struct BroadcastPublisher {
+    market: broadcast::Sender<MarketUpdate>,
+    accounts: broadcast::Sender<AccountUpdate>,
+}
+
+impl Publisher for BroadcastPublisher {
+    fn publish(&mut self, update: MarketUpdate) {
+        // no subscribers is not an error
+        let _ = self.market.send(update);
+    }
+
+    fn publish_account(&mut self, update: AccountUpdate) {
+        let _ = self.accounts.send(update);
+    }
+}
+
+// first message of a stream, framed like the updates that follow it
+#[derive(Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "camelCase")]
+enum Snapshot {
+    L2Snapshot(L2Update),
+    L3Snapshot(L3Snapshot),
+}
+
+// sends `initial` and then every update `filter` lets through, as it returns
+// it, until the client goes away. a subscriber that falls behind the broadcast buffer has
+// missed updates and is disconnected so that it resubscribes
+async fn forward_updates<T: Clone + Serialize>(
+    mut session: actix_ws::Session,
+    mut messages: actix_ws::MessageStream,
+    initial: Vec<String>,
+    mut updates: broadcast::Receiver<T>,
+    filter: impl Fn(T) -> Option<T>,
+) {
+    for message in initial {
+        if session.text(message).await.is_err() {
+            return;
+        }
+    }
+    let reason = loop {
+        tokio::select! {
+            update = updates.recv() => match update {
+                Ok(update) => {
+                    if let Some(update) = filter(update) {
+                        let message = serde_json::to_string(&update).unwrap();
+                        if session.text(message).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(_) => {
+                    break Some(actix_ws::CloseReason {
+                        code: actix_ws::CloseCode::Again,
+                        description: Some("fell behind, resubscribe".to_string()),
+                    })
+                }
+            },
+            message = messages.recv() => match message {
+                Some(Ok(actix_ws::Message::Ping(bytes))) => {
+                    if session.pong(&bytes).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Ok(actix_ws::Message::Close(_))) | Some(Err(_)) | None => break None,
+                Some(Ok(_)) => {}
+            },
+        }
+    };
+    let _ = session.close(reason).await;
+}
+
+// full depth l2 snapshot followed by an update for every change to a level,
+// updates with a seq at or below the snapshot's are already part of it
+#[get("/book/{symbol}")]
+async fn stream_book(
+    request: HttpRequest,
+    body: web::Payload,
+    engine: web::Data<Mutex<Engine>>,
+    updates: web::Data<broadcast::Sender<MarketUpdate>>,
+    symbol: web::Path<Symbol>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let symbol = *symbol;
+    let (response, session, messages) = actix_ws::handle(&request, body)?;
+    // subscribing under the engine lock leaves no gap between the two
+    let (snapshot, receiver) = {
+        let engine = engine.lock().unwrap();
+        (engine.get_l2_snapshot(symbol), updates.subscribe())
+    };
+    let snapshot = serde_json::to_string(&Snapshot::L2Snapshot(snapshot)).unwrap();
+    actix_web::rt::spawn(forward_updates(
+        session,
+        messages,
+        vec![snapshot],
+        receiver,
+        move |update| {
+            matches!(&update, MarketUpdate::L2Update(l2) if l2.symbol == symbol).then_some(update)
+        },
+    ));
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct L3Query {
+    // leave out the traders behind the orders, and swap the order hashes
+    // for ids that only mean something on this feed
+    #[serde(default)]
+    anonymise: bool,
+}
+
+#[get("/{symbol}/l3")]
+async fn get_l3_book(
+    engine: web::Data<Mutex<Engine>>,
+    feed_key: web::Data<FeedKey>,
+    symbol: web::Path<Symbol>,
+    query: web::Query<L3Query>,
+) -> impl Responder {
+    let mut snapshot = engine.lock().unwrap().get_l3_snapshot(*symbol);
+    if query.anonymise {
+        snapshot.anonymise(&feed_key);
+    }
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(snapshot))
+}
+
+// every resting order followed by an update for every add, modify and delete,
+// on the same seq rules as the l2 stream
+#[get("/l3/{symbol}")]
+async fn stream_l3_book(
+    request: HttpRequest,
+    body: web::Payload,
+    engine: web::Data<Mutex<Engine>>,
+    updates: web::Data<broadcast::Sender<MarketUpdate>>,
+    feed_key: web::Data<FeedKey>,
+    symbol: web::Path<Symbol>,
+    query: web::Query<L3Query>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let symbol = *symbol;
+    let anonymise = query.anonymise;
+    let feed_key = **feed_key;
+    let (response, session, messages) = actix_ws::handle(&request, body)?;
+    let (mut snapshot, receiver) = {
+        let engine = engine.lock().unwrap();
+        (engine.get_l3_snapshot(symbol), updates.subscribe())
+    };
+    if anonymise {
+        snapshot.anonymise(&feed_key);
+    }
+    let snapshot = serde_json::to_string(&Snapshot::L3Snapshot(snapshot)).unwrap();
+    actix_web::rt::spawn(forward_updates(
+        session,
+        messages,
+        vec![snapshot],
+        receiver,
+        move |update| match update {
+            MarketUpdate::L3Update(mut l3) if l3.symbol == symbol => {
+                if anonymise {
+                    l3.anonymise(&feed_key);
+                }
+                Some(MarketUpdate::L3Update(l3))
+            }
+            _ => None,
+        },
+    ));
+    Ok(response)
+}
+
+#[get("/{symbol}/funding")]
+async fn get_funding(
+    engine: web::Data<Mutex<Engine>>,
+    symbol: web::Path<Symbol>,
+) -> impl Responder {
+    let funding = engine.lock().unwrap().get_funding(*symbol)?;
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(funding))
+}
+
+#[derive(Deserialize)]
+struct TradesQuery {
+    // seq of the last trade the client has, the latest trades without it
+    since: Option<u64>,
+    #[serde(default = "default_trades_limit")]
+    limit: usize,
+}
+
+fn default_trades_limit() -> usize {
+    100
+}
+
+#[get("/{symbol}/trades")]
+async fn get_trades(
+    engine: web::Data<Mutex<Engine>>,
+    symbol: web::Path<Symbol>,
+    query: web::Query<TradesQuery>,
+) -> impl Responder {
+    let trades = engine
+        .lock()
+        .unwrap()
+        .get_trades(*symbol, query.since, query.limit);
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(trades))
+}
+
+#[derive(Deserialize)]
+struct CandlesQuery {
+    interval: Interval,
+    // open time in nanoseconds of the first bar wanted, the latest bars
+    // without it
+    since: Option<u64>,
+    #[serde(default = "default_candles_limit")]
+    limit: usize,
+}
+
+fn default_candles_limit() -> usize {
+    500
+}
+
+#[get("/{symbol}/candles")]
+async fn get_candles(
+    engine: web::Data<Mutex<Engine>>,
+    symbol: web::Path<Symbol>,
+    query: web::Query<CandlesQuery>,
+) -> impl Responder {
+    let candles = engine.lock().unwrap().get_candles(
+        *symbol,
+        query.interval,
+        query.since.map(u128::from),
+        query.limit,
+    );
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(candles))
+}
+
+#[get("/{symbol}/stats")]
+async fn get_market_stats(
+    engine: web::Data<Mutex<Engine>>,
+    symbol: web::Path<Symbol>,
+) -> impl Responder {
+    let stats = engine.lock().unwrap().get_market_stats(*symbol);
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(stats))
+}
+
+// every trade of the market from the moment of subscribing, earlier ones come
+// from GET /markets/{symbol}/trades
+#[get("/trades/{symbol}")]
+async fn stream_trades(
+    request: HttpRequest,
+    body: web::Payload,
+    updates: web::Data<broadcast::Sender<MarketUpdate>>,
+    symbol: web::Path<Symbol>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let symbol = *symbol;
+    let (response, session, messages) = actix_ws::handle(&request, body)?;
+    actix_web::rt::spawn(forward_updates(
+        session,
+        messages,
+        vec![],
+        updates.subscribe(),
+        move |update| {
+            matches!(&update, MarketUpdate::Trade(trade) if trade.symbol == symbol)
+                .then_some(update)
+        },
+    ));
+    Ok(response)
+}
+
+// the market's 24h stats now and again after every command it trades in
+#[get("/ticker/{symbol}")]
+async fn stream_ticker(
+    request: HttpRequest,
+    body: web::Payload,
+    engine: web::Data<Mutex<Engine>>,
+    updates: web::Data<broadcast::Sender<MarketUpdate>>,
+    symbol: web::Path<Symbol>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let symbol = *symbol;
+    // subscribing under the lock keeps the stats from missing a trade
+    let (stats, receiver) = {
+        let engine = engine.lock().unwrap();
+        (engine.get_market_stats(symbol), updates.subscribe())
+    };
+    let stats = serde_json::to_string(&MarketUpdate::Ticker(stats)).unwrap();
+    let (response, session, messages) = actix_ws::handle(&request, body)?;
+    actix_web::rt::spawn(forward_updates(
+        session,
+        messages,
+        vec![stats],
+        receiver,
+        move |update| {
+            matches!(&update, MarketUpdate::Ticker(stats) if stats.symbol == symbol)
+                .then_some(update)
+        },
+    ));
+    Ok(response)
+}
+
+// funding settlements and liquidations in every market, as the engine
+// applies them on its own
+#[get("/events")]
+async fn stream_events(
+    request: HttpRequest,
+    body: web::Payload,
+    updates: web::Data<broadcast::Sender<MarketUpdate>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (response, session, messages) = actix_ws::handle(&request, body)?;
+    actix_web::rt::spawn(forward_updates(
+        session,
+        messages,
+        vec![],
+        updates.subscribe(),
+        |update| {
+            matches!(
+                &update,
+                MarketUpdate::Funding(_) | MarketUpdate::Liquidation(_)
+            )
+            .then_some(update)
+        },
+    ));
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct StreamAuth {
+    // query strings cannot carry a u128
+    timestamp: u64,
+    // personal_sign of account_stream_message(trader, timestamp)
+    signature: Bytes,
+}
+
+// how far a signed timestamp may be from the server's clock
+const SIGNATURE_WINDOW: Duration = Duration::from_secs(60);
+
+// signed messages already accepted, with their timestamps. a message names
+// its trader, action and timestamp, so it is accepted once, and forgotten
+// when the window alone rejects it
+#[derive(Default)]
+struct SeenMessages(Mutex<HashMap<String, u128>>);
+
+// a signature over `message` by the trader, which names a timestamp close to
+// now so that one seen by someone else is soon useless, and that was not
+// accepted before
+fn check_signature(
+    seen: &SeenMessages,
+    trader_address: Address,
+    message: &str,
+    timestamp: u128,
+    signature: &[u8],
+) -> Result<(), DerivadexError> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let window = SIGNATURE_WINDOW.as_nanos();
+    if now.abs_diff(timestamp) > window
+        || recover_signer(message, signature) != Some(trader_address)
+    {
+        return Err(DerivadexError::Unauthorized);
+    }
+    let mut seen = seen.0.lock().unwrap();
+    seen.retain(|_, timestamp| now.abs_diff(*timestamp) <= window);
+    if seen.insert(message.to_owned(), timestamp).is_some() {
+        return Err(DerivadexError::Unauthorized);
+    }
+    Ok(())
+}
+
+// order lifecycle, fills and balance changes of one trader, for as long as
+// the connection lasts. opening it takes a fresh signature by that trader
+#[get("/account/{traderAddress}")]
+async fn stream_account(
+    request: HttpRequest,
+    body: web::Payload,
+    accounts: web::Data<broadcast::Sender<AccountUpdate>>,
+    seen: web::Data<SeenMessages>,
+    trader_address: web::Path<Address>,
+    auth: web::Query<StreamAuth>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let trader_address = *trader_address;
+    let timestamp = auth.timestamp as u128;
+    let message = account_stream_message(trader_address, timestamp);
+    check_signature(
+        &seen,
+        trader_address,
+        &message,
+        timestamp,
+        &auth.signature.0,
+    )?;
+    let (response, session, messages) = actix_ws::handle(&request, body)?;
+    actix_web::rt::spawn(forward_updates(
+        session,
+        messages,
+        vec![],
+        accounts.subscribe(),
+        move |update: AccountUpdate| (update.trader_address() == trader_address).then_some(update),
+    ));
+    Ok(response)
+}
+
+#[get("/state/root")]
+async fn get_state_root(engine: web::Data<Mutex<Engine>>) -> impl Responder {
+    let root = engine.lock().unwrap().state_root();
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(root))
+}
+
+#[derive(Serialize)]
+struct CheckpointResponse {
+    #[serde(flatten)]
+    checkpoint: Checkpoint,
+    // abi encoded submitCheckpoint arguments
+    batch: Bytes,
+}
+
+impl From<Checkpoint> for CheckpointResponse {
+    fn from(checkpoint: Checkpoint) -> Self {
+        Self {
+            batch: checkpoint.abi_encode().into(),
+            checkpoint,
+        }
+    }
+}
+
+// cuts a checkpoint now rather than waiting for the next scheduled one, no
+// content when nothing changed since the last
+#[post("/")]
+async fn create_checkpoint(engine: web::Data<Mutex<Engine>>) -> impl Responder {
+    let checkpoint = engine.lock().unwrap().checkpoint();
+    Ok::<HttpResponse, DerivadexError>(match checkpoint {
+        Some(checkpoint) => HttpResponse::Ok().json(CheckpointResponse::from(checkpoint)),
+        None => HttpResponse::NoContent().finish(),
+    })
+}
+
+#[get("/{id}")]
+async fn get_checkpoint(engine: web::Data<Mutex<Engine>>, id: web::Path<u64>) -> impl Responder {
+    let checkpoint = engine.lock().unwrap().get_checkpoint(*id)?;
+    Ok::<HttpResponse, DerivadexError>(
+        HttpResponse::Ok().json(CheckpointResponse::from(checkpoint)),
+    )
+}
+
+#[get("/insurance")]
+async fn get_insurance_fund(engine: web::Data<Mutex<Engine>>) -> impl Responder {
+    let engine = engine.lock().unwrap();
+    let account = engine.get_account(engine.insurance_fund())?;
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(account))
+}
+
+#[get("/{symbol}/mark")]
+async fn get_mark(engine: web::Data<Mutex<Engine>>, symbol: web::Path<Symbol>) -> impl Responder {
+    let mark = engine.lock().unwrap().get_mark(*symbol);
+    Ok::<HttpResponse, DerivadexError>(HttpResponse::Ok().json(mark))
+}
+
+fn env_address(name: &str) -> std::io::Result<Option<Address>> {
+    std::env::var(name)
+        .ok()
+        .map(|value| {
+            value.parse().map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{} is not an address", name),
+                )
+            })
+        })
+        .transpose()
+}
+
+// deposits are read from a json-rpc node when DERIVADEX_DEPOSIT_RPC is set and
+// from a fixture file when DERIVADEX_DEPOSIT_FIXTURE is, accounts are funded
+// through POST /accounts otherwise
+fn deposit_watcher() -> std::io::Result<Option<DepositWatcher>> {
+    let invalid = |e: DepositError| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+    let source: Box<dyn DepositSource> = if let Ok(url) = std::env::var("DERIVADEX_DEPOSIT_RPC") {
+        let contract = env_address("DERIVADEX_DEPOSIT_CONTRACT")?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "DERIVADEX_DEPOSIT_CONTRACT is not set",
+            )
+        })?;
+        Box::new(RpcDepositSource::new(&url, contract).map_err(invalid)?)
+    } else if let Ok(path) = std::env::var("DERIVADEX_DEPOSIT_FIXTURE") {
+        Box::new(FixtureDepositSource::new(path))
+    } else {
+        return Ok(None);
+    };
+    let mut tokens = HashMap::new();
+    if let Some(token) = env_address("DERIVADEX_DDX_TOKEN")? {
+        tokens.insert(token, Asset::Ddx);
+    }
+    if let Some(token) = env_address("DERIVADEX_USD_TOKEN")? {
+        tokens.insert(token, Asset::Usd);
+    }
+    let number = |name: &str, default: u64| {
+        std::env::var(name).map_or(Ok(default), |value| {
+            value.parse().map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{} is not a number", name),
+                )
+            })
+        })
+    };
+    Ok(Some(DepositWatcher::new(
+        source,
+        number("DERIVADEX_CONFIRMATIONS", 12)?,
+        tokens,
+        number("DERIVADEX_DEPOSIT_FROM_BLOCK", 0)?,
+    )))
+}
+
+// the engine already applied the command it could not log, serving on would
+// acknowledge state a restart forgets
+fn journal_failed(e: JournalError) -> ! {
+    eprintln!("failed to append to the event log, shutting down: {}", e);
+    std::process::abort();
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let app_data = web::Data::new(Mutex::new(Engine::new()));
+    // every accepted command is appended to the log, startup restores the
+    // latest snapshot and replays whatever was logged after it
+    let event_log =
+        std::env::var("DERIVADEX_EVENT_LOG").unwrap_or_else(|_| "derivadex.log".to_string());
+    let snapshot =
+        std::env::var("DERIVADEX_SNAPSHOT").unwrap_or_else(|_| "derivadex.snapshot".to_string());
+    let mut engine = Engine::restore(&snapshot, event_log, Box::new(SystemClock))
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    engine.set_journal_failure(journal_failed);
+    // recorded index prices to replay, until a live feed is wired in
+    if let Ok(path) = std::env::var("DERIVADEX_PRICE_FEED") {
+        let price_feed = ReplayPriceFeed::from_file(path)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        engine.set_price_feed(Box::new(price_feed));
+    }
+    let (updates, _) = broadcast::channel(1024);
+    let (accounts, _) = broadcast::channel(1024);
+    engine.set_publisher(Box::new(BroadcastPublisher {
+        market: updates.clone(),
+        accounts: accounts.clone(),
+    }));
+    let updates = web::Data::new(updates);
+    let accounts = web::Data::new(accounts);
+    let app_data = web::Data::new(Mutex::new(engine));
+    let seen = web::Data::new(SeenMessages::default());
+    // one per process, anonymised l3 subscribers see the same ids until a
+    // restart
+    let feed_key = web::Data::new(FeedKey::default());
+    let deposit_watcher = deposit_watcher()?;
+    let settings = web::Data::new(Settings {
+        chain_deposits: deposit_watcher.is_some(),
+    });
+
+    // credits confirmed deposits, the chain is read without holding the engine
+    if let Some(mut watcher) = deposit_watcher {
+        let deposit_data = app_data.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(Duration::from_secs(5));
+            loop {
+                interval.tick().await;
+                let deposits = match watcher.poll().await {
+                    Ok(deposits) => deposits,
+                    Err(e) => {
+                        eprintln!("failed to poll deposits: {}", e);
+                        continue;
+                    }
+                };
+                let mut engine = deposit_data.lock().unwrap();
+                for deposit in deposits {
+                    if let Err(e) = engine.deposit(deposit) {
+                        eprintln!("failed to credit deposit: {}", e);
+                    }
+                }
+            }
+        });
+    }
+
+    // drives funding settlement and liquidations, the engine decides what is due
+    // and publishes what it did
+    let ticker_data = app_data.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            ticker_data.lock().unwrap().tick();
+        }
+    });
+
+    // keeps restarts from having to replay the whole log
+    let snapshot_data = app_data.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(300));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = snapshot_data.lock().unwrap().write_snapshot(&snapshot) {
+                eprintln!("failed to write snapshot: {}", e);
+            }
+        }
+    });
+
+    // batches for the settlement contract, picked up by id
+    let checkpoint_data = app_data.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(600));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            checkpoint_data.lock().unwrap().checkpoint();
+        }
+    });
+
     HttpServer::new(move || {
         App::new()
             .app_data(
@@ -98,19 +1082,65 @@ async fn main() -> std::io::Result<()> {
                     .error_handler(|err, _| actix_web::error::ErrorBadRequest(err)),
             )
             .app_data(app_data.clone())
+            .app_data(settings.clone())
+            .app_data(updates.clone())
+            .app_data(accounts.clone())
+            .app_data(seen.clone())
+            .app_data(feed_key.clone())
             .service(
                 web::scope("/accounts")
                     .service(create_account)
                     .service(get_account)
+                    .service(get_positions)
+                    .service(get_margin)
+                    .service(get_account_proof)
+                    .service(set_margin_mode)
+                    .service(adjust_isolated_margin)
+                    .service(create_withdrawal)
+                    .service(get_withdrawals)
+                    .service(get_ledger)
+                    .service(get_account_orders)
+                    .service(cancel_all)
+                    .service(get_adl_ranks)
                     .service(delete_account),
             )
             .service(
                 web::scope("/orders")
+                    // ahead of /{hash}, which would take "batch" for a hash
+                    .service(create_orders)
+                    .service(delete_orders)
                     .service(create_order)
                     .service(get_order)
                     .service(delete_order),
             )
+            .service(
+                web::scope("/markets")
+                    .service(get_funding)
+                    .service(get_mark)
+                    .service(get_trades)
+                    .service(get_candles)
+                    .service(get_market_stats)
+                    .service(get_l3_book),
+            )
+            .service(web::scope("/withdrawals").service(get_withdrawal_claim))
+            .service(
+                web::scope("/checkpoints")
+                    .service(create_checkpoint)
+                    .service(get_checkpoint),
+            )
+            .service(
+                web::scope("/ws")
+                    .service(stream_book)
+                    .service(stream_l3_book)
+                    .service(stream_trades)
+                    .service(stream_ticker)
+                    .service(stream_events)
+                    .service(stream_account),
+            )
             .service(get_book)
+            .service(get_book_ticker)
+            .service(get_insurance_fund)
+            .service(get_state_root)
     })
     .bind(("127.0.0.1", 4321))?
     .run()